@@ -0,0 +1,253 @@
+use crate::error::WebmentionError;
+use crate::wm_url::Url;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Decides which remote addresses we are allowed to connect to.
+///
+/// Anyone can send a webmention with an arbitrary source, and anyone can advertise an
+/// arbitrary endpoint, so by default only public addresses are allowed. This prevents
+/// [SSRF](https://www.w3.org/TR/webmention/#security-considerations) against
+/// loopback, private, link-local and multicast addresses. IPv6 addresses that embed an IPv4
+/// address (IPv4-mapped and -compatible, NAT64, 6to4) are judged by the IPv4 address.
+#[derive(Debug, Clone, Default)]
+pub struct AddressPolicy {
+    /// Allow all addresses, including non-public ones
+    pub allow_all: bool,
+    /// Hosts that are allowed regardless of the addresses they resolve to
+    pub allowed_hosts: Vec<String>,
+    /// Non-public networks that are allowed nevertheless
+    pub allowed_networks: Vec<IpNet>,
+}
+
+impl AddressPolicy {
+    /// Policy that allows connecting anywhere, including localhost.
+    pub fn allow_all() -> AddressPolicy {
+        AddressPolicy {
+            allow_all: true,
+            ..AddressPolicy::default()
+        }
+    }
+
+    /// Whether the policy allows connecting to `address`.
+    pub fn is_allowed(&self, address: IpAddr) -> bool {
+        self.allow_all
+            || is_public(address)
+            || self
+                .allowed_networks
+                .iter()
+                .any(|net| net.contains(&address))
+    }
+
+    fn is_allowed_host(&self, host: &str) -> bool {
+        self.allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+
+    /// Resolves the host of `url` and checks every address it resolves to.
+    ///
+    /// Returns the address to connect to, so that the host could not be re-resolved to a
+    /// different address between the check and the request.
+    pub async fn resolve(&self, url: &Url) -> Result<SocketAddr, WebmentionError> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| WebmentionError::RequestFailed {
+                url: url.clone(),
+                source: anyhow::anyhow!("URL has no host"),
+            })?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| WebmentionError::RequestFailed {
+                url: url.clone(),
+                source: anyhow::anyhow!("URL has no port"),
+            })?;
+
+        let addresses: Vec<SocketAddr> = match url.host() {
+            Some(url::Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+            Some(url::Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+            _ => tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|err| WebmentionError::RequestFailed {
+                    url: url.clone(),
+                    source: err.into(),
+                })?
+                .collect(),
+        };
+
+        if !self.is_allowed_host(host) {
+            if let Some(forbidden) = addresses.iter().find(|a| !self.is_allowed(a.ip())) {
+                return Err(WebmentionError::ForbiddenAddress {
+                    url: url.clone(),
+                    address: forbidden.ip(),
+                });
+            }
+        }
+
+        addresses
+            .into_iter()
+            .next()
+            .ok_or_else(|| WebmentionError::RequestFailed {
+                url: url.clone(),
+                source: anyhow::anyhow!("host resolved to no addresses"),
+            })
+    }
+}
+
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(embedded) => is_public_v4(embedded),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        // "this network", 0.0.0.0/8
+        || octets[0] == 0
+        // carrier-grade NAT, 100.64.0.0/10
+        || (octets[0] == 100 && (octets[1] & 0b1100_0000) == 64)
+        // IETF protocol assignments, 192.0.0.0/24
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        // benchmarking, 198.18.0.0/15
+        || (octets[0] == 198 && (octets[1] & 0b1111_1110) == 18)
+        // reserved for future use, 240.0.0.0/4
+        || octets[0] >= 240)
+}
+
+/// IPv4 address that an IPv6 address reaches through a translation or tunneling mechanism
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let v4 = |high: u16, low: u16| Some(Ipv4Addr::from(((high as u32) << 16) | low as u32));
+    match segments {
+        // IPv4-mapped, ::ffff:0:0/96
+        [0, 0, 0, 0, 0, 0xffff, high, low]
+        // IPv4-compatible, ::/96, which also holds :: and ::1
+        | [0, 0, 0, 0, 0, 0, high, low]
+        // NAT64, 64:ff9b::/96
+        | [0x64, 0xff9b, 0, 0, 0, 0, high, low] => v4(high, low),
+        // 6to4, 2002::/16
+        [0x2002, high, low, ..] => v4(high, low),
+        _ => None,
+    }
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // local-use NAT64, 64:ff9b:1::/48, translating to any IPv4 address
+        || (first == 0x64 && ip.segments()[1] == 0xff9b && ip.segments()[2] == 1)
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // site-local, fec0::/10
+        || (first & 0xffc0) == 0xfec0
+        // documentation, 2001:db8::/32
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod test {
+    use super::AddressPolicy;
+    use crate::error::WebmentionError;
+    use crate::wm_url::Url;
+    use std::net::IpAddr;
+    use tokio_test::block_on;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = AddressPolicy::default();
+        assert!(policy.is_allowed(ip("93.184.216.34")));
+        assert!(policy.is_allowed(ip("2606:2800:220:1:248:1893:25c8:1946")));
+        // translated or tunneled to a public IPv4 address
+        assert!(policy.is_allowed(ip("64:ff9b::5db8:d822")));
+        assert!(policy.is_allowed(ip("2002:5db8:d822::1")));
+        assert!(policy.is_allowed(ip("198.20.0.1")));
+
+        for forbidden in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "64:ff9b::a00:1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b:1::a00:1",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+            "::10.0.0.1",
+            "::127.0.0.1",
+            "fec0::1",
+        ] {
+            assert!(
+                !policy.is_allowed(ip(forbidden)),
+                "{} is allowed",
+                forbidden
+            );
+        }
+    }
+
+    #[test]
+    fn test_allowed_networks() {
+        let policy = AddressPolicy {
+            allowed_networks: vec!["10.0.0.0/8".parse().unwrap()],
+            ..AddressPolicy::default()
+        };
+        assert!(policy.is_allowed(ip("10.1.2.3")));
+        assert!(!policy.is_allowed(ip("192.168.1.1")));
+        assert!(AddressPolicy::allow_all().is_allowed(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let policy = AddressPolicy::default();
+        let metadata = Url::parse("http://169.254.169.254/latest/meta-data/").unwrap();
+        match block_on(policy.resolve(&metadata)) {
+            Err(WebmentionError::ForbiddenAddress { address, .. }) => {
+                assert_eq!(address, ip("169.254.169.254"))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let localhost = Url::parse("http://localhost:8080/").unwrap();
+        assert!(block_on(policy.resolve(&localhost)).is_err());
+
+        let policy = AddressPolicy {
+            allowed_hosts: vec!["localhost".to_string()],
+            ..AddressPolicy::default()
+        };
+        let address = block_on(policy.resolve(&localhost)).unwrap();
+        assert!(address.ip().is_loopback());
+        assert_eq!(address.port(), 8080);
+    }
+}
@@ -4,13 +4,32 @@ extern crate rocket;
 
 use anyhow::{anyhow, Context, Result};
 
+use std::sync::Arc;
 use url::Url;
+use webmention::address_policy::AddressPolicy;
+use webmention::endpoint_discovery::cache::{FileEndpointCache, PerOriginCache};
+use webmention::endpoint_discovery::{DiscoveryOptions, DiscoveryStrategy};
 use webmention::error::WebmentionError;
-use webmention::webmention::Webmention;
+use webmention::http_client::HttpClient;
+use webmention::rate_limit::{RateLimits, RequestScheduler};
+#[cfg(feature = "receiver")]
+use webmention::receiver::policy::DomainList;
+#[cfg(feature = "receive")]
+use webmention::receiver::spam::{AkismetFilter, HeuristicFilter, SpamFilter};
+#[cfg(feature = "receiver")]
+use webmention::render::{Renderer, Templates};
+#[cfg(feature = "receiver")]
+use webmention::storage::{JsonlWebmentionStorage, WebmentionStorage};
+#[cfg(feature = "receiver")]
+use webmention::webmention::Moderation;
+use webmention::webmention::{Webmention, WebmentionAcceptance};
 
-async fn fetch_links(u: &Url) -> Result<std::collections::HashSet<Url>, WebmentionError> {
-    let response = webmention::http_client::get(u).await?;
-    let links = response.html.find_links().await;
+async fn fetch_links(
+    client: &HttpClient,
+    u: &Url,
+) -> Result<std::collections::HashSet<Url>, WebmentionError> {
+    let response = client.get(u).await?;
+    let links = response.html.find_links().await?;
 
     Ok(links.into_iter().collect())
 }
@@ -22,20 +41,31 @@ fn parse_url(u: &str) -> Result<Url> {
         Ok(url) => Ok(url),
         Err(url::ParseError::RelativeUrlWithoutBase) => {
             let with_http = "http://".to_owned() + u;
-            return Url::parse(&with_http).with_context(|| {
+            Url::parse(&with_http).with_context(|| {
                 format!(
                     "Failed to parse URL after prepending http:// prefix to <{}>",
                     u
                 )
-            });
+            })
         }
         Err(e) => Err(e.into()),
     }
 }
 
+fn parse_positive<T: std::str::FromStr + Default + PartialOrd>(
+    value: &str,
+    option: &str,
+) -> Result<T> {
+    value
+        .parse::<T>()
+        .ok()
+        .filter(|n| *n > T::default())
+        .ok_or_else(|| anyhow!("{} expects a positive number, got {}", option, value))
+}
+
 pub async fn send_mentions_for_link(u: &Url) -> Result<(), WebmentionError> {
     let response = webmention::http_client::get(u).await?;
-    let links = response.html.find_links().await;
+    let links = response.html.find_links().await?;
 
     for link in links.into_iter() {
         Webmention::from((u.clone(), link)).send().await?;
@@ -43,22 +73,29 @@ pub async fn send_mentions_for_link(u: &Url) -> Result<(), WebmentionError> {
     Ok(())
 }
 
-async fn send_link(input: (Url, Url)) -> Result<bool> {
+async fn send_link(
+    client: &HttpClient,
+    discovery: &DiscoveryOptions,
+    input: (Url, Url),
+) -> Result<WebmentionAcceptance> {
     let (source_url, target_url) = input;
     let mut mention = Webmention::from((&source_url, &target_url));
-    mention.send().await.with_context(|| {
-        format!(
-            "Failed to send webmention from <{}> to <{}>",
-            source_url, target_url
-        )
-    })
+    mention
+        .send_with_options(client, discovery)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to send webmention from <{}> to <{}>",
+                source_url, target_url
+            )
+        })
 }
 
-async fn send_all(source: Url) -> Result<()> {
-    let links = fetch_links(&source)
+async fn send_all(client: &HttpClient, discovery: &DiscoveryOptions, source: Url) -> Result<()> {
+    let links = fetch_links(client, &source)
         .await
         .with_context(|| format!("Failed to fetch links from <{}>", source))?;
-    if links.len() == 0 {
+    if links.is_empty() {
         println!("No links found");
     } else {
         println!("Links:");
@@ -76,9 +113,11 @@ async fn send_all(source: Url) -> Result<()> {
             mention
         })
         .map(|mut w| {
+            let client = client.clone();
+            let discovery = discovery.clone();
             tokio::task::spawn(async move {
-                w.send().await?;
-                Ok(w) as Result<Webmention, WebmentionError>
+                let acceptance = w.send_with_options(&client, &discovery).await?;
+                Ok((w, acceptance)) as Result<(Webmention, WebmentionAcceptance), WebmentionError>
             })
         })
         .collect();
@@ -87,7 +126,9 @@ async fn send_all(source: Url) -> Result<()> {
         let result = handle.await;
         match result {
             Ok(r) => match r {
-                Ok(r) => println!("OK {}", r.target),
+                Ok((w, acceptance)) => {
+                    println!("{}: {}", w.target, acceptance)
+                }
                 Err(e) => println!("Could not send webmention: {:?}", e),
             },
             Err(e) => println!("Could not send webmention: {:?}", e),
@@ -97,13 +138,421 @@ async fn send_all(source: Url) -> Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "receiver")]
+fn edit_domain_list(matches: &clap::ArgMatches<'_>) -> Result<()> {
+    let path = matches.value_of("file").unwrap();
+    let mut list = if std::path::Path::new(path).exists() {
+        DomainList::read(path).with_context(|| format!("Failed to read {}", path))?
+    } else {
+        DomainList::new()
+    };
+
+    match matches.subcommand() {
+        ("add", Some(add_matches)) => {
+            for pattern in add_matches.values_of("pattern").into_iter().flatten() {
+                if !list.insert(pattern) {
+                    return Err(anyhow!("Not a domain: {}", pattern));
+                }
+            }
+        }
+        ("remove", Some(remove_matches)) => {
+            for pattern in remove_matches.values_of("pattern").into_iter().flatten() {
+                if !list.remove(pattern) {
+                    println!("{} is not on the list", pattern);
+                }
+            }
+        }
+        ("import", Some(import_matches)) => {
+            for other in import_matches.values_of("other").into_iter().flatten() {
+                let before = list.len();
+                list.extend(
+                    &DomainList::read(other)
+                        .with_context(|| format!("Failed to read {}", other))?,
+                );
+                println!("Imported {} domains from {}", list.len() - before, other);
+            }
+        }
+        ("export", _) => {
+            print!("{}", list);
+            return Ok(());
+        }
+        _ => return Err(anyhow!("No command specified, see `domain-list --help`")),
+    }
+
+    list.write(path)
+        .with_context(|| format!("Failed to write {}", path))
+}
+
+#[cfg(feature = "receiver")]
+fn open_storage(matches: &clap::ArgMatches<'_>) -> Result<JsonlWebmentionStorage> {
+    let path = matches.value_of("storage").unwrap();
+    JsonlWebmentionStorage::open(path).with_context(|| format!("Failed to open {}", path))
+}
+
+#[cfg(feature = "receiver")]
+fn edit_storage(matches: &clap::ArgMatches<'_>) -> Result<()> {
+    let storage = open_storage(matches)?;
+    let urls = |matches: &clap::ArgMatches<'_>| -> Result<(Url, Url)> {
+        let source = matches.value_of("source").unwrap();
+        let target = matches.value_of("target").unwrap();
+        Ok((
+            parse_url(source)
+                .with_context(|| format!("Failed to parse source URL: <{}>", source))?,
+            parse_url(target)
+                .with_context(|| format!("Failed to parse target URL: <{}>", target))?,
+        ))
+    };
+
+    match matches.subcommand() {
+        ("moderate", Some(moderate_matches)) => {
+            let (source, target) = urls(moderate_matches)?;
+            let moderation = match moderate_matches.value_of("moderation").unwrap() {
+                "approved" => Moderation::Approved,
+                "spam" => Moderation::Spam,
+                _ => Moderation::Pending,
+            };
+            if !storage.moderate(&source, &target, moderation)? {
+                return Err(anyhow!("No webmention from <{}> to <{}>", source, target));
+            }
+        }
+        ("delete", Some(delete_matches)) => {
+            let (source, target) = urls(delete_matches)?;
+            if !storage.delete(&source, &target)? {
+                return Err(anyhow!("No webmention from <{}> to <{}>", source, target));
+            }
+        }
+        ("compact", _) => {
+            let dropped = storage.compact()?;
+            println!("Dropped {} superseded lines", dropped);
+        }
+        _ => return Err(anyhow!("No command specified, see `storage --help`")),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "receiver")]
+fn import_mentions(matches: &clap::ArgMatches<'_>) -> Result<()> {
+    let storage = open_storage(matches)?;
+    let options = webmention::snapshot::SnapshotOptions::default();
+    for path in matches.values_of("file").into_iter().flatten() {
+        let mentions = match matches.value_of("format").unwrap() {
+            "webmention-io" => webmention::import::read_webmention_io(path, &options),
+            format => webmention::import::read(path, format.parse()?),
+        }
+        .with_context(|| format!("Failed to read {}", path))?;
+        for invalid in &mentions.invalid {
+            println!("Skipped in {}: {}", path, invalid);
+        }
+        let summary = webmention::import::import(&storage, mentions)?;
+        println!(
+            "Imported {} webmentions from {}, skipped {} duplicates and {} invalid entries",
+            summary.imported, path, summary.duplicates, summary.invalid
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "receiver")]
+fn export_mentions(matches: &clap::ArgMatches<'_>) -> Result<()> {
+    let storage = open_storage(matches)?;
+    let format = matches.value_of("format").unwrap().parse()?;
+    let exported = webmention::export::export(&storage, format)?;
+    match matches.value_of("output") {
+        Some(path) => {
+            std::fs::write(path, exported).with_context(|| format!("Failed to write {}", path))
+        }
+        None => {
+            print!("{}", exported);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "receiver")]
+fn render_mentions(matches: &clap::ArgMatches<'_>) -> Result<()> {
+    let target = matches.value_of("target").unwrap();
+    let target =
+        parse_url(target).with_context(|| format!("Failed to parse target URL: <{}>", target))?;
+    let storage = open_storage(matches)?;
+    let templates = match matches.value_of("templates") {
+        Some(dir) => Templates::read_dir(dir)
+            .with_context(|| format!("Failed to read templates from {}", dir))?,
+        None => Templates::default(),
+    };
+    let renderer = Renderer {
+        templates,
+        include_pending: matches.is_present("include-pending"),
+        ..Renderer::default()
+    };
+
+    let html = renderer.render(&storage.lookup_by_target(target)?);
+    match matches.value_of("output") {
+        Some(path) => {
+            std::fs::write(path, html).with_context(|| format!("Failed to write {}", path))
+        }
+        None => {
+            print!("{}", html);
+            Ok(())
+        }
+    }
+}
+
+async fn discover_verbose(
+    client: &HttpClient,
+    discovery: &DiscoveryOptions,
+    target: &Url,
+) -> Result<Option<Url>> {
+    let report = webmention::endpoint_discovery::discover_with(client, discovery, target)
+        .await
+        .with_context(|| format!("Failed to discover endpoint of <{}>", target))?;
+    for redirect in report.redirects.iter() {
+        println!(
+            "{} <{}> -> <{}>",
+            redirect.status.as_u16(),
+            redirect.from,
+            redirect.to
+        );
+    }
+    if report.cached {
+        println!("Cached, fetched from <{}>", report.fetched_url);
+    } else {
+        println!("Fetched <{}>", report.fetched_url);
+    }
+
+    if report.cached {
+        // candidates are not cached
+    } else if report.candidates.is_empty() {
+        println!("No candidates");
+    } else {
+        println!("Candidates:");
+    }
+    let chosen = report
+        .candidates
+        .iter()
+        .position(|candidate| candidate.url.is_some());
+    for (i, candidate) in report.candidates.iter().enumerate() {
+        let resolved = candidate
+            .url
+            .as_ref()
+            .map_or_else(|| "invalid URL".to_string(), |url| format!("<{}>", url));
+        println!(
+            "{}\t{} #{}\t{:?} -> {}",
+            if chosen == Some(i) { "*" } else { "" },
+            candidate.source,
+            candidate.position,
+            candidate.href,
+            resolved
+        );
+    }
+
+    Ok(report.endpoint)
+}
+
+mod queue {
+    use anyhow::{anyhow, Context, Result};
+    use clap::ArgMatches;
+    use std::time::{Duration, SystemTime};
+    use webmention::endpoint_discovery::DiscoveryOptions;
+    use webmention::http_client::HttpClient;
+    use webmention::queue::{DeliveryState, FileSendQueue, QueuedWebmention, SendQueue, Worker};
+    use webmention::webmention::Webmention;
+
+    fn describe(entry: &QueuedWebmention) -> String {
+        let details = match entry.state {
+            DeliveryState::Pending if entry.attempts == 0 => "not attempted yet".to_string(),
+            DeliveryState::Pending => format!(
+                "next attempt {}, last error: {}",
+                httpdate::fmt_http_date(entry.next_attempt_at),
+                entry.last_error.as_deref().unwrap_or("none")
+            ),
+            DeliveryState::Delivered | DeliveryState::Rejected => {
+                entry.outcome.clone().unwrap_or_default()
+            }
+            DeliveryState::Failed => entry.last_error.clone().unwrap_or_default(),
+        };
+        format!(
+            "{}\t{}\t{} attempts\t<{}> -> <{}>\t{}",
+            entry.id,
+            entry.state,
+            entry.attempts,
+            entry.mention.source,
+            entry.mention.target,
+            details
+        )
+    }
+
+    fn ids(matches: &ArgMatches<'_>) -> Result<Vec<u64>> {
+        matches
+            .values_of("id")
+            .into_iter()
+            .flatten()
+            .map(|id| id.parse().map_err(|_| anyhow!("Invalid id {}", id)))
+            .collect()
+    }
+
+    pub async fn run(
+        client: HttpClient,
+        discovery_options: DiscoveryOptions,
+        matches: &ArgMatches<'_>,
+    ) -> Result<()> {
+        let dir = matches.value_of("dir").unwrap();
+        let queue =
+            FileSendQueue::open(dir).with_context(|| format!("Failed to open queue in {}", dir))?;
+
+        match matches.subcommand() {
+            ("add", Some(add_matches)) => {
+                let source = add_matches.value_of("source").unwrap();
+                let source = super::parse_url(source)
+                    .with_context(|| format!("Failed to parse source URL: <{}>", source))?;
+                let targets = match add_matches.value_of("target") {
+                    Some(target) => vec![super::parse_url(target)
+                        .with_context(|| format!("Failed to parse target URL: <{}>", target))?],
+                    None => super::fetch_links(&client, &source)
+                        .await
+                        .with_context(|| format!("Failed to fetch links from <{}>", source))?
+                        .into_iter()
+                        .collect(),
+                };
+                if targets.is_empty() {
+                    println!("No links found");
+                }
+                for target in targets {
+                    let mut mention = Webmention::from((source.clone(), target));
+                    // links were taken from the source itself
+                    if add_matches.value_of("target").is_none() {
+                        mention.set_checked(true);
+                    }
+                    let entry = queue.push(mention)?;
+                    println!("Queued {}\t<{}>", entry.id, entry.mention.target);
+                }
+            }
+            ("list", _) => {
+                for entry in queue.list()? {
+                    println!("{}", describe(&entry));
+                }
+            }
+            ("retry", Some(retry_matches)) => {
+                for id in ids(retry_matches)? {
+                    let mut entry = queue
+                        .get(id)?
+                        .ok_or_else(|| anyhow!("No webmention with id {} in the queue", id))?;
+                    entry.reschedule(SystemTime::now());
+                    queue.update(&entry)?;
+                    println!("{}", describe(&entry));
+                }
+            }
+            ("drop", Some(drop_matches)) => {
+                for id in ids(drop_matches)? {
+                    if !queue.remove(id)? {
+                        return Err(anyhow!("No webmention with id {} in the queue", id));
+                    }
+                    println!("Dropped {}", id);
+                }
+            }
+            ("work", Some(work_matches)) => {
+                let mut worker = Worker {
+                    client,
+                    discovery_options,
+                    ..Worker::default()
+                };
+                if let Some(interval) = work_matches.value_of("interval") {
+                    let seconds = interval
+                        .parse()
+                        .map_err(|_| anyhow!("--interval expects seconds, got {}", interval))?;
+                    worker.poll_interval = Duration::from_secs(seconds);
+                }
+                loop {
+                    for entry in worker.process_due(&queue, SystemTime::now()).await? {
+                        println!("{}", describe(&entry));
+                    }
+                    if work_matches.is_present("once") {
+                        break;
+                    }
+                    tokio::time::sleep(worker.poll_interval).await;
+                }
+            }
+            _ => return Err(anyhow!("No queue command specified, see `queue --help`")),
+        }
+        Ok(())
+    }
+}
+
 #[cfg(feature = "receive")]
 mod receive {
     use anyhow::Result;
-    use rocket::request::Form;
+    use rocket::http::Status;
+    use rocket::request::{Form, Request};
+    use rocket::response::{self, Responder, Response};
     use rocket::State;
+    use std::io::Cursor;
+    use std::net::SocketAddr;
+    use std::time::Duration;
     use url::Url;
-    use webmention::storage::InMemoryWebmentionStorage;
+    use webmention::error::WebmentionError;
+    use webmention::receiver::Receiver;
+    use webmention::storage::{
+        InMemoryWebmentionStorage, JsonlWebmentionStorage, WebmentionStorage,
+    };
+    use webmention::webmention::Webmention;
+
+    /// Domain for which we receive webmentions
+    struct Domain(Url);
+
+    /// Received webmentions, kept in a file when `--storage` is given
+    enum Storage {
+        Memory(InMemoryWebmentionStorage),
+        File(JsonlWebmentionStorage),
+    }
+
+    impl WebmentionStorage for Storage {
+        fn store(&self, mention: Webmention) -> Result<(), WebmentionError> {
+            match self {
+                Storage::Memory(storage) => storage.store(mention),
+                Storage::File(storage) => storage.store(mention),
+            }
+        }
+
+        fn lookup_by_target(&self, target: Url) -> Result<Vec<Webmention>, WebmentionError> {
+            match self {
+                Storage::Memory(storage) => storage.lookup_by_target(target),
+                Storage::File(storage) => storage.lookup_by_target(target),
+            }
+        }
+
+        fn list(&self) -> Result<Vec<Webmention>, WebmentionError> {
+            match self {
+                Storage::Memory(storage) => storage.list(),
+                Storage::File(storage) => storage.list(),
+            }
+        }
+    }
+
+    /// Rocket handlers are synchronous, so every request gets its own runtime for the async part
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Could not start runtime")
+            .block_on(future)
+    }
+
+    enum Reply {
+        Text(&'static str),
+        TooManyRequests(Duration),
+    }
+
+    impl<'r> Responder<'r> for Reply {
+        fn respond_to(self, request: &Request) -> response::Result<'r> {
+            match self {
+                Reply::Text(text) => text.respond_to(request),
+                Reply::TooManyRequests(retry_after) => Response::build()
+                    .status(Status::TooManyRequests)
+                    .raw_header("Retry-After", retry_after.as_secs().max(1).to_string())
+                    .sized_body(Cursor::new("TOO MANY REQUESTS"))
+                    .ok(),
+            }
+        }
+    }
 
     #[derive(FromForm)]
     struct WebmentionAttempt {
@@ -113,34 +562,76 @@ mod receive {
 
     #[post("/webmention", data = "<webmention>")]
     fn webmention_endpoint(
-        storage: State<InMemoryWebmentionStorage>,
+        storage: State<Storage>,
+        receiver: State<Receiver>,
+        domain: State<Domain>,
+        remote: SocketAddr,
         webmention: Form<WebmentionAttempt>,
-    ) -> &'static str {
+    ) -> Reply {
         let urls = (
             Url::parse(&webmention.source),
             Url::parse(&webmention.target),
         );
         if let Ok(source_url) = urls.0 {
             if let Ok(target_url) = urls.1 {
-                match async_std::task::block_on(webmention::receive_webmention(
+                if !receiver.equivalence.same_host(&target_url, &domain.0) {
+                    return Reply::Text("NOT OK");
+                }
+                match block_on(receiver.receive(
                     &*storage,
                     &source_url,
                     &target_url,
+                    Some(remote.ip()),
                 )) {
-                    Ok(true) => return "OK",
-                    Ok(false) => return "NOT OK",
-                    Err(_) => return "ALSO NOT OK",
+                    Ok(true) => return Reply::Text("OK"),
+                    Ok(false) => return Reply::Text("NOT OK"),
+                    Err(WebmentionError::TooManyRequests { retry_after, .. }) => {
+                        return Reply::TooManyRequests(retry_after)
+                    }
+                    Err(_) => return Reply::Text("ALSO NOT OK"),
                 }
             }
         }
-        "NOT OK"
+        Reply::Text("NOT OK")
     }
 
-    pub async fn start_receiver(_domain: Url) -> Result<()> {
-        rocket::ignite()
-            .manage(webmention::storage::InMemoryWebmentionStorage::new())
-            .mount("/", routes![webmention_endpoint])
-            .launch();
+    #[cfg(feature = "pingback")]
+    #[post("/pingback", data = "<body>")]
+    fn pingback_endpoint(
+        storage: State<Storage>,
+        receiver: State<Receiver>,
+        domain: State<Domain>,
+        remote: SocketAddr,
+        body: String,
+    ) -> rocket::response::content::Xml<String> {
+        rocket::response::content::Xml(block_on(webmention::pingback::receive_pingback_with(
+            &*receiver,
+            &*storage,
+            &domain.0,
+            &body,
+            Some(remote.ip()),
+        )))
+    }
+
+    pub async fn start_receiver(
+        receiver: Receiver,
+        domain: Url,
+        storage: Option<JsonlWebmentionStorage>,
+    ) -> Result<()> {
+        let storage = match storage {
+            Some(storage) => Storage::File(storage),
+            None => Storage::Memory(InMemoryWebmentionStorage::with_equivalence(
+                receiver.equivalence.clone(),
+            )),
+        };
+        let rocket = rocket::ignite()
+            .manage(storage)
+            .manage(receiver)
+            .manage(Domain(domain))
+            .mount("/", routes![webmention_endpoint]);
+        #[cfg(feature = "pingback")]
+        let rocket = rocket.mount("/", routes![pingback_endpoint]);
+        rocket.launch();
         Ok(())
     }
 }
@@ -151,7 +642,70 @@ async fn main() -> Result<()> {
     let app = App::new("webmention")
         .version("0.1.0")
         .author("Tim Marinin <mt@marinintim.com>")
-        .about("Send and receive webmentions");
+        .about("Send and receive webmentions")
+        .arg(
+            Arg::with_name("allow-host")
+                .long("allow-host")
+                .value_name("HOST")
+                .help("Allow requests to HOST even if it is not a public address, e.g. localhost")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("head-first")
+                .long("head-first")
+                .help("Look for endpoints in response to HEAD before downloading targets")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("endpoint-cache")
+                .long("endpoint-cache")
+                .value_name("FILE")
+                .help("Remember discovered endpoints in FILE, as long as targets allow caching")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("cache-per-origin")
+                .long("cache-per-origin")
+                .help("Use endpoint discovered for one page for all pages of the same site")
+                .requires("endpoint-cache")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("max-attempts")
+                .long("max-attempts")
+                .value_name("N")
+                .help("Make up to N attempts when a request fails with a transient error [default: 3]")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("max-concurrent")
+                .long("max-concurrent")
+                .value_name("N")
+                .help("Make at most N requests at once [default: 16]")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("per-host-concurrent")
+                .long("per-host-concurrent")
+                .value_name("N")
+                .help("Make at most N requests at once to a single host [default: 2]")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("per-host-rate")
+                .long("per-host-rate")
+                .value_name("RATE")
+                .help("Start at most RATE requests per second to a single host, 0 for no limit [default: 2]")
+                .takes_value(true)
+                .global(true),
+        );
 
     let app = app.subcommand(
         SubCommand::with_name("send")
@@ -186,6 +740,78 @@ async fn main() -> Result<()> {
                     .help("Domain for which we intend to receive webmentions")
                     .takes_value(true)
                     .required(true),
+            )
+            .arg(
+                Arg::with_name("per-client")
+                    .long("per-client")
+                    .value_name("N")
+                    .help("Accept N webmentions per minute from a single address or IPv6 /64 network [default: 30]")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("per-source-host")
+                    .long("per-source-host")
+                    .value_name("N")
+                    .help("Accept N webmentions per minute with sources on a single host [default: 10]")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("max-pending")
+                    .long("max-pending")
+                    .value_name("N")
+                    .help("Verify at most N webmentions for a single target at once [default: 5]")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("blocklist")
+                    .long("blocklist")
+                    .value_name("FILE")
+                    .help("Refuse webmentions from domains listed in FILE")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("allowlist")
+                    .long("allowlist")
+                    .value_name("FILE")
+                    .help("Approve webmentions from domains listed in FILE without moderation")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("spam-filter")
+                    .long("spam-filter")
+                    .value_name("FILTER")
+                    .help("Judge sources that are not allowed with this spam filter")
+                    .possible_values(&["heuristic", "akismet"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("akismet-key")
+                    .long("akismet-key")
+                    .value_name("KEY")
+                    .help("API key of Akismet, for --spam-filter akismet")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("akismet-endpoint")
+                    .long("akismet-endpoint")
+                    .value_name("URL")
+                    .help("comment-check URL of an Akismet-compatible service, instead of Akismet itself")
+                    .takes_value(true)
+                    .conflicts_with("akismet-key"),
+            )
+            .arg(
+                Arg::with_name("summary-length")
+                    .long("summary-length")
+                    .value_name("N")
+                    .help("Store summaries of sources of at most N characters [default: 280]")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("storage")
+                    .long("storage")
+                    .value_name("FILE")
+                    .help("Store webmentions in FILE, which other commands can share, not in memory")
+                    .takes_value(true),
             ),
     );
 
@@ -198,6 +824,285 @@ async fn main() -> Result<()> {
                     .help("URL that we want to discover endpoint for")
                     .index(1)
                     .required(true),
+            )
+            .arg(
+                Arg::with_name("verbose")
+                    .short("v")
+                    .long("verbose")
+                    .help("Print redirects and every endpoint candidate found while discovering"),
+            ),
+    );
+
+    let id_arg = || {
+        Arg::with_name("id")
+            .value_name("ID")
+            .help("Id of a queued webmention, as printed by `queue list`")
+            .index(1)
+            .multiple(true)
+            .required(true)
+    };
+    let app = app.subcommand(
+        SubCommand::with_name("queue")
+            .about("queue webmentions and deliver them in background")
+            .arg(
+                Arg::with_name("dir")
+                    .long("dir")
+                    .value_name("DIR")
+                    .help("Directory with the queue, created if needed")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about("queue webmentions for links of a page, or for one link")
+                    .arg(
+                        Arg::with_name("source")
+                            .short("f")
+                            .long("from")
+                            .value_name("URL")
+                            .help("The URL that we're linking from")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("target")
+                            .short("t")
+                            .long("to")
+                            .value_name("URL")
+                            .help("The URL that we had linked to"),
+                    ),
+            )
+            .subcommand(SubCommand::with_name("list").about("list queued webmentions"))
+            .subcommand(
+                SubCommand::with_name("retry")
+                    .about("make webmentions pending again, with a fresh count of attempts")
+                    .arg(id_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("drop")
+                    .about("remove webmentions from the queue")
+                    .arg(id_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("work")
+                    .about("deliver queued webmentions as they become due")
+                    .arg(
+                        Arg::with_name("once")
+                            .long("once")
+                            .help("Deliver the webmentions that are due and exit"),
+                    )
+                    .arg(
+                        Arg::with_name("interval")
+                            .long("interval")
+                            .value_name("SECONDS")
+                            .help("How often to look for due webmentions [default: 30]")
+                            .takes_value(true),
+                    ),
+            ),
+    );
+
+    #[cfg(feature = "receiver")]
+    let pattern_arg = || {
+        Arg::with_name("pattern")
+            .value_name("DOMAIN")
+            .help("Domain, *.domain for the domain with subdomains, or URL")
+            .index(1)
+            .multiple(true)
+            .required(true)
+    };
+    #[cfg(feature = "receiver")]
+    let app = app.subcommand(
+        SubCommand::with_name("domain-list")
+            .about("edit, import and export lists of blocked or allowed domains")
+            .arg(
+                Arg::with_name("file")
+                    .long("file")
+                    .value_name("FILE")
+                    .help("The list, one domain per line, created if needed")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about("add domains")
+                    .arg(pattern_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("remove")
+                    .about("remove domains")
+                    .arg(pattern_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("import")
+                    .about("add every domain of other lists")
+                    .arg(
+                        Arg::with_name("other")
+                            .value_name("OTHER")
+                            .help("File with a list in the same format")
+                            .index(1)
+                            .multiple(true)
+                            .required(true),
+                    ),
+            )
+            .subcommand(SubCommand::with_name("export").about("print the list in normalized form")),
+    );
+
+    #[cfg(feature = "receiver")]
+    let app = app.subcommand(
+        SubCommand::with_name("render")
+            .about("render stored webmentions of a target as HTML")
+            .arg(
+                Arg::with_name("target")
+                    .short("t")
+                    .long("target")
+                    .value_name("URL")
+                    .help("The URL whose webmentions are rendered")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("storage")
+                    .long("storage")
+                    .value_name("FILE")
+                    .help("Stored webmentions, one JSON object per line")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("templates")
+                    .long("templates")
+                    .value_name("DIR")
+                    .help("Directory with templates replacing the default ones, such as reply.html")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("include-pending")
+                    .long("include-pending")
+                    .help("Render webmentions that are not approved yet"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .help("Write HTML to FILE instead of printing it")
+                    .takes_value(true),
+            ),
+    );
+
+    #[cfg(feature = "receiver")]
+    let app = app.subcommand(
+        SubCommand::with_name("import")
+            .about("import webmentions received elsewhere, skipping those already stored")
+            .arg(
+                Arg::with_name("storage")
+                    .long("storage")
+                    .value_name("FILE")
+                    .help("Stored webmentions, one JSON object per line, created if needed")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Format of the imported files")
+                    .possible_values(&["webmention-io", "jsonl", "csv", "jf2"])
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("file")
+                    .value_name("FILE")
+                    .help("File to import, such as a JF2 export of webmention.io")
+                    .index(1)
+                    .multiple(true)
+                    .required(true),
+            ),
+    );
+
+    #[cfg(feature = "receiver")]
+    let app = app.subcommand(
+        SubCommand::with_name("export")
+            .about("export stored webmentions for backups, other storages or spreadsheets")
+            .arg(
+                Arg::with_name("storage")
+                    .long("storage")
+                    .value_name("FILE")
+                    .help("Stored webmentions, one JSON object per line")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Format of the export, which can be imported back")
+                    .possible_values(&["jsonl", "csv", "jf2"])
+                    .default_value("jsonl")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .help("Write the export to FILE instead of printing it")
+                    .takes_value(true),
+            ),
+    );
+
+    #[cfg(feature = "receiver")]
+    let mention_args = || {
+        [
+            Arg::with_name("source")
+                .short("s")
+                .long("source")
+                .value_name("URL")
+                .help("Source of the webmention")
+                .takes_value(true)
+                .required(true),
+            Arg::with_name("target")
+                .short("t")
+                .long("target")
+                .value_name("URL")
+                .help("Target of the webmention")
+                .takes_value(true)
+                .required(true),
+        ]
+    };
+    #[cfg(feature = "receiver")]
+    let app = app.subcommand(
+        SubCommand::with_name("storage")
+            .about("moderate, delete and compact stored webmentions")
+            .arg(
+                Arg::with_name("storage")
+                    .long("storage")
+                    .value_name("FILE")
+                    .help("Stored webmentions, one JSON object per line")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .subcommand(
+                SubCommand::with_name("moderate")
+                    .about("approve a webmention, mark it as spam or as pending again")
+                    .args(&mention_args())
+                    .arg(
+                        Arg::with_name("moderation")
+                            .value_name("MODERATION")
+                            .possible_values(&["approved", "pending", "spam"])
+                            .index(1)
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("delete")
+                    .about("delete a webmention")
+                    .args(&mention_args()),
+            )
+            .subcommand(
+                SubCommand::with_name("compact")
+                    .about("rewrite the file with only the current state of every webmention"),
             ),
     );
 
@@ -207,6 +1112,45 @@ async fn main() -> Result<()> {
 
     let matches = app.get_matches();
 
+    let mut client = HttpClient::new(AddressPolicy {
+        allowed_hosts: matches
+            .values_of("allow-host")
+            .map(|hosts| hosts.map(|host| host.to_string()).collect())
+            .unwrap_or_default(),
+        ..AddressPolicy::default()
+    });
+    if let Some(attempts) = matches.value_of("max-attempts") {
+        client.retry_policy.max_attempts = parse_positive(attempts, "--max-attempts")?;
+    }
+    let mut limits = RateLimits::default();
+    if let Some(n) = matches.value_of("max-concurrent") {
+        limits.max_concurrent = parse_positive(n, "--max-concurrent")?;
+    }
+    if let Some(n) = matches.value_of("per-host-concurrent") {
+        limits.max_concurrent_per_host = parse_positive(n, "--per-host-concurrent")?;
+    }
+    if let Some(rate) = matches.value_of("per-host-rate") {
+        limits.requests_per_second_per_host = rate
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| *rate >= 0.0)
+            .ok_or_else(|| anyhow!("--per-host-rate expects a number, got {}", rate))?;
+    }
+    client.scheduler = Arc::new(RequestScheduler::new(limits));
+    let mut discovery = DiscoveryOptions::default();
+    if matches.is_present("head-first") {
+        discovery.strategy = DiscoveryStrategy::HeadFirst;
+    }
+    if let Some(path) = matches.value_of("endpoint-cache") {
+        let cache = FileEndpointCache::open(path)
+            .with_context(|| format!("Failed to open endpoint cache <{}>", path))?;
+        discovery.endpoint_cache = if matches.is_present("cache-per-origin") {
+            Some(Arc::new(PerOriginCache(cache)))
+        } else {
+            Some(Arc::new(cache))
+        };
+    }
+
     if let Some(send_matches) = matches.subcommand_matches("send") {
         let source = send_matches.value_of("source").unwrap();
         let source = parse_url(source)
@@ -216,9 +1160,10 @@ async fn main() -> Result<()> {
             let target = parse_url(target)
                 .with_context(|| format!("Failed to parse target URL: <{}>", target))?;
 
-            send_link((source, target)).await?;
+            let acceptance = send_link(&client, &discovery, (source, target)).await?;
+            println!("{}", acceptance);
         } else {
-            send_all(source).await?;
+            send_all(&client, &discovery, source).await?;
         }
         return Ok(());
     } else if let Some(_receive_matches) = matches.subcommand_matches("receive") {
@@ -227,20 +1172,102 @@ async fn main() -> Result<()> {
             let domain = _receive_matches.value_of("domain").unwrap();
             let domain = parse_url(domain)
                 .with_context(|| format!("Failed to parse domain URL: <{}>", domain))?;
-            receive::start_receiver(domain).await?;
+            let mut limits = webmention::receiver::limit::ReceiveLimits::default();
+            if let Some(n) = _receive_matches.value_of("per-client") {
+                limits.per_client = parse_positive(n, "--per-client")?;
+            }
+            if let Some(n) = _receive_matches.value_of("per-source-host") {
+                limits.per_source_host = parse_positive(n, "--per-source-host")?;
+            }
+            if let Some(n) = _receive_matches.value_of("max-pending") {
+                limits.max_pending_per_target = parse_positive(n, "--max-pending")?;
+            }
+            let mut source_policy = webmention::receiver::policy::SourcePolicy::default();
+            if let Some(path) = _receive_matches.value_of("blocklist") {
+                source_policy.blocked = DomainList::read(path)
+                    .with_context(|| format!("Failed to read blocklist {}", path))?;
+            }
+            if let Some(path) = _receive_matches.value_of("allowlist") {
+                source_policy.allowed = DomainList::read(path)
+                    .with_context(|| format!("Failed to read allowlist {}", path))?;
+            }
+            let spam_filter: Option<Arc<dyn SpamFilter>> =
+                match _receive_matches.value_of("spam-filter") {
+                    Some("heuristic") => Some(Arc::new(HeuristicFilter::default())),
+                    Some(_) => {
+                        let mut filter =
+                            if let Some(endpoint) = _receive_matches.value_of("akismet-endpoint") {
+                                let endpoint = parse_url(endpoint).with_context(|| {
+                                    format!("Failed to parse Akismet endpoint URL: <{}>", endpoint)
+                                })?;
+                                AkismetFilter::with_endpoint(endpoint, domain.clone())
+                            } else if let Some(key) = _receive_matches.value_of("akismet-key") {
+                                AkismetFilter::new(key, domain.clone())
+                                    .context("Failed to build Akismet endpoint URL")?
+                            } else {
+                                return Err(anyhow!("Akismet needs a key or an endpoint"));
+                            };
+                        filter.client = client.clone();
+                        Some(Arc::new(filter))
+                    }
+                    None => None,
+                };
+            let mut snapshot_options = webmention::snapshot::SnapshotOptions::default();
+            if let Some(n) = _receive_matches.value_of("summary-length") {
+                snapshot_options.max_summary_length = parse_positive(n, "--summary-length")?;
+            }
+            let receiver = webmention::receiver::Receiver {
+                limiter: Some(Arc::new(webmention::receiver::limit::ReceiveLimiter::new(
+                    limits,
+                ))),
+                source_policy,
+                spam_filter,
+                snapshot_options,
+                ..webmention::receiver::Receiver::new(client)
+            };
+            let storage = match _receive_matches.value_of("storage") {
+                Some(path) => Some(
+                    JsonlWebmentionStorage::with_equivalence(path, receiver.equivalence.clone())
+                        .with_context(|| format!("Failed to open {}", path))?,
+                ),
+                None => None,
+            };
+            receive::start_receiver(receiver, domain, storage).await?;
             return Ok(());
         }
     } else if let Some(discover_matches) = matches.subcommand_matches("discover-endpoint") {
         let target = discover_matches.value_of("target").unwrap();
         let target = parse_url(target)
             .with_context(|| format!("Failed to parse target URL: <{}>", target))?;
-        let endpoint = webmention::endpoint_discovery::find_target_endpoint(&target).await?;
+        let endpoint = if discover_matches.is_present("verbose") {
+            discover_verbose(&client, &discovery, &target).await?
+        } else {
+            webmention::endpoint_discovery::find_target_endpoint_with(&client, &discovery, &target)
+                .await?
+        };
         if let Some(endpoint) = endpoint {
             println!("{}", endpoint);
         } else {
             println!("Endpoint of <{}> could not be determined", target);
         }
         return Ok(());
+    } else if let Some(queue_matches) = matches.subcommand_matches("queue") {
+        return queue::run(client, discovery, queue_matches).await;
+    } else if let Some(_list_matches) = matches.subcommand_matches("domain-list") {
+        #[cfg(feature = "receiver")]
+        return edit_domain_list(_list_matches);
+    } else if let Some(_render_matches) = matches.subcommand_matches("render") {
+        #[cfg(feature = "receiver")]
+        return render_mentions(_render_matches);
+    } else if let Some(_import_matches) = matches.subcommand_matches("import") {
+        #[cfg(feature = "receiver")]
+        return import_mentions(_import_matches);
+    } else if let Some(_export_matches) = matches.subcommand_matches("export") {
+        #[cfg(feature = "receiver")]
+        return export_mentions(_export_matches);
+    } else if let Some(_storage_matches) = matches.subcommand_matches("storage") {
+        #[cfg(feature = "receiver")]
+        return edit_storage(_storage_matches);
     }
     println!("{}", help);
     Err(anyhow!("No command specified"))
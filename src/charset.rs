@@ -0,0 +1,279 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+
+/// How many bytes of the document are scanned for `<meta charset>`, as in the HTML spec
+const PRESCAN_LIMIT: usize = 1024;
+
+/// Decodes fetched document, detecting its encoding as described in the [HTML encoding
+/// sniffing algorithm][sniffing]: byte order mark, then `charset` of `Content-Type`, then
+/// `<meta>` prescan (or XML declaration for XML documents), defaulting to UTF-8.
+///
+/// [sniffing]: https://html.spec.whatwg.org/multipage/parsing.html#encoding-sniffing-algorithm
+pub fn decode(body: &[u8], content_type: Option<&str>) -> String {
+    let (text, _, _) = sniff(body, content_type).decode(body);
+    text.to_string()
+}
+
+/// Detects the encoding of the document, see [decode].
+pub fn sniff(body: &[u8], content_type: Option<&str>) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return encoding;
+    }
+
+    if let Some(encoding) = content_type
+        .and_then(charset_param)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+    {
+        return encoding;
+    }
+
+    let essence = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|essence| essence.trim().to_ascii_lowercase());
+    let from_document = match essence.as_deref() {
+        Some("text/html") | Some("application/xhtml+xml") | None => prescan(body),
+        Some(xml) if xml.ends_with("/xml") || xml.ends_with("+xml") => xml_declaration(body),
+        _ => None,
+    };
+
+    from_document.unwrap_or(UTF_8)
+}
+
+/// Extracts `charset` parameter from `Content-Type` header or `content` attribute.
+fn charset_param(content_type: &str) -> Option<String> {
+    let lowercase = content_type.to_ascii_lowercase();
+    let start = lowercase.find("charset")? + "charset".len();
+    let rest = lowercase[start..]
+        .trim_start()
+        .strip_prefix('=')?
+        .trim_start();
+    let value = match rest.chars().next()? {
+        quote @ '"' | quote @ '\'' => rest[1..].split(quote).next()?,
+        _ => rest.split(|c: char| c == ';' || c.is_whitespace()).next()?,
+    };
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// Encoding declared in the document itself must not be UTF-16, since we could read it as ASCII.
+fn declared(label: &[u8]) -> Option<&'static Encoding> {
+    let encoding = Encoding::for_label(label)?;
+    if encoding == UTF_16BE || encoding == UTF_16LE {
+        Some(UTF_8)
+    } else if encoding.name() == "x-user-defined" {
+        Some(WINDOWS_1252)
+    } else {
+        Some(encoding)
+    }
+}
+
+/// Looks for `<meta charset>` or `<meta http-equiv="Content-Type" content="...; charset=...">`
+/// in the beginning of the document, skipping comments and other tags.
+fn prescan(body: &[u8]) -> Option<&'static Encoding> {
+    let head = &body[..body.len().min(PRESCAN_LIMIT)];
+    let mut position = 0;
+
+    while position < head.len() {
+        let rest = &head[position..];
+        if rest.starts_with(b"<!--") {
+            position += find(&rest[4..], b"-->").map_or(rest.len(), |end| end + 4 + 3);
+        } else if starts_with_ignore_case(rest, b"<meta")
+            && rest.get(5).is_some_and(|&c| is_space(c) || c == b'/')
+        {
+            let (attributes, length) = attributes(&rest[5..]);
+            position += 5 + length;
+
+            let mut charset = None;
+            let mut http_equiv = false;
+            let mut content = None;
+            for (name, value) in attributes {
+                match name.as_str() {
+                    "charset" if charset.is_none() => charset = Some(value),
+                    "http-equiv" => http_equiv = value.eq_ignore_ascii_case("content-type"),
+                    "content" if content.is_none() => content = Some(value),
+                    _ => {}
+                }
+            }
+
+            let label = charset.or_else(|| {
+                if http_equiv {
+                    content.as_deref().and_then(charset_param)
+                } else {
+                    None
+                }
+            });
+            if let Some(encoding) = label.and_then(|label| declared(label.trim().as_bytes())) {
+                return Some(encoding);
+            }
+        } else if rest.starts_with(b"<") && rest.len() > 1 {
+            // any other tag, closing tag, doctype or processing instruction
+            let (_, length) = attributes(&rest[1..]);
+            position += 1 + length;
+        } else {
+            position += 1;
+        }
+    }
+    None
+}
+
+/// Looks for `encoding` in `<?xml version="1.0" encoding="...">`.
+fn xml_declaration(body: &[u8]) -> Option<&'static Encoding> {
+    if !body.starts_with(b"<?xml") {
+        return None;
+    }
+    let end = find(body, b"?>")?;
+    let (attributes, _) = attributes(&body[5..end]);
+    attributes
+        .into_iter()
+        .find(|(name, _)| name == "encoding")
+        .and_then(|(_, value)| declared(value.as_bytes()))
+}
+
+/// Parses attributes of a tag up to `>`. Returns attributes with lowercased names and how many
+/// bytes were consumed.
+fn attributes(tag: &[u8]) -> (Vec<(String, String)>, usize) {
+    let mut attributes = Vec::new();
+    let mut position = 0;
+
+    loop {
+        while position < tag.len() && (is_space(tag[position]) || tag[position] == b'/') {
+            position += 1;
+        }
+        if position >= tag.len() {
+            return (attributes, position);
+        }
+        if tag[position] == b'>' {
+            return (attributes, position + 1);
+        }
+
+        let name_start = position;
+        while position < tag.len()
+            && !is_space(tag[position])
+            && !matches!(tag[position], b'=' | b'>' | b'/')
+        {
+            position += 1;
+        }
+        let name = String::from_utf8_lossy(&tag[name_start..position]).to_ascii_lowercase();
+
+        while position < tag.len() && is_space(tag[position]) {
+            position += 1;
+        }
+        let mut value = String::new();
+        if tag.get(position) == Some(&b'=') {
+            position += 1;
+            while position < tag.len() && is_space(tag[position]) {
+                position += 1;
+            }
+            match tag.get(position) {
+                Some(&quote) if quote == b'"' || quote == b'\'' => {
+                    let value_start = position + 1;
+                    let value_end = tag[value_start..]
+                        .iter()
+                        .position(|&c| c == quote)
+                        .map_or(tag.len(), |end| value_start + end);
+                    value = String::from_utf8_lossy(&tag[value_start..value_end]).to_string();
+                    position = (value_end + 1).min(tag.len());
+                }
+                _ => {
+                    let value_start = position;
+                    while position < tag.len() && !is_space(tag[position]) && tag[position] != b'>'
+                    {
+                        position += 1;
+                    }
+                    value = String::from_utf8_lossy(&tag[value_start..position]).to_string();
+                }
+            }
+        }
+        attributes.push((name, value));
+    }
+}
+
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r' | b'\x0c')
+}
+
+fn starts_with_ignore_case(haystack: &[u8], prefix: &[u8]) -> bool {
+    haystack.len() >= prefix.len() && haystack[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, sniff};
+    use encoding_rs::{SHIFT_JIS, UTF_16LE, UTF_8, WINDOWS_1251, WINDOWS_1252};
+
+    #[test]
+    fn test_bom_wins() {
+        let body = b"\xEF\xBB\xBF<meta charset=windows-1251>";
+        assert_eq!(sniff(body, Some("text/html; charset=shift_jis")), UTF_8);
+
+        let body = b"\xFF\xFEh\x00i\x00";
+        assert_eq!(sniff(body, None), UTF_16LE);
+        assert_eq!(decode(body, None), "hi");
+    }
+
+    #[test]
+    fn test_content_type_charset() {
+        let body = b"<meta charset=utf-8>";
+        assert_eq!(
+            sniff(body, Some("text/html; charset=\"windows-1251\"")),
+            WINDOWS_1251
+        );
+        assert_eq!(sniff(body, Some("text/html;charset=Shift_JIS")), SHIFT_JIS);
+    }
+
+    #[test]
+    fn test_meta_charset() {
+        let body = b"<!doctype html><html><head><!-- <meta charset=koi8-r> -->
+            <title>Test</title><meta charset=\"windows-1251\"></head>";
+        assert_eq!(sniff(body, Some("text/html")), WINDOWS_1251);
+
+        let body =
+            b"<html><head><META HTTP-EQUIV='Content-Type' CONTENT='text/html; charset=Shift_JIS'>";
+        assert_eq!(sniff(body, None), SHIFT_JIS);
+
+        // content without http-equiv does not count
+        let body = b"<meta name=description content='charset=Shift_JIS'>";
+        assert_eq!(sniff(body, Some("text/html")), UTF_8);
+
+        // UTF-16 declared in the document means UTF-8
+        let body = b"<meta charset=utf-16>";
+        assert_eq!(sniff(body, Some("text/html")), UTF_8);
+    }
+
+    #[test]
+    fn test_meta_after_prescan_limit() {
+        let mut body = "<!-- padding -->".repeat(100).into_bytes();
+        body.extend_from_slice(b"<meta charset=windows-1251>");
+        assert_eq!(sniff(&body, Some("text/html")), UTF_8);
+    }
+
+    #[test]
+    fn test_xml_declaration() {
+        let body = b"<?xml version=\"1.0\" encoding=\"windows-1252\"?><feed/>";
+        assert_eq!(sniff(body, Some("application/atom+xml")), WINDOWS_1252);
+        assert_eq!(sniff(body, Some("application/json")), UTF_8);
+    }
+
+    #[test]
+    fn test_decode_cyrillic_and_japanese() {
+        let (cyrillic, _, _) = WINDOWS_1251.encode("<meta charset=windows-1251><p>Привет</p>");
+        assert_eq!(
+            decode(&cyrillic, Some("text/html")),
+            "<meta charset=windows-1251><p>Привет</p>"
+        );
+
+        let (japanese, _, _) = SHIFT_JIS.encode("<meta charset=\"Shift_JIS\"><p>こんにちは</p>");
+        assert_eq!(
+            decode(&japanese, Some("text/html")),
+            "<meta charset=\"Shift_JIS\"><p>こんにちは</p>"
+        );
+    }
+}
@@ -0,0 +1,457 @@
+use crate::error::WebmentionError;
+use crate::wm_url::Url;
+use reqwest::header::{
+    HeaderMap, HeaderValue, AGE, CACHE_CONTROL, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Upper bound for freshness guessed from `Last-Modified`, so that old pages that change their
+/// endpoint are noticed within a week
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Result of endpoint discovery remembered for a target, along with what HTTP caching headers
+/// of the target say about it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedEndpoint {
+    /// URL discovery was requested for
+    pub target: Url,
+    /// URL the target was fetched from after redirects
+    pub fetched_url: Url,
+    /// Discovered endpoint, `None` if the target has none
+    pub endpoint: Option<Url>,
+    pub fetched_at: SystemTime,
+    /// The entry could be used without asking the target until then
+    pub fresh_until: SystemTime,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl CachedEndpoint {
+    /// Builds an entry from the headers of the response the target was fetched with, following
+    /// [RFC 9111](https://www.rfc-editor.org/rfc/rfc9111.html): `Cache-Control` takes precedence
+    /// over `Expires`, and without either freshness is guessed from `Last-Modified`.
+    ///
+    /// Returns `None` if the response must not be stored (`Cache-Control: no-store`).
+    pub fn from_headers(
+        target: &Url,
+        fetched_url: &Url,
+        endpoint: Option<Url>,
+        headers: &HeaderMap,
+        now: SystemTime,
+    ) -> Option<CachedEndpoint> {
+        let directives = cache_control(headers);
+        if directives.contains_key("no-store") {
+            return None;
+        }
+
+        Some(CachedEndpoint {
+            target: target.clone(),
+            fetched_url: fetched_url.clone(),
+            endpoint,
+            fetched_at: now,
+            fresh_until: now + freshness_lifetime(headers, &directives, now),
+            etag: header_string(headers, &ETAG),
+            last_modified: header_string(headers, &LAST_MODIFIED),
+        })
+    }
+
+    /// Updates the entry after the target responded with `304 Not Modified`.
+    ///
+    /// Returns `None` if the entry must not be stored anymore.
+    pub fn revalidated(&self, headers: &HeaderMap, now: SystemTime) -> Option<CachedEndpoint> {
+        let mut entry = CachedEndpoint::from_headers(
+            &self.target,
+            &self.fetched_url,
+            self.endpoint.clone(),
+            headers,
+            now,
+        )?;
+        entry.etag = entry.etag.or_else(|| self.etag.clone());
+        entry.last_modified = entry.last_modified.or_else(|| self.last_modified.clone());
+        Some(entry)
+    }
+
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        now < self.fresh_until
+    }
+
+    /// `If-None-Match` and `If-Modified-Since` headers to revalidate the entry with.
+    pub fn conditional_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = self
+            .etag
+            .as_deref()
+            .and_then(|v| HeaderValue::from_str(v).ok())
+        {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = self
+            .last_modified
+            .as_deref()
+            .and_then(|v| HeaderValue::from_str(v).ok())
+        {
+            headers.insert(IF_MODIFIED_SINCE, date);
+        }
+        headers
+    }
+}
+
+fn header_string(headers: &HeaderMap, name: &reqwest::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+}
+
+fn header_date(headers: &HeaderMap, name: &reqwest::header::HeaderName) -> Option<SystemTime> {
+    header_string(headers, name).and_then(|value| httpdate::parse_http_date(&value).ok())
+}
+
+/// Directives of `Cache-Control` with lowercased names and unquoted values
+fn cache_control(headers: &HeaderMap) -> HashMap<String, Option<String>> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn freshness_lifetime(
+    headers: &HeaderMap,
+    directives: &HashMap<String, Option<String>>,
+    now: SystemTime,
+) -> Duration {
+    if directives.contains_key("no-cache") {
+        return Duration::ZERO;
+    }
+
+    let age = header_string(headers, &AGE)
+        .and_then(|age| age.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let date = header_date(headers, &DATE).unwrap_or(now);
+
+    let lifetime = if let Some(max_age) = directives.get("max-age") {
+        max_age
+            .as_deref()
+            .and_then(|max_age| max_age.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default()
+    } else if headers.contains_key(EXPIRES) {
+        // invalid dates, such as "0", mean already expired
+        header_date(headers, &EXPIRES)
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or_default()
+    } else if let Some(last_modified) = header_date(headers, &LAST_MODIFIED) {
+        date.duration_since(last_modified)
+            .map(|since| (since / 10).min(MAX_HEURISTIC_FRESHNESS))
+            .unwrap_or_default()
+    } else {
+        Duration::ZERO
+    };
+
+    lifetime.saturating_sub(age)
+}
+
+/// Storage of discovered endpoints, keyed by target URL.
+pub trait EndpointCache: std::fmt::Debug + Send + Sync {
+    fn get(&self, target: &Url) -> Result<Option<CachedEndpoint>, WebmentionError>;
+    fn put(&self, target: &Url, entry: CachedEndpoint) -> Result<(), WebmentionError>;
+    fn remove(&self, target: &Url) -> Result<(), WebmentionError>;
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryEndpointCache {
+    entries: Mutex<HashMap<Url, CachedEndpoint>>,
+}
+
+impl InMemoryEndpointCache {
+    pub fn new() -> InMemoryEndpointCache {
+        InMemoryEndpointCache::default()
+    }
+}
+
+impl EndpointCache for InMemoryEndpointCache {
+    fn get(&self, target: &Url) -> Result<Option<CachedEndpoint>, WebmentionError> {
+        Ok(self.entries.lock().unwrap().get(target).cloned())
+    }
+
+    fn put(&self, target: &Url, entry: CachedEndpoint) -> Result<(), WebmentionError> {
+        self.entries.lock().unwrap().insert(target.clone(), entry);
+        Ok(())
+    }
+
+    fn remove(&self, target: &Url) -> Result<(), WebmentionError> {
+        self.entries.lock().unwrap().remove(target);
+        Ok(())
+    }
+}
+
+/// Cache kept in a JSON file, so that it survives between runs of the CLI.
+///
+/// The whole file is read on [open](FileEndpointCache::open) and rewritten on every change.
+#[derive(Debug)]
+pub struct FileEndpointCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, CachedEndpoint>>,
+}
+
+impl FileEndpointCache {
+    /// Opens the cache at `path`, which does not have to exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> Result<FileEndpointCache, WebmentionError> {
+        let path = path.into();
+        let entries = match std::fs::read(&path) {
+            Ok(contents) => {
+                serde_json::from_slice(&contents).map_err(|err| WebmentionError::InvalidFile {
+                    path: path.clone(),
+                    source: err.into(),
+                })?
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(source) => return Err(WebmentionError::Io { path, source }),
+        };
+
+        Ok(FileEndpointCache {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Writes to a temporary file first, so that a crash does not leave the cache truncated.
+    fn save(&self, entries: &HashMap<String, CachedEndpoint>) -> Result<(), WebmentionError> {
+        let contents = serde_json::to_vec(entries).map_err(|err| WebmentionError::InvalidFile {
+            path: self.path.clone(),
+            source: err.into(),
+        })?;
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        std::fs::write(&temporary, contents)
+            .and_then(|_| std::fs::rename(&temporary, &self.path))
+            .map_err(|source| WebmentionError::Io {
+                path: self.path.clone(),
+                source,
+            })
+    }
+}
+
+impl EndpointCache for FileEndpointCache {
+    fn get(&self, target: &Url) -> Result<Option<CachedEndpoint>, WebmentionError> {
+        Ok(self.entries.lock().unwrap().get(target.as_str()).cloned())
+    }
+
+    fn put(&self, target: &Url, entry: CachedEndpoint) -> Result<(), WebmentionError> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(target.to_string(), entry);
+        self.save(&entries)
+    }
+
+    fn remove(&self, target: &Url) -> Result<(), WebmentionError> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(target.as_str()).is_some() {
+            self.save(&entries)?;
+        }
+        Ok(())
+    }
+}
+
+/// Heuristic for sites that use one endpoint for all of their pages: keys the wrapped cache by
+/// origin of the target, so that the endpoint discovered for one page is used for all the others.
+///
+/// Validators are only used for the page they were received for, other pages of the origin are
+/// fetched anew once the entry is stale.
+#[derive(Debug)]
+pub struct PerOriginCache<C: EndpointCache>(pub C);
+
+impl<C: EndpointCache> PerOriginCache<C> {
+    fn key(target: &Url) -> Url {
+        Url::parse(&target.origin().ascii_serialization()).unwrap_or_else(|_| target.clone())
+    }
+}
+
+impl<C: EndpointCache> EndpointCache for PerOriginCache<C> {
+    fn get(&self, target: &Url) -> Result<Option<CachedEndpoint>, WebmentionError> {
+        self.0.get(&Self::key(target))
+    }
+
+    fn put(&self, target: &Url, entry: CachedEndpoint) -> Result<(), WebmentionError> {
+        self.0.put(&Self::key(target), entry)
+    }
+
+    fn remove(&self, target: &Url) -> Result<(), WebmentionError> {
+        self.0.remove(&Self::key(target))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        CachedEndpoint, EndpointCache, FileEndpointCache, InMemoryEndpointCache, PerOriginCache,
+    };
+    use crate::http_client::test::TempDir;
+    use crate::wm_url::Url;
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use std::time::{Duration, SystemTime};
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn entry(pairs: &[(&'static str, &str)], now: SystemTime) -> Option<CachedEndpoint> {
+        let target = url("https://example.com/post");
+        CachedEndpoint::from_headers(
+            &target,
+            &target,
+            Some(url("https://example.com/webmention")),
+            &headers(pairs),
+            now,
+        )
+    }
+
+    fn lifetime(pairs: &[(&'static str, &str)]) -> Duration {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let entry = entry(pairs, now).unwrap();
+        entry.fresh_until.duration_since(now).unwrap()
+    }
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_freshness() {
+        let date = ("date", "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        assert_eq!(
+            lifetime(&[("cache-control", "public, max-age=600")]),
+            600 * SEC
+        );
+        assert_eq!(
+            lifetime(&[("cache-control", "max-age=\"600\""), ("age", "100")]),
+            500 * SEC
+        );
+        assert_eq!(
+            lifetime(&[("cache-control", "max-age=600, no-cache")]),
+            Duration::ZERO
+        );
+        assert_eq!(
+            lifetime(&[
+                ("cache-control", "max-age=60"),
+                ("expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+                date
+            ]),
+            60 * SEC
+        );
+        assert_eq!(
+            lifetime(&[("expires", "Sun, 06 Nov 1994 09:49:37 GMT"), date]),
+            3600 * SEC
+        );
+        assert_eq!(lifetime(&[("expires", "0"), date]), Duration::ZERO);
+        assert_eq!(
+            lifetime(&[("last-modified", "Sun, 06 Nov 1994 06:49:37 GMT"), date]),
+            720 * SEC
+        );
+        assert_eq!(
+            lifetime(&[("last-modified", "Tue, 06 Nov 1984 06:49:37 GMT"), date]),
+            7 * 24 * 3600 * SEC
+        );
+        assert_eq!(lifetime(&[date]), Duration::ZERO);
+
+        assert!(entry(
+            &[("cache-control", "no-store, max-age=600")],
+            SystemTime::now()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_revalidation() {
+        let now = SystemTime::now();
+        let stale = entry(
+            &[
+                ("etag", "\"v1\""),
+                ("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("cache-control", "no-cache"),
+            ],
+            now,
+        )
+        .unwrap();
+        assert!(!stale.is_fresh(now));
+
+        let conditional = stale.conditional_headers();
+        assert_eq!(conditional["if-none-match"], "\"v1\"");
+        assert_eq!(
+            conditional["if-modified-since"],
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+
+        let fresh = stale
+            .revalidated(&headers(&[("cache-control", "max-age=60")]), now)
+            .unwrap();
+        assert!(fresh.is_fresh(now));
+        assert_eq!(fresh.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(fresh.endpoint, stale.endpoint);
+    }
+
+    #[test]
+    fn test_file_cache() {
+        let dir = TempDir::new("endpoint-cache");
+        let path = dir.path().join("endpoints.json");
+        let target = url("https://example.com/post");
+        let cached = entry(&[("cache-control", "max-age=60")], SystemTime::now()).unwrap();
+
+        let cache = FileEndpointCache::open(&path).unwrap();
+        assert_eq!(cache.get(&target).unwrap(), None);
+        cache.put(&target, cached.clone()).unwrap();
+
+        let reopened = FileEndpointCache::open(&path).unwrap();
+        assert_eq!(reopened.get(&target).unwrap(), Some(cached));
+        reopened.remove(&target).unwrap();
+        assert_eq!(
+            FileEndpointCache::open(&path)
+                .unwrap()
+                .get(&target)
+                .unwrap(),
+            None
+        );
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(FileEndpointCache::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_per_origin_cache() {
+        let cache = PerOriginCache(InMemoryEndpointCache::new());
+        let cached = entry(&[("cache-control", "max-age=60")], SystemTime::now()).unwrap();
+        cache
+            .put(&url("https://example.com/post"), cached.clone())
+            .unwrap();
+
+        assert_eq!(
+            cache.get(&url("https://example.com/other/page")).unwrap(),
+            Some(cached)
+        );
+        assert_eq!(cache.get(&url("http://example.com/post")).unwrap(), None);
+    }
+}
@@ -1,69 +1,337 @@
 use crate::error::WebmentionError;
-use crate::http_client::get;
+use crate::http_client::{HttpClient, Redirect, Response};
 use crate::wm_url::absolute_url;
 use crate::wm_url::Url;
+use cache::CachedEndpoint;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
 use select::node::Node;
 use select::predicate::{Name, Predicate};
+use std::sync::Arc;
+use std::time::SystemTime;
 
-struct RelWebmention;
-impl Predicate for RelWebmention {
+/// Caching discovered endpoints
+pub mod cache;
+
+struct Rel(&'static str);
+impl Predicate for Rel {
     fn matches(&self, node: &Node) -> bool {
-        node.attr("rel").map_or(false, |rels| {
-            rels.split_whitespace().any(|rel| rel == "webmention")
-        })
+        node.attr("rel")
+            .is_some_and(|rels| rels.split_whitespace().any(|rel| rel == self.0))
     }
 }
 
-pub async fn find_target_endpoint(url: &Url) -> Result<Option<Url>, WebmentionError> {
-    let mut endpoint_candidates: Vec<(usize, Url)> = Vec::new();
+/// How the target is fetched for discovery
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiscoveryStrategy {
+    /// GET the target and look both in the `Link` header and in the document
+    #[default]
+    Get,
+    /// Send HEAD first and use the `Link` header if it advertises an endpoint. Falls back to
+    /// [Get](DiscoveryStrategy::Get) otherwise, or when the target does not support HEAD.
+    /// Saves downloading large targets, such as media pages.
+    HeadFirst,
+}
 
-    let response = get(url)
-        .await
-        .map_err(|source| WebmentionError::DiscoveryRequestFailed {
-            url: url.clone(),
-            source: Box::new(source),
-        })?;
+/// How endpoints are discovered, apart from the HTTP client that fetches targets
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveryOptions {
+    /// How targets are fetched to discover their endpoints
+    pub strategy: DiscoveryStrategy,
+    /// Where discovered endpoints are remembered, if anywhere
+    pub endpoint_cache: Option<Arc<dyn cache::EndpointCache>>,
+}
+
+/// Where an endpoint candidate was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateSource {
+    /// `Link: <...>; rel="webmention"` HTTP header
+    LinkHeader,
+    /// `<link rel="webmention">` element
+    LinkElement,
+    /// `<a rel="webmention">` element
+    AnchorElement,
+}
+
+impl std::fmt::Display for CandidateSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CandidateSource::LinkHeader => "Link header",
+            CandidateSource::LinkElement => "<link>",
+            CandidateSource::AnchorElement => "<a>",
+        })
+    }
+}
 
-    let url = response.url.clone();
+/// Webmention endpoint advertised by the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub source: CandidateSource,
+    /// Index of the link inside of the `Link` header, or position of the element in the document
+    pub position: usize,
+    /// URL as written by the target
+    pub href: String,
+    /// `href` resolved relative to the fetched URL, if it is a valid URL
+    pub url: Option<Url>,
+}
 
-    let endpoint_in_link_header = response
-        .rels
-        .get("webmention")
-        .and_then(|urls| urls.first());
+/// Everything discovery has seen, for debugging surprising endpoints
+#[derive(Debug, Clone)]
+pub struct DiscoveryReport {
+    /// URL discovery was requested for
+    pub target: Url,
+    /// URL the target was fetched from after redirects
+    pub fetched_url: Url,
+    pub redirects: Vec<Redirect>,
+    /// Every advertised endpoint in order of precedence: `Link` header first, then elements
+    /// in document order
+    pub candidates: Vec<Candidate>,
+    /// Endpoint chosen according to the spec: the first candidate that is a valid URL
+    pub endpoint: Option<Url>,
+    /// Whether the endpoint was taken from [cache](DiscoveryOptions::endpoint_cache), in which case
+    /// there are no candidates and redirects
+    pub cached: bool,
+}
 
-    if let Some(link_str) = endpoint_in_link_header {
-        if let Ok(u) = absolute_url(link_str, &url) {
-            endpoint_candidates.push((0, u));
+impl DiscoveryReport {
+    fn from_cache(target: &Url, entry: &CachedEndpoint) -> DiscoveryReport {
+        DiscoveryReport {
+            target: target.clone(),
+            fetched_url: entry.fetched_url.clone(),
+            redirects: Vec::new(),
+            candidates: Vec::new(),
+            endpoint: entry.endpoint.clone(),
+            cached: true,
         }
     }
+}
 
-    let doc = response.html.doc()?;
+pub async fn find_target_endpoint(url: &Url) -> Result<Option<Url>, WebmentionError> {
+    find_target_endpoint_with(&HttpClient::default(), &DiscoveryOptions::default(), url).await
+}
 
-    {
-        let mut link_rels = doc.find(Name("link").and(RelWebmention));
+/// Same as [find_target_endpoint], but fetches the target with the given `client` and
+/// discovers according to `options`.
+pub async fn find_target_endpoint_with(
+    client: &HttpClient,
+    options: &DiscoveryOptions,
+    url: &Url,
+) -> Result<Option<Url>, WebmentionError> {
+    Ok(discover_with(client, options, url).await?.endpoint)
+}
 
-        if let Some(node) = link_rels.next() {
-            if let Some(href) = node.attr("href") {
-                if let Ok(url) = absolute_url(href, &url) {
-                    endpoint_candidates.push((node.index(), url));
-                }
-            }
+/// Fetches the target according to `options.strategy` and reports every endpoint candidate
+/// found, see [DiscoveryReport].
+///
+/// With `options.endpoint_cache`, fresh cached endpoints are used without fetching the target,
+/// and stale ones are revalidated with a conditional request.
+pub async fn discover_with(
+    client: &HttpClient,
+    options: &DiscoveryOptions,
+    url: &Url,
+) -> Result<DiscoveryReport, WebmentionError> {
+    Ok(discover(client, options, url).await?.0)
+}
+
+/// Same as [discover_with], but also returns the fetched target, unless the endpoint was taken
+/// from cache, so that it can be looked at without fetching it again.
+pub(crate) async fn discover(
+    client: &HttpClient,
+    options: &DiscoveryOptions,
+    url: &Url,
+) -> Result<(DiscoveryReport, Option<Response>), WebmentionError> {
+    let cache = match &options.endpoint_cache {
+        Some(cache) => cache,
+        None => {
+            let response = fetch(client, options.strategy, url, &HeaderMap::new()).await?;
+            return Ok((discovery_report(&response)?, Some(response)));
+        }
+    };
+
+    let now = SystemTime::now();
+    let cached = cache.get(url)?;
+    if let Some(entry) = cached.as_ref().filter(|entry| entry.is_fresh(now)) {
+        return Ok((DiscoveryReport::from_cache(url, entry), None));
+    }
+
+    // validators belong to the page they came with, not to other pages of the same origin
+    let stale = cached.filter(|entry| &entry.target == url);
+    let conditional = stale
+        .as_ref()
+        .map(CachedEndpoint::conditional_headers)
+        .unwrap_or_default();
+    let response = fetch(client, options.strategy, url, &conditional).await?;
+
+    if let (StatusCode::NOT_MODIFIED, Some(stale)) = (response.status, &stale) {
+        match stale.revalidated(&response.headers, now) {
+            Some(entry) => cache.put(url, entry)?,
+            None => cache.remove(url)?,
         }
+        return Ok((DiscoveryReport::from_cache(url, stale), None));
     }
 
-    {
-        let mut a_rels = doc.find(Name("a").and(RelWebmention));
+    let report = discovery_report(&response)?;
+    if response.status.is_success() {
+        let entry = CachedEndpoint::from_headers(
+            url,
+            &report.fetched_url,
+            report.endpoint.clone(),
+            &response.headers,
+            now,
+        );
+        match entry {
+            Some(entry) => cache.put(url, entry)?,
+            None => cache.remove(url)?,
+        }
+    }
+    Ok((report, Some(response)))
+}
 
-        if let Some(node) = a_rels.next() {
-            if let Some(href) = node.attr("href") {
-                if let Ok(url) = absolute_url(href, &url) {
-                    endpoint_candidates.push((node.index(), url));
-                }
+/// Fetches the target for discovery with additional `headers`. With
+/// [HeadFirst](DiscoveryStrategy::HeadFirst), the response to HEAD is used if it advertises an
+/// endpoint or tells that the target has not been modified.
+async fn fetch(
+    client: &HttpClient,
+    strategy: DiscoveryStrategy,
+    url: &Url,
+    headers: &HeaderMap,
+) -> Result<Response, WebmentionError> {
+    if strategy == DiscoveryStrategy::HeadFirst {
+        // any failure of HEAD is retried with GET, which reports the actual error
+        if let Ok(response) = client.head_with_headers(url, headers).await {
+            if response.status == StatusCode::NOT_MODIFIED
+                || (response.status.is_success() && discovery_report(&response)?.endpoint.is_some())
+            {
+                return Ok(response);
             }
         }
     }
-    endpoint_candidates.sort_by(|a, b| a.0.cmp(&b.0));
-    Ok(endpoint_candidates.first().map(|s| s.1.clone()))
+
+    client
+        .get_with_headers(url, headers)
+        .await
+        .map_err(|source| WebmentionError::DiscoveryRequestFailed {
+            url: url.clone(),
+            source: Box::new(source),
+        })
+}
+
+/// Finds webmention endpoint in already fetched target. Relative endpoints are resolved against
+/// the URL the target was fetched from after redirects.
+pub fn find_endpoint_in(response: &Response) -> Result<Option<Url>, WebmentionError> {
+    Ok(discovery_report(response)?.endpoint)
+}
+
+/// Whether the link from `Link` header of a response from `url` is about `url` itself, rather
+/// than about the other resource in its `anchor`.
+fn is_about(link: &crate::link_header::Link, url: &Url) -> bool {
+    let mut context = url.clone();
+    context.set_fragment(None);
+    link.context_url(&context).as_ref() == Some(&context)
+}
+
+/// Reports every endpoint candidate of already fetched target, see [DiscoveryReport].
+///
+/// Links of `Link` header with `anchor` pointing to some other resource are ignored.
+pub fn discovery_report(response: &Response) -> Result<DiscoveryReport, WebmentionError> {
+    let url = &response.url;
+    let candidate = |source, position, href: &str| Candidate {
+        source,
+        position,
+        href: href.to_string(),
+        url: absolute_url(href, url).ok(),
+    };
+
+    let mut candidates: Vec<Candidate> = response
+        .links
+        .links
+        .iter()
+        .enumerate()
+        .filter(|(_, link)| link.rels().iter().any(|rel| rel == "webmention"))
+        .filter(|(_, link)| is_about(link, url))
+        .map(|(position, link)| candidate(CandidateSource::LinkHeader, position, &link.target))
+        .collect();
+
+    let doc = response.html.doc()?;
+    let mut in_document: Vec<Candidate> = doc
+        .find(Name("link").and(Rel("webmention")))
+        .filter_map(|node| {
+            node.attr("href")
+                .map(|href| candidate(CandidateSource::LinkElement, node.index(), href))
+        })
+        .chain(
+            doc.find(Name("a").and(Rel("webmention")))
+                .filter_map(|node| {
+                    node.attr("href")
+                        .map(|href| candidate(CandidateSource::AnchorElement, node.index(), href))
+                }),
+        )
+        .collect();
+    in_document.sort_by_key(|candidate| candidate.position);
+    candidates.extend(in_document);
+
+    let endpoint = candidates
+        .iter()
+        .find_map(|candidate| candidate.url.clone());
+    Ok(DiscoveryReport {
+        target: response
+            .redirects
+            .first()
+            .map_or_else(|| url.clone(), |redirect| redirect.from.clone()),
+        fetched_url: url.clone(),
+        redirects: response.redirects.clone(),
+        candidates,
+        endpoint,
+        cached: false,
+    })
+}
+
+/// Discovers pingback server of the target: `X-Pingback` header takes precedence over
+/// `Link` header, which takes precedence over `<link rel="pingback">`.
+#[cfg(feature = "pingback")]
+pub async fn find_pingback_endpoint(
+    client: &HttpClient,
+    url: &Url,
+) -> Result<Option<Url>, WebmentionError> {
+    let response =
+        client
+            .get(url)
+            .await
+            .map_err(|source| WebmentionError::DiscoveryRequestFailed {
+                url: url.clone(),
+                source: Box::new(source),
+            })?;
+    find_pingback_endpoint_in(&response)
+}
+
+/// Finds pingback server in already fetched target, see [find_pingback_endpoint].
+#[cfg(feature = "pingback")]
+pub fn find_pingback_endpoint_in(response: &Response) -> Result<Option<Url>, WebmentionError> {
+    let url = &response.url;
+
+    let in_x_pingback = response
+        .headers
+        .get("x-pingback")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim());
+    let in_link_header = response
+        .links
+        .links
+        .iter()
+        .filter(|link| is_about(link, url))
+        .find(|link| link.rels().iter().any(|rel| rel == "pingback"))
+        .map(|link| link.target.as_str());
+    let in_document = response
+        .html
+        .doc()?
+        .find(Name("link").and(Rel("pingback")))
+        .find_map(|node| node.attr("href"));
+
+    Ok(in_x_pingback
+        .into_iter()
+        .chain(in_link_header)
+        .chain(in_document)
+        .find_map(|candidate| absolute_url(candidate, url).ok()))
 }
 
 #[cfg(test)]
@@ -1,7 +1,223 @@
-use super::find_target_endpoint;
+use super::cache::{EndpointCache, InMemoryEndpointCache, PerOriginCache};
+use super::{
+    discover_with, discovery_report, find_target_endpoint, Candidate, CandidateSource,
+    DiscoveryOptions, DiscoveryStrategy,
+};
+use crate::html::HTML;
+use crate::http_client::test::{local_client, serve_sequence};
+use crate::http_client::{Redirect, Response};
+use crate::link_header::LinkHeader;
 use crate::wm_url::Url;
+use std::sync::Arc;
 use tokio_test::block_on;
 
+#[test]
+fn discovery_report_test() {
+    let target = Url::parse("https://example.com/post").unwrap();
+    let fetched = Url::parse("https://example.com/posts/1/").unwrap();
+    let html = r#"<html><head><link rel="stylesheet" href="style.css">
+        <link rel="webmention" href="https://[invalid/"></head>
+        <body><a rel="webmention nofollow" href="../wm">endpoint</a>
+        <link rel="webmention" href="/later"></body></html>"#;
+
+    let links = LinkHeader::parse(
+        "<https://[bad/>; rel=webmention, <wm?header>; rel=\"webmention\", \
+         </elsewhere>; rel=webmention; anchor=\"/other\"",
+    )
+    .unwrap();
+    let response = Response {
+        url: fetched.clone(),
+        redirects: vec![Redirect {
+            from: target.clone(),
+            to: fetched.clone(),
+            status: reqwest::StatusCode::MOVED_PERMANENTLY,
+        }],
+        status: reqwest::StatusCode::OK,
+        content_type: Some("text/html".to_string()),
+        html: HTML::new(fetched.clone(), html.to_string()),
+        links,
+        headers: reqwest::header::HeaderMap::new(),
+    };
+
+    let report = discovery_report(&response).unwrap();
+    assert_eq!(report.target, target);
+    assert_eq!(report.fetched_url, fetched);
+    let sources: Vec<(CandidateSource, &str)> = report
+        .candidates
+        .iter()
+        .map(|candidate| (candidate.source, candidate.href.as_str()))
+        .collect();
+    assert_eq!(
+        sources,
+        vec![
+            (CandidateSource::LinkHeader, "https://[bad/"),
+            (CandidateSource::LinkHeader, "wm?header"),
+            (CandidateSource::LinkElement, "https://[invalid/"),
+            (CandidateSource::AnchorElement, "../wm"),
+            (CandidateSource::LinkElement, "/later"),
+        ]
+    );
+    assert_eq!(
+        report.candidates[1],
+        Candidate {
+            source: CandidateSource::LinkHeader,
+            position: 1,
+            href: "wm?header".to_string(),
+            url: Some(Url::parse("https://example.com/posts/1/wm?header").unwrap()),
+        }
+    );
+    assert_eq!(report.candidates[0].url, None);
+    assert_eq!(
+        report.candidates[3].url,
+        Some(Url::parse("https://example.com/posts/wm").unwrap())
+    );
+    assert_eq!(report.endpoint, report.candidates[1].url);
+}
+
+#[test]
+fn anchor_discovery_test() {
+    let url = serve_sequence(vec![b"HTTP/1.1 200 OK\r\n\
+        Content-Type: text/html\r\n\
+        Link: </comments-endpoint>; rel=webmention; anchor=\"#comments\"\r\n\
+        Link: </other-endpoint>; rel=webmention; anchor=\"/other\"\r\n\
+        Link: <endpoint>; rel=webmention; anchor=\"/\"\r\n\
+        Connection: close\r\n\r\n"
+        .to_vec()]);
+    let response = block_on(local_client(1024).get(&url)).unwrap();
+
+    let report = discovery_report(&response).unwrap();
+    assert_eq!(report.candidates.len(), 1);
+    assert_eq!(report.candidates[0].position, 2);
+    // anchor pointing to the fetched URL itself is fine
+    assert_eq!(report.endpoint, Some(url.join("endpoint").unwrap()));
+}
+
+fn head_first() -> DiscoveryOptions {
+    DiscoveryOptions {
+        strategy: DiscoveryStrategy::HeadFirst,
+        ..DiscoveryOptions::default()
+    }
+}
+
+#[test]
+fn head_first_discovery_test() {
+    // only one response is served, so GET would fail
+    let url = serve_sequence(vec![b"HTTP/1.1 200 OK\r\n\
+        Content-Type: video/mp4\r\n\
+        Content-Length: 100000000\r\n\
+        Link: </webmention>; rel=\"webmention\"\r\n\r\n"
+        .to_vec()]);
+
+    let report = block_on(discover_with(&local_client(1024), &head_first(), &url)).unwrap();
+    assert_eq!(report.endpoint, Some(url.join("/webmention").unwrap()));
+    assert_eq!(report.candidates[0].source, CandidateSource::LinkHeader);
+}
+
+#[test]
+fn head_first_fallback_test() {
+    let url = serve_sequence(vec![
+        b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\n\r\n\
+            <link rel=\"webmention\" href=\"/endpoint\">"
+            .to_vec(),
+    ]);
+
+    let report = block_on(discover_with(&local_client(1024), &head_first(), &url)).unwrap();
+    assert_eq!(report.endpoint, Some(url.join("/endpoint").unwrap()));
+    assert_eq!(report.candidates[0].source, CandidateSource::LinkElement);
+}
+
+fn caching(cache: impl EndpointCache + 'static) -> DiscoveryOptions {
+    DiscoveryOptions {
+        endpoint_cache: Some(Arc::new(cache)),
+        ..DiscoveryOptions::default()
+    }
+}
+
+fn page(headers: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n{}Connection: close\r\n\r\n\
+            <link rel=\"webmention\" href=\"/endpoint\">",
+        headers
+    )
+    .into_bytes()
+}
+
+#[test]
+fn cached_discovery_test() {
+    // only one response is served, so the second discovery has to use the cache
+    let url = serve_sequence(vec![page("Cache-Control: max-age=60\r\n")]);
+    let client = local_client(1024);
+    let options = caching(InMemoryEndpointCache::new());
+
+    let fetched = block_on(discover_with(&client, &options, &url)).unwrap();
+    assert!(!fetched.cached);
+    let cached = block_on(discover_with(&client, &options, &url)).unwrap();
+    assert!(cached.cached);
+    assert_eq!(cached.endpoint, Some(url.join("/endpoint").unwrap()));
+    assert_eq!(cached.endpoint, fetched.endpoint);
+}
+
+#[test]
+fn revalidated_discovery_test() {
+    let url = serve_sequence(vec![
+        page("Cache-Control: no-cache\r\nETag: \"v1\"\r\n"),
+        b"HTTP/1.1 304 Not Modified\r\nCache-Control: max-age=60\r\n\r\n".to_vec(),
+    ]);
+    let client = local_client(1024);
+    let options = caching(InMemoryEndpointCache::new());
+
+    assert!(
+        !block_on(discover_with(&client, &options, &url))
+            .unwrap()
+            .cached
+    );
+    let revalidated = block_on(discover_with(&client, &options, &url)).unwrap();
+    assert!(revalidated.cached);
+    assert_eq!(revalidated.endpoint, Some(url.join("/endpoint").unwrap()));
+    // now fresh thanks to max-age of 304, no more responses are served
+    assert!(
+        block_on(discover_with(&client, &options, &url))
+            .unwrap()
+            .cached
+    );
+}
+
+#[test]
+fn per_origin_discovery_test() {
+    let url = serve_sequence(vec![page("Cache-Control: max-age=60\r\n")]);
+    let client = local_client(1024);
+    let options = caching(PerOriginCache(InMemoryEndpointCache::new()));
+
+    block_on(discover_with(&client, &options, &url)).unwrap();
+    let other = url.join("/other/page").unwrap();
+    let report = block_on(discover_with(&client, &options, &other)).unwrap();
+    assert!(report.cached);
+    assert_eq!(report.target, other);
+    assert_eq!(report.endpoint, Some(url.join("/endpoint").unwrap()));
+}
+
+#[test]
+fn no_store_discovery_test() {
+    let url = serve_sequence(vec![
+        page("Cache-Control: no-store, max-age=60\r\n"),
+        page(""),
+    ]);
+    let client = local_client(1024);
+    let options = caching(InMemoryEndpointCache::new());
+
+    assert!(
+        !block_on(discover_with(&client, &options, &url))
+            .unwrap()
+            .cached
+    );
+    assert!(
+        !block_on(discover_with(&client, &options, &url))
+            .unwrap()
+            .cached
+    );
+}
+
 #[test]
 fn webmention_rocks_discovery_endpoint_test1() {
     let url = Url::parse("https://webmention.rocks/test/1").unwrap();
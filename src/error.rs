@@ -35,6 +35,15 @@ pub enum WebmentionError {
     NotAccepted {
         endpoint: String,
         status_code: reqwest::StatusCode,
+        /// How long the endpoint asked to wait before trying again, from `Retry-After`
+        retry_after: Option<std::time::Duration>,
+    },
+
+    #[error("<{url}> responded with {status_code}")]
+    UnavailableDocument {
+        url: Url,
+        status_code: reqwest::StatusCode,
+        retry_after: Option<std::time::Duration>,
     },
 
     #[error("generic request failed for URL <{url}>")]
@@ -67,4 +76,106 @@ pub enum WebmentionError {
 
     #[error("no document links found")]
     NoDocumentLinks,
+
+    #[error("refusing to connect to <{url}>: {address} is not a public address")]
+    ForbiddenAddress { url: Url, address: std::net::IpAddr },
+
+    #[error("response from <{url}> is larger than {limit} bytes")]
+    ResponseTooLarge { url: Url, limit: usize },
+
+    #[error("response from <{url}> has unsupported content type {content_type}")]
+    UnsupportedContentType { url: Url, content_type: String },
+
+    #[error("malformed XML-RPC message: {0}")]
+    MalformedXmlRpc(String),
+
+    #[error("could not access {path}")]
+    Io {
+        path: std::path::PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("{reason}, retry after {retry_after:?}")]
+    TooManyRequests {
+        reason: String,
+        retry_after: std::time::Duration,
+    },
+
+    #[error("webmentions from <{0}> are blocked")]
+    BlockedSource(Url),
+
+    #[error("could not check for spam: {0}")]
+    SpamCheckFailed(String),
+
+    #[error("unknown format {0}")]
+    UnknownFormat(String),
+
+    #[error("storage does not support listing webmentions")]
+    ListingUnsupported,
+
+    #[error("line {line} is not a webmention: {reason}")]
+    InvalidRecord { line: usize, reason: String },
+
+    #[error("entry {entry} is not a webmention: {reason}")]
+    InvalidEntry { entry: usize, reason: String },
+
+    #[error("line {line} is not a domain: {pattern}")]
+    InvalidDomainPattern { line: usize, pattern: String },
+
+    #[error("could not parse {path}")]
+    InvalidFile {
+        path: std::path::PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+}
+
+impl WebmentionError {
+    /// Whether repeating the request later could succeed: timeouts, refused or reset
+    /// connections, `429 Too Many Requests` and server errors. Invalid URLs and documents,
+    /// hosts that cannot be resolved, forbidden addresses and client errors are permanent.
+    pub fn is_retryable(&self) -> bool {
+        use std::io::ErrorKind;
+
+        match self {
+            WebmentionError::RequestFailed { source, .. }
+            | WebmentionError::SendingRequestFailed { source, .. } => {
+                match source.downcast_ref::<reqwest::Error>() {
+                    Some(err) => err.is_timeout() || err.is_connect(),
+                    // resolving the host, which fails for good when it does not exist
+                    None => matches!(
+                        source
+                            .downcast_ref::<std::io::Error>()
+                            .map(|err| err.kind()),
+                        Some(
+                            ErrorKind::TimedOut
+                                | ErrorKind::ConnectionRefused
+                                | ErrorKind::ConnectionReset
+                                | ErrorKind::ConnectionAborted
+                        )
+                    ),
+                }
+            }
+            WebmentionError::RequestFailedRecv { .. } => true,
+            WebmentionError::NotAccepted { status_code, .. }
+            | WebmentionError::UnavailableDocument { status_code, .. } => {
+                crate::retry::RetryPolicy::is_retryable_status(*status_code)
+            }
+            WebmentionError::DiscoveryRequestFailed { source, .. }
+            | WebmentionError::StorageError { source } => source.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// How long the server asked to wait before trying again, if it did.
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            WebmentionError::NotAccepted { retry_after, .. }
+            | WebmentionError::UnavailableDocument { retry_after, .. } => *retry_after,
+            WebmentionError::TooManyRequests { retry_after, .. } => Some(*retry_after),
+            WebmentionError::DiscoveryRequestFailed { source, .. } => source.retry_after(),
+            _ => None,
+        }
+    }
 }
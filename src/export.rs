@@ -0,0 +1,310 @@
+use crate::error::WebmentionError;
+use crate::snapshot::Snapshot;
+use crate::storage::WebmentionStorage;
+use crate::timestamp;
+use crate::webmention::Webmention;
+use serde::Serialize;
+use serde_json::json;
+
+/// Columns of exported CSV, in order
+pub const CSV_COLUMNS: [&str; 15] = [
+    "source",
+    "target",
+    "checked",
+    "sent",
+    "moderation",
+    "kind",
+    "author_name",
+    "author_url",
+    "author_photo",
+    "published",
+    "fetched_at",
+    "etag",
+    "in_reply_to",
+    "summary",
+    "content",
+];
+
+/// Format of exported webmentions, which can be [imported](crate::import::read) back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Each webmention as a JSON object on its own line, with everything that is stored
+    Jsonl,
+    /// A header with [CSV_COLUMNS] and a row per webmention, for spreadsheets. Times lose
+    /// fractions of seconds.
+    Csv,
+    /// A JF2 feed like the one exported by webmention.io, with moderation as `wm-moderation`
+    /// and whether there is a snapshot as `wm-snapshot`. Whether webmentions were checked or
+    /// sent is lost, and so are ETags and fractions of seconds.
+    Jf2,
+}
+
+impl std::str::FromStr for Format {
+    type Err = WebmentionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "jf2" => Ok(Format::Jf2),
+            other => Err(WebmentionError::UnknownFormat(other.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Jsonl => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+            Format::Jf2 => write!(f, "jf2"),
+        }
+    }
+}
+
+/// Every webmention of `storage` in `format`.
+pub fn export(storage: &impl WebmentionStorage, format: Format) -> Result<String, WebmentionError> {
+    let mentions = storage.list()?;
+    Ok(match format {
+        Format::Jsonl => to_jsonl(&mentions),
+        Format::Csv => to_csv(&mentions),
+        Format::Jf2 => to_jf2(&mentions),
+    })
+}
+
+/// Webmentions as JSON Lines, see [Format::Jsonl].
+pub fn to_jsonl(mentions: &[Webmention]) -> String {
+    let mut jsonl = String::new();
+    for mention in mentions.iter() {
+        jsonl.push_str(&serde_json::to_string(mention).expect("webmentions are serializable"));
+        jsonl.push('\n');
+    }
+    jsonl
+}
+
+/// Webmentions as CSV, see [Format::Csv].
+pub fn to_csv(mentions: &[Webmention]) -> String {
+    let mut csv = CSV_COLUMNS.join(",");
+    csv.push('\n');
+    for mention in mentions.iter() {
+        let snapshot = mention.snapshot.as_ref();
+        let author = snapshot.and_then(|s| s.author.as_ref());
+        let row = [
+            mention.source.to_string(),
+            mention.target.to_string(),
+            mention
+                .checked()
+                .map(|checked| checked.to_string())
+                .unwrap_or_default(),
+            mention.is_sent().to_string(),
+            serde_name(&mention.moderation),
+            snapshot
+                .map(|s| s.kind.property().to_string())
+                .unwrap_or_default(),
+            author.and_then(|a| a.name.clone()).unwrap_or_default(),
+            author
+                .and_then(|a| a.url.as_ref().map(|url| url.to_string()))
+                .unwrap_or_default(),
+            author
+                .and_then(|a| a.photo.as_ref().map(|url| url.to_string()))
+                .unwrap_or_default(),
+            snapshot
+                .and_then(|s| s.published.clone())
+                .unwrap_or_default(),
+            snapshot
+                .map(|s| timestamp::format(s.fetched_at))
+                .unwrap_or_default(),
+            snapshot.and_then(|s| s.etag.clone()).unwrap_or_default(),
+            snapshot
+                .map(|s| {
+                    let urls: Vec<&str> = s.in_reply_to.iter().map(|url| url.as_str()).collect();
+                    urls.join(" ")
+                })
+                .unwrap_or_default(),
+            snapshot.map(|s| s.summary.clone()).unwrap_or_default(),
+            snapshot.map(|s| s.content.clone()).unwrap_or_default(),
+        ];
+        let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Webmentions as a JF2 feed, see [Format::Jf2].
+pub fn to_jf2(mentions: &[Webmention]) -> String {
+    let children: Vec<serde_json::Value> = mentions.iter().map(jf2_entry).collect();
+    let feed = json!({
+        "type": "feed",
+        "name": "Webmentions",
+        "children": children,
+    });
+    serde_json::to_string_pretty(&feed).expect("webmentions are serializable")
+}
+
+fn jf2_entry(mention: &Webmention) -> serde_json::Value {
+    let mut entry = json!({
+        "type": "entry",
+        "url": mention.source,
+        "wm-source": mention.source,
+        "wm-target": mention.target,
+        "wm-private": mention.moderation != crate::webmention::Moderation::Approved,
+        "wm-moderation": mention.moderation,
+        "wm-snapshot": mention.snapshot.is_some(),
+    });
+    if let Some(Snapshot {
+        content,
+        summary,
+        fetched_at,
+        kind,
+        author,
+        published,
+        in_reply_to,
+        ..
+    }) = &mention.snapshot
+    {
+        entry["wm-property"] = json!(kind.property());
+        entry[kind.property()] = json!(mention.target);
+        entry["wm-received"] = json!(timestamp::format(*fetched_at));
+        entry["content"] = json!({ "html": content, "text": summary });
+        if let Some(author) = author {
+            entry["author"] = json!({
+                "type": "card",
+                "name": author.name,
+                "url": author.url,
+                "photo": author.photo,
+            });
+        }
+        if let Some(published) = published {
+            entry["published"] = json!(published);
+        }
+        if !in_reply_to.is_empty() {
+            entry["in-reply-to"] = json!(in_reply_to);
+        }
+    }
+    entry
+}
+
+/// Name of a unit variant as it is serialized
+fn serde_name(value: &impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// Whether a spreadsheet could take `field` for a formula, so that it needs a leading `'`.
+/// Fields starting with `'` get another one, so that [import](crate::import::parse_csv) can
+/// tell them apart.
+pub(crate) fn needs_formula_guard(field: &str) -> bool {
+    field.starts_with(['=', '+', '-', '@', '\''])
+}
+
+/// Quotes a field of CSV if needed, and keeps spreadsheets from evaluating it
+fn csv_field(field: &str) -> String {
+    let field = if needs_formula_guard(field) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export, to_csv, to_jf2, to_jsonl, Format};
+    use crate::import::{parse_csv, parse_jf2, parse_jsonl};
+    use crate::snapshot::{Author, MentionKind, Snapshot};
+    use crate::storage::{InMemoryWebmentionStorage, WebmentionStorage};
+    use crate::webmention::{Moderation, Webmention};
+    use crate::wm_url::Url;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn mentions() -> Vec<Webmention> {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let mut sent =
+            Webmention::from((url("https://bob.example/post"), url("https://x.example/")));
+        sent.set_checked(true);
+        sent.set_sent(true);
+
+        let mut reply = Webmention::from((
+            url("https://alice.example/reply"),
+            url("https://bob.example/post"),
+        ));
+        reply.set_checked(true);
+        reply.moderation = Moderation::Spam;
+        reply.snapshot = Some(Snapshot {
+            content: "<p>Hi, \"Bob\"\nsee <a href=\"https://alice.example/\" rel=\"nofollow ugc noopener noreferrer\">this</a></p>".to_string(),
+            summary: "Hi, \"Bob\" see this".to_string(),
+            fetched_at: UNIX_EPOCH + Duration::from_secs(1_709_208_005),
+            etag: Some("\"v1\"".to_string()),
+            kind: MentionKind::Reply,
+            author: Some(Author {
+                name: Some("Alice".to_string()),
+                url: Some(url("https://alice.example/")),
+                photo: None,
+            }),
+            published: Some("2024-02-29".to_string()),
+            in_reply_to: vec![url("https://bob.example/post"), url("https://c.example/")],
+        });
+        vec![sent, reply]
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let mentions = mentions();
+        assert_eq!(parse_jsonl(&to_jsonl(&mentions)).unwrap(), mentions);
+        assert_eq!(parse_csv(&to_csv(&mentions)).unwrap(), mentions);
+
+        // JF2 has no place for checked, sent or ETags
+        let jf2 = to_jf2(&mentions);
+        assert!(jf2.contains(r#""wm-snapshot": false"#));
+        let jf2 = parse_jf2(&jf2).unwrap().mentions;
+        let mut sent = mentions[0].clone();
+        sent.set_sent(false);
+        assert_eq!(jf2[0], sent);
+        let mut reply = mentions[1].clone();
+        reply.snapshot.as_mut().unwrap().etag = None;
+        assert_eq!(jf2[1], reply);
+    }
+
+    #[test]
+    fn test_export() {
+        let storage = InMemoryWebmentionStorage::new();
+        for mention in mentions() {
+            storage.store(mention).unwrap();
+        }
+        let csv = export(&storage, "csv".parse().unwrap()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].starts_with("source,target,checked,sent,moderation,kind,"));
+        assert_eq!(
+            lines[1],
+            "https://bob.example/post,https://x.example/,true,true,Pending,,,,,,,,,,"
+        );
+        assert!(lines[2].contains(",\"Hi, \"\"Bob\"\" see this\","));
+
+        let jsonl = export(&storage, Format::Jsonl).unwrap();
+        assert_eq!(jsonl.lines().count(), 2);
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_csv_formulas() {
+        let mut mentions = mentions();
+        let snapshot = mentions[1].snapshot.as_mut().unwrap();
+        snapshot.author.as_mut().unwrap().name =
+            Some("=HYPERLINK(\"https://evil.example/\")".into());
+        snapshot.summary = "'quoted".to_string();
+        snapshot.etag = Some("-1".to_string());
+
+        let csv = to_csv(&mentions);
+        assert!(csv.contains(",\"'=HYPERLINK(\"\"https://evil.example/\"\")\","));
+        assert!(csv.contains(",''quoted,"));
+        assert!(csv.contains(",'-1,"));
+        assert_eq!(parse_csv(&csv).unwrap(), mentions);
+    }
+}
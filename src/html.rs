@@ -1,13 +1,34 @@
-use crate::{wm_url::Url, WebmentionError};
+use crate::{
+    wm_url::{absolute_url, Url, UrlEquivalence},
+    WebmentionError,
+};
 use select::{
     document::Document,
     node::Node,
     predicate::{Attr, Class, Name, Not, Predicate},
 };
 
+/// Elements and their attributes that link to other documents, according to the spec
+const LINKING_ATTRIBUTES: [(&str, &str); 5] = [
+    ("a", "href"),
+    ("img", "src"),
+    ("video", "src"),
+    ("audio", "src"),
+    ("source", "src"),
+];
+
+/// Elements whose contents are never displayed
+const INVISIBLE_ELEMENTS: [&str; 5] = ["head", "script", "style", "noscript", "template"];
+
+/// Elements that do not break the text flow, so no whitespace is added around their text
+const INLINE_ELEMENTS: [&str; 25] = [
+    "a", "abbr", "b", "bdi", "bdo", "cite", "code", "data", "del", "dfn", "em", "i", "ins", "kbd",
+    "mark", "q", "s", "samp", "small", "span", "strong", "sub", "sup", "time", "u",
+];
+
 #[derive(Debug)]
 pub struct HTML {
-    _source: Url,
+    source: Url,
     raw_html: String,
     doc: Option<Document>,
 }
@@ -15,7 +36,7 @@ pub struct HTML {
 impl HTML {
     pub fn new(url: Url, raw_html: String) -> HTML {
         let mut html = HTML {
-            _source: url,
+            source: url,
             raw_html,
             doc: None,
         };
@@ -23,18 +44,54 @@ impl HTML {
         html
     }
 
+    /// Checks that the document links to target under default [UrlEquivalence].
+    ///
+    /// See [contains_with](HTML::contains_with).
     pub fn contains(&self, target: &Url) -> Result<(), WebmentionError> {
+        self.contains_with(target, &UrlEquivalence::default())
+    }
+
+    /// Checks that `<a href>`, `<img src>`, `<video src>`, `<audio src>` or `<source src>` of
+    /// the document links to target. Links are resolved relative to the document (or its
+    /// `<base href>`) and compared with `equivalence`.
+    pub fn contains_with(
+        &self,
+        target: &Url,
+        equivalence: &UrlEquivalence,
+    ) -> Result<(), WebmentionError> {
         let doc = self
             .doc
             .as_ref()
             .ok_or(WebmentionError::UnparseableDocument)?;
-        let links = Name("a").and(Attr("href", target.as_str()));
+        let base = self.base_url();
+        let target = equivalence.normalize(target);
 
-        if doc.find(links).count() > 0 {
-            Ok(())
-        } else {
-            Err(WebmentionError::NoDocumentLinks)
+        for (name, attribute) in LINKING_ATTRIBUTES.iter() {
+            let found = doc
+                .find(Name(*name).and(Attr(*attribute, ())))
+                .filter_map(|node| node.attr(attribute))
+                .filter_map(|link| absolute_url(link.trim(), &base).ok())
+                .any(|link| equivalence.normalize(&link) == target);
+            if found {
+                return Ok(());
+            }
         }
+
+        Err(WebmentionError::NoDocumentLinks)
+    }
+
+    /// URL that relative links of the document are resolved against
+    pub fn base_url(&self) -> Url {
+        self.doc
+            .as_ref()
+            .and_then(|doc| doc.find(Name("base")).find_map(|node| node.attr("href")))
+            .and_then(|href| absolute_url(href.trim(), &self.source).ok())
+            .unwrap_or_else(|| self.source.clone())
+    }
+
+    /// The document as it was fetched
+    pub fn raw(&self) -> &str {
+        &self.raw_html
     }
 
     pub fn doc(&self) -> Result<&Document, WebmentionError> {
@@ -43,6 +100,17 @@ impl HTML {
             .ok_or(WebmentionError::UnparseableDocument)
     }
 
+    /// The first `h-entry` of the document, which is what the document is about.
+    pub fn entry(&self) -> Option<Node<'_>> {
+        self.doc.as_ref()?.find(Class("h-entry")).next()
+    }
+
+    /// The first `h-entry` of the document, or the whole `<body>` if there is none.
+    pub fn main_content(&self) -> Option<Node<'_>> {
+        self.entry()
+            .or_else(|| self.doc.as_ref()?.find(Name("body")).next())
+    }
+
     pub async fn find_links(self: &HTML) -> Result<Vec<Url>, WebmentionError> {
         let mut links = Vec::new();
 
@@ -75,12 +143,117 @@ impl HTML {
     }
 }
 
+/// Text of `node` as it would be displayed, without scripts and styles, with whitespace
+/// collapsed.
+pub fn visible_text(node: &Node) -> String {
+    fn collect(node: &Node, text: &mut String) {
+        if let Some(fragment) = node.as_text() {
+            text.push_str(fragment);
+            return;
+        }
+        let name = node.name().unwrap_or_default();
+        if INVISIBLE_ELEMENTS.contains(&name) {
+            return;
+        }
+        let block = node.name().is_some() && !INLINE_ELEMENTS.contains(&name);
+        if block {
+            text.push(' ');
+        }
+        for child in node.children() {
+            collect(&child, text);
+        }
+        if block {
+            text.push(' ');
+        }
+    }
+
+    let mut text = String::new();
+    collect(node, &mut text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
 #[cfg(test)]
 mod test {
+    use super::{visible_text, HTML};
     use crate::http_client::get;
-    use crate::wm_url::Url;
+    use crate::wm_url::{Url, UrlEquivalence};
     use tokio_test::block_on;
 
+    fn html(raw: &str) -> HTML {
+        HTML::new(
+            Url::parse("https://marinintim.com/notes/2021/hwc-rsvp/").unwrap(),
+            raw.to_string(),
+        )
+    }
+
+    #[test]
+    fn contains_test() {
+        let target = Url::parse("https://example.com/post").unwrap();
+        let links_to_target = [
+            r#"<a href="https://example.com/post">exact</a>"#,
+            r#"<a href="https://example.com/post/">trailing slash</a>"#,
+            r#"<a href="HTTPS://EXAMPLE.COM:443/p%6fst">encoded</a>"#,
+            r#"<a href="https://example.com/post#comments">fragment</a>"#,
+            r#"<a href=" https://example.com/post ">whitespace</a>"#,
+            r#"<img src="https://example.com/post">"#,
+            r#"<video src="https://example.com/post"></video>"#,
+            r#"<audio><source src="https://example.com/post"></audio>"#,
+        ];
+        for raw in links_to_target.iter() {
+            assert!(html(raw).contains(&target).is_ok(), "{}", raw);
+        }
+
+        let not_linking = [
+            r#"<a href="https://example.com/post-2">other</a>"#,
+            r#"<a href="http://example.com:8080/post">other port</a>"#,
+            r#"<link href="https://example.com/post">"#,
+            r#"<p>https://example.com/post</p>"#,
+        ];
+        for raw in not_linking.iter() {
+            assert!(html(raw).contains(&target).is_err(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn contains_relative_test() {
+        let target = Url::parse("https://marinintim.com/notes/2021/other/?a=1&b=2").unwrap();
+        assert!(html(r#"<a href="../other/?a=1&amp;b=2">relative</a>"#)
+            .contains(&target)
+            .is_ok());
+        assert!(html(
+            r#"<base href="https://marinintim.com/notes/"><a href="2021/other?a=1&amp;b=2">x</a>"#
+        )
+        .contains(&target)
+        .is_ok());
+    }
+
+    #[test]
+    fn contains_fragment_test() {
+        let target = Url::parse("https://example.com/post#comment-1").unwrap();
+        let doc = html(r#"<a href="https://example.com/post#comment-2">comment</a>"#);
+        assert!(doc.contains(&target).is_ok());
+        assert!(doc
+            .contains_with(&target, &UrlEquivalence::strict())
+            .is_err());
+    }
+
+    #[test]
+    fn main_content_test() {
+        let doc = html(
+            "<html><head><title>Title</title></head><body><nav>Menu</nav>\
+             <article class=\"h-entry\"><p>Hello, <b>wor</b>ld!</p>\
+             <script>track()</script><p>Bye</p></article></body></html>",
+        );
+        assert_eq!(
+            visible_text(&doc.main_content().unwrap()),
+            "Hello, world! Bye"
+        );
+
+        let doc = html("<title>Title</title><p>Just</p>\n<p>text</p>");
+        assert!(doc.entry().is_none());
+        assert_eq!(visible_text(&doc.main_content().unwrap()), "Just text");
+    }
+
     #[ignore]
     #[test]
     fn find_links_test() {
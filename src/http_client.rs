@@ -1,73 +1,775 @@
+use crate::address_policy::AddressPolicy;
 use crate::error::WebmentionError;
 use crate::html::HTML;
-use crate::wm_url::Url;
+use crate::link_header::LinkHeader;
+use crate::rate_limit::{RequestPermit, RequestScheduler};
+use crate::retry::{retry_after, RetryPolicy};
+use crate::wm_url::{absolute_url, Url};
+use reqwest::header::HeaderMap;
+use reqwest::{redirect::Policy, Method, RequestBuilder, StatusCode};
 use serde::Serialize;
+use std::sync::Arc;
 
 pub struct Response {
+    /// URL the document was fetched from, after following redirects
     pub url: Url,
+    /// Redirects that were followed to get to `url`, in order
+    pub redirects: Vec<Redirect>,
+    pub status: StatusCode,
+    pub content_type: Option<String>,
     pub html: HTML,
-    pub rels: std::collections::HashMap<String, Vec<String>>,
+    /// Every link of `Link` headers
+    pub links: LinkHeader,
+    pub headers: reqwest::header::HeaderMap,
 }
 
-pub async fn get(u: &Url) -> Result<Response, WebmentionError> {
-    let client = reqwest::Client::new();
-    let req = client.get(u.clone());
+/// A single followed redirect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub from: Url,
+    pub to: Url,
+    pub status: StatusCode,
+}
 
-    let res = req
-        .send()
-        .await
-        .map_err(|err| WebmentionError::RequestFailed {
-            url: u.clone(),
-            source: err.into(),
-        })?;
+/// Default limit of redirects we follow before giving up
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// Default limit for response bodies, 5 MiB
+pub const DEFAULT_MAX_BODY_SIZE: usize = 5 * 1024 * 1024;
+
+/// HTTP client that applies [AddressPolicy] to every request, including every redirect.
+///
+/// Fetched documents must have a textual content type and are read up to `max_body_size` bytes.
+/// At most `max_redirects` redirects are followed, after that the request fails with
+/// [TooManyRedirects](WebmentionError::TooManyRedirects). GET and POST requests that fail
+/// temporarily are repeated according to `retry_policy`. Requests wait for their turn in
+/// `scheduler`, which is shared by clones of the client.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    pub address_policy: AddressPolicy,
+    pub max_body_size: usize,
+    pub max_redirects: usize,
+    pub retry_policy: RetryPolicy,
+    /// Limits concurrency and per-host rate of requests
+    pub scheduler: Arc<RequestScheduler>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient {
+            address_policy: AddressPolicy::default(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            retry_policy: RetryPolicy::default(),
+            scheduler: Arc::new(RequestScheduler::default()),
+        }
+    }
+}
+
+impl HttpClient {
+    pub fn new(address_policy: AddressPolicy) -> HttpClient {
+        HttpClient {
+            address_policy,
+            ..HttpClient::default()
+        }
+    }
+
+    pub async fn get(&self, u: &Url) -> Result<Response, WebmentionError> {
+        self.get_with_headers(u, &HeaderMap::new()).await
+    }
+
+    /// Same as [get](HttpClient::get), but sends additional `headers`, e.g. for conditional
+    /// requests.
+    ///
+    /// Fails with [UnavailableDocument](WebmentionError::UnavailableDocument) if the response
+    /// still has a [retryable status](RetryPolicy::is_retryable_status) after all retries.
+    pub async fn get_with_headers(
+        &self,
+        u: &Url,
+        headers: &HeaderMap,
+    ) -> Result<Response, WebmentionError> {
+        let (res, redirects, _permit) = self
+            .execute_with_retries(Method::GET, u, headers, &|req| req)
+            .await?;
+        let url = res.url().clone();
+        let status = res.status();
+        if RetryPolicy::is_retryable_status(status) {
+            return Err(WebmentionError::UnavailableDocument {
+                url,
+                status_code: status,
+                retry_after: retry_after(res.headers()),
+            });
+        }
+
+        let headers = res.headers().clone();
+        let content_type = headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        if let Some(content_type) = content_type {
+            if !is_textual(content_type) {
+                return Err(WebmentionError::UnsupportedContentType {
+                    url,
+                    content_type: content_type.to_string(),
+                });
+            }
+        }
+
+        let links = LinkHeader::from_headers(headers.get_all(reqwest::header::LINK));
+
+        let body = self.read_body(&url, res).await?;
+        let response = crate::charset::decode(&body, content_type);
+
+        Ok(Response {
+            html: HTML::new(url.clone(), response),
+            url,
+            redirects,
+            status,
+            content_type: content_type.map(|content_type| content_type.to_string()),
+            links,
+            headers,
+        })
+    }
+
+    /// Sends HEAD request. The response has an empty document and any content type is accepted.
+    ///
+    /// HEAD requests are not retried, since they are only used to avoid a GET.
+    pub async fn head(&self, u: &Url) -> Result<Response, WebmentionError> {
+        self.head_with_headers(u, &HeaderMap::new()).await
+    }
+
+    /// Same as [head](HttpClient::head), but sends additional `headers`.
+    pub async fn head_with_headers(
+        &self,
+        u: &Url,
+        headers: &HeaderMap,
+    ) -> Result<Response, WebmentionError> {
+        let (res, redirects, _permit) = self.execute(Method::HEAD, u, headers, &|req| req).await?;
+        let url = res.url().clone();
+        let headers = res.headers().clone();
+
+        Ok(Response {
+            html: HTML::new(url.clone(), String::new()),
+            url,
+            redirects,
+            status: res.status(),
+            content_type: headers
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(|content_type| content_type.to_string()),
+            links: LinkHeader::from_headers(headers.get_all(reqwest::header::LINK)),
+            headers,
+        })
+    }
+
+    pub async fn post(
+        &self,
+        endpoint: &Url,
+        body: &(impl Serialize + Sync),
+    ) -> Result<bool, WebmentionError> {
+        let (response, _, _permit) = self
+            .execute_with_retries(Method::POST, endpoint, &HeaderMap::new(), &|req| {
+                req.form(body)
+            })
+            .await
+            .map_err(sending_failed)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(true),
+            reqwest::StatusCode::CREATED => Ok(true),
+            reqwest::StatusCode::ACCEPTED => Ok(true),
+            status => Err(WebmentionError::NotAccepted {
+                endpoint: endpoint.as_str().to_string(),
+                status_code: status,
+                retry_after: retry_after(response.headers()),
+            }),
+        }
+    }
 
-    let link_headers = res.headers().get_all("link");
-    let rels = crate::link_header::all_rels(link_headers);
+    /// POSTs an XML document (such as an XML-RPC call) and returns the response body.
+    pub async fn post_xml(&self, endpoint: &Url, body: String) -> Result<String, WebmentionError> {
+        let (response, _, _permit) = self
+            .execute_with_retries(Method::POST, endpoint, &HeaderMap::new(), &|req| {
+                req.header(reqwest::header::CONTENT_TYPE, "text/xml")
+                    .body(body.clone())
+            })
+            .await
+            .map_err(sending_failed)?;
+        self.accepted_body(endpoint, response).await
+    }
 
-    let response =
-        res.text_with_charset("utf-8")
+    /// POSTs `body` as a form and returns the response body, for APIs that answer with content.
+    pub async fn post_form(
+        &self,
+        endpoint: &Url,
+        body: &(impl Serialize + Sync),
+    ) -> Result<String, WebmentionError> {
+        let (response, _, _permit) = self
+            .execute_with_retries(Method::POST, endpoint, &HeaderMap::new(), &|req| {
+                req.form(body)
+            })
             .await
-            .map_err(|err| WebmentionError::RequestFailedRecv {
-                url: u.clone(),
-                source: err.into(),
-            })?;
-
-    Ok(Response {
-        url: u.clone(),
-        html: HTML::new(u.clone(), response),
-        rels,
-    })
+            .map_err(sending_failed)?;
+        self.accepted_body(endpoint, response).await
+    }
+
+    /// Reads the body of a successful response to POST, fails with
+    /// [NotAccepted](WebmentionError::NotAccepted) otherwise.
+    async fn accepted_body(
+        &self,
+        endpoint: &Url,
+        response: reqwest::Response,
+    ) -> Result<String, WebmentionError> {
+        if !response.status().is_success() {
+            return Err(WebmentionError::NotAccepted {
+                endpoint: endpoint.as_str().to_string(),
+                status_code: response.status(),
+                retry_after: retry_after(response.headers()),
+            });
+        }
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+        let body = self.read_body(endpoint, response).await?;
+        Ok(crate::charset::decode(&body, content_type.as_deref()))
+    }
+
+    /// Reads the body chunk by chunk, giving up as soon as it exceeds `max_body_size`.
+    async fn read_body(
+        &self,
+        u: &Url,
+        mut res: reqwest::Response,
+    ) -> Result<Vec<u8>, WebmentionError> {
+        let too_large = || WebmentionError::ResponseTooLarge {
+            url: u.clone(),
+            limit: self.max_body_size,
+        };
+
+        if let Some(length) = res.content_length() {
+            if length > self.max_body_size as u64 {
+                return Err(too_large());
+            }
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) =
+            res.chunk()
+                .await
+                .map_err(|err| WebmentionError::RequestFailedRecv {
+                    url: u.clone(),
+                    source: err.into(),
+                })?
+        {
+            if body.len() + chunk.len() > self.max_body_size {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// Sends the request with [execute](HttpClient::execute), repeating it according to
+    /// `retry_policy` while it fails with retryable errors or statuses. The last response is
+    /// returned even if its status is still retryable.
+    async fn execute_with_retries(
+        &self,
+        method: Method,
+        u: &Url,
+        headers: &HeaderMap,
+        with_body: &(dyn Fn(RequestBuilder) -> RequestBuilder + Sync),
+    ) -> Result<(reqwest::Response, Vec<Redirect>, RequestPermit), WebmentionError> {
+        let mut attempt = 1;
+        loop {
+            let result = self.execute(method.clone(), u, headers, with_body).await;
+            let retry_after = match &result {
+                Ok((res, _, _)) if RetryPolicy::is_retryable_status(res.status()) => {
+                    retry_after(res.headers())
+                }
+                Err(err) if err.is_retryable() => None,
+                _ => return result,
+            };
+
+            match self.retry_policy.next_delay(attempt, retry_after) {
+                Some(delay) => {
+                    // do not hold the permit while waiting
+                    drop(result);
+                    tokio::time::sleep(delay).await
+                }
+                None => return result,
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Sends the request, following redirects manually so that every hop is checked against
+    /// the address policy and connects exactly to the address that was checked.
+    ///
+    /// `headers` are sent on every hop, while `with_body` is applied to the request as long as
+    /// the redirects preserve the method. Returns the final response along with the redirects
+    /// that led to it, and the permit of [scheduler](HttpClient::scheduler) that should be held
+    /// until its body is read.
+    async fn execute(
+        &self,
+        method: Method,
+        u: &Url,
+        headers: &HeaderMap,
+        with_body: &(dyn Fn(RequestBuilder) -> RequestBuilder + Sync),
+    ) -> Result<(reqwest::Response, Vec<Redirect>, RequestPermit), WebmentionError> {
+        let mut method = method;
+        let mut url = u.clone();
+        let mut preserve_body = true;
+        let mut redirects = Vec::new();
+
+        loop {
+            let permit = self.scheduler.acquire(&url).await;
+            let address = self.address_policy.resolve(&url).await?;
+            let mut builder = reqwest::Client::builder().redirect(Policy::none());
+            if let Some(url::Host::Domain(domain)) = url.host() {
+                builder = builder.resolve(domain, address);
+            }
+            let client = builder
+                .build()
+                .map_err(|err| WebmentionError::RequestFailed {
+                    url: url.clone(),
+                    source: err.into(),
+                })?;
+
+            let mut req = client
+                .request(method.clone(), url.clone())
+                .headers(headers.clone());
+            if preserve_body {
+                req = with_body(req);
+            }
+            let res = req
+                .send()
+                .await
+                .map_err(|err| WebmentionError::RequestFailed {
+                    url: url.clone(),
+                    source: err.into(),
+                })?;
+
+            let location = res
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok());
+            let next = match location {
+                Some(location) if res.status().is_redirection() => absolute_url(location, &url)?,
+                _ => return Ok((res, redirects, permit)),
+            };
+            if redirects.len() >= self.max_redirects {
+                return Err(WebmentionError::TooManyRedirects { url: u.clone() });
+            }
+
+            // same as browsers do: only 307 and 308 preserve the method and the body
+            if res.status() != StatusCode::TEMPORARY_REDIRECT
+                && res.status() != StatusCode::PERMANENT_REDIRECT
+                && method != Method::HEAD
+            {
+                method = Method::GET;
+                preserve_body = false;
+            }
+            redirects.push(Redirect {
+                from: url,
+                to: next.clone(),
+                status: res.status(),
+            });
+            url = next;
+        }
+    }
+}
+
+/// Whether the content type is something we could look for links in: HTML, XML, JSON or text.
+fn is_textual(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let (kind, subtype) = match essence.split_once('/') {
+        Some(parts) => parts,
+        None => return false,
+    };
+
+    kind == "text"
+        || (kind == "application"
+            && (matches!(subtype, "xhtml+xml" | "xml" | "json")
+                || subtype.ends_with("+xml")
+                || subtype.ends_with("+json")))
 }
 
-pub async fn post(endpoint: &Url, body: &impl Serialize) -> Result<bool, WebmentionError> {
-    let client = reqwest::Client::new();
-
-    let response = client
-        .post(endpoint.clone())
-        .form(body)
-        .send()
-        .await
-        .map_err(|source| WebmentionError::SendingRequestFailed {
-            url: endpoint.clone(),
-            source: source.into(),
-        })?;
-
-    match response.status() {
-        reqwest::StatusCode::OK => Ok(true),
-        reqwest::StatusCode::CREATED => Ok(true),
-        reqwest::StatusCode::ACCEPTED => Ok(true),
-        status => Err(WebmentionError::NotAccepted {
-            endpoint: endpoint.as_str().to_string(),
-            status_code: status,
-        }),
+fn sending_failed(err: WebmentionError) -> WebmentionError {
+    match err {
+        WebmentionError::RequestFailed { url, source } => {
+            WebmentionError::SendingRequestFailed { url, source }
+        }
+        err => err,
     }
 }
 
+pub async fn get(u: &Url) -> Result<Response, WebmentionError> {
+    HttpClient::default().get(u).await
+}
+
+pub async fn post(endpoint: &Url, body: &(impl Serialize + Sync)) -> Result<bool, WebmentionError> {
+    HttpClient::default().post(endpoint, body).await
+}
+
 #[cfg(test)]
-mod test {
-    use super::get;
+pub(crate) mod test {
+    use super::{get, is_textual, HttpClient, Redirect, Response};
+    use crate::address_policy::AddressPolicy;
+    use crate::error::WebmentionError;
+    use crate::html::HTML;
+    use crate::link_header::LinkHeader;
+    use crate::rate_limit::{RateLimits, RequestScheduler};
+    use crate::retry::RetryPolicy;
     use crate::wm_url::Url;
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
     use tokio_test::block_on;
+
+    /// Empty directory for files of a test, removed when dropped
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// Directory named after `name` and the process, so that tests do not share it
+        pub(crate) fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("webmention-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Serves a single raw HTTP response on localhost
+    pub(crate) fn serve_once(response: Vec<u8>) -> Url {
+        serve_sequence(vec![response])
+    }
+
+    /// Serves raw HTTP responses on localhost, one per connection
+    pub(crate) fn serve_sequence(responses: Vec<Vec<u8>>) -> Url {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(&response);
+            }
+        });
+        url
+    }
+
+    pub(crate) fn local_client(max_body_size: usize) -> HttpClient {
+        HttpClient {
+            max_body_size,
+            scheduler: Arc::new(RequestScheduler::new(RateLimits::unlimited())),
+            ..HttpClient::new(AddressPolicy::allow_all())
+        }
+    }
+
+    /// Source at `https://alice.example/notes/reply` fetched with `ETag: "v1"`, without
+    /// a server
+    pub(crate) fn response(content_type: &str, body: &str) -> Response {
+        let source = Url::parse("https://alice.example/notes/reply").unwrap();
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::ETAG,
+            reqwest::header::HeaderValue::from_static("\"v1\""),
+        );
+        Response {
+            html: HTML::new(source.clone(), body.to_string()),
+            url: source,
+            redirects: Vec::new(),
+            status: reqwest::StatusCode::OK,
+            content_type: Some(content_type.to_string()),
+            links: LinkHeader::default(),
+            headers,
+        }
+    }
+
+    #[test]
+    fn test_is_textual() {
+        assert!(is_textual("text/html; charset=utf-8"));
+        assert!(is_textual("text/plain"));
+        assert!(is_textual("application/xhtml+xml"));
+        assert!(is_textual("application/atom+xml"));
+        assert!(is_textual("application/JSON"));
+        assert!(is_textual("application/mf2+json"));
+        assert!(!is_textual("image/png"));
+        assert!(!is_textual("application/octet-stream"));
+        assert!(!is_textual("video/mp4"));
+        assert!(!is_textual("garbage"));
+    }
+
+    #[test]
+    fn test_body_limit() {
+        let body = "<a href=\"https://example.com/\">link</a>".repeat(100);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\n\r\n{}",
+            body
+        );
+
+        let url = serve_once(response.clone().into_bytes());
+        let fetched = block_on(local_client(body.len()).get(&url)).unwrap();
+        assert!(fetched
+            .html
+            .contains(&Url::parse("https://example.com/").unwrap())
+            .is_ok());
+
+        let url = serve_once(response.into_bytes());
+        match block_on(local_client(body.len() - 1).get(&url)) {
+            Err(WebmentionError::ResponseTooLarge { limit, .. }) => {
+                assert_eq!(limit, body.len() - 1)
+            }
+            other => panic!("unexpected result {:?}", other.map(|r| r.url)),
+        }
+    }
+
+    #[test]
+    fn test_binary_content_type() {
+        let url = serve_once(
+            b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 4\r\n\r\n\x89PNG"
+                .to_vec(),
+        );
+        match block_on(local_client(1024).get(&url)) {
+            Err(WebmentionError::UnsupportedContentType { content_type, .. }) => {
+                assert_eq!(content_type, "image/png")
+            }
+            other => panic!("unexpected result {:?}", other.map(|r| r.url)),
+        }
+    }
+
+    #[test]
+    fn test_redirect_chain() {
+        let page = serve_once(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\n\r\n<a href=\"post\">x</a>"
+                .to_vec(),
+        );
+        let moved = serve_once(
+            format!(
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: {}notes/\r\nConnection: close\r\n\r\n",
+                page
+            )
+            .into_bytes(),
+        );
+        let start = serve_once(
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nConnection: close\r\n\r\n",
+                moved
+            )
+            .into_bytes(),
+        );
+        let notes = page.join("notes/").unwrap();
+
+        let response = block_on(local_client(1024).get(&start)).unwrap();
+        assert_eq!(response.url, notes);
+        assert_eq!(
+            response.redirects,
+            vec![
+                Redirect {
+                    from: start.clone(),
+                    to: moved.clone(),
+                    status: reqwest::StatusCode::FOUND,
+                },
+                Redirect {
+                    from: moved,
+                    to: notes.clone(),
+                    status: reqwest::StatusCode::MOVED_PERMANENTLY,
+                },
+            ]
+        );
+        assert!(response.html.contains(&notes.join("post").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_headers_follow_redirects() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let page = Url::parse(&format!("http://{}/page", listener.local_addr().unwrap())).unwrap();
+        std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 4096];
+                let read = stream.read(&mut request).unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..read]).to_ascii_lowercase();
+                let response = if request.starts_with("get /page ") {
+                    if request.contains("if-none-match: \"v1\"") {
+                        "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n"
+                    } else {
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\n\r\n"
+                    }
+                } else {
+                    "HTTP/1.1 301 Moved Permanently\r\nLocation: /page\r\nConnection: close\r\n\r\n"
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        let start = page.join("/start").unwrap();
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::IF_NONE_MATCH,
+            reqwest::header::HeaderValue::from_static("\"v1\""),
+        );
+        let response = block_on(local_client(1024).get_with_headers(&start, &headers)).unwrap();
+        assert_eq!(response.url, page);
+        assert_eq!(response.status, reqwest::StatusCode::NOT_MODIFIED);
+        assert_eq!(response.redirects.len(), 1);
+    }
+
+    #[test]
+    fn test_too_many_redirects() {
+        let page = serve_once(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n".to_vec());
+        let start = serve_once(
+            format!(
+                "HTTP/1.1 307 Temporary Redirect\r\nLocation: {}\r\nConnection: close\r\n\r\n",
+                page
+            )
+            .into_bytes(),
+        );
+        let client = HttpClient {
+            max_redirects: 0,
+            ..local_client(1024)
+        };
+        match block_on(client.get(&start)) {
+            Err(WebmentionError::TooManyRedirects { url }) => assert_eq!(url, start),
+            other => panic!("unexpected result {:?}", other.map(|r| r.url)),
+        }
+    }
+
+    fn retrying_client() -> HttpClient {
+        HttpClient {
+            retry_policy: RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_secs(1),
+                jitter: true,
+            },
+            ..local_client(1024)
+        }
+    }
+
+    #[test]
+    fn test_retry_post() {
+        let endpoint = serve_sequence(vec![
+            b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        let form = [("source", "a"), ("target", "b")];
+        assert!(block_on(retrying_client().post(&endpoint, &form)).unwrap());
+    }
+
+    #[test]
+    fn test_retry_gives_up() {
+        let url = serve_sequence(vec![
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 7\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+        ]);
+        match block_on(retrying_client().get(&url)) {
+            Err(err @ WebmentionError::UnavailableDocument { .. }) => {
+                assert!(err.is_retryable());
+                assert_eq!(err.retry_after(), Some(Duration::from_secs(7)));
+            }
+            other => panic!("unexpected result {:?}", other.map(|r| r.url)),
+        }
+
+        // asking to wait longer than max_backoff stops retries right away
+        let endpoint = serve_sequence(vec![
+            b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 3600\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+        ]);
+        match block_on(retrying_client().post(&endpoint, &[("source", "a")])) {
+            Err(WebmentionError::NotAccepted { retry_after, .. }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(3600)))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // client errors are permanent
+        let endpoint = serve_sequence(vec![
+            b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        match block_on(retrying_client().post(&endpoint, &[("source", "a")])) {
+            Err(err @ WebmentionError::NotAccepted { .. }) => assert!(!err.is_retryable()),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        let url = Url::parse("https://unknown.invalid/").unwrap();
+        let io_error = |kind, message| WebmentionError::RequestFailed {
+            url: url.clone(),
+            source: std::io::Error::new(kind, message).into(),
+        };
+        assert!(io_error(std::io::ErrorKind::TimedOut, "timed out").is_retryable());
+        assert!(!io_error(
+            std::io::ErrorKind::Other,
+            "failed to lookup address information: Name or service not known"
+        )
+        .is_retryable());
+
+        // what resolving an unknown host fails with, unless there is no network at all
+        let policy = AddressPolicy::default();
+        match block_on(policy.resolve(&url)) {
+            Err(err @ WebmentionError::RequestFailed { .. }) => assert!(!err.is_retryable()),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // nothing listens on the port of a dropped listener
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let refused = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+        match block_on(local_client(1024).get(&refused)) {
+            Err(err) => assert!(err.is_retryable(), "{:?}", err),
+            Ok(response) => panic!("unexpected response from {}", response.url),
+        }
+    }
+
+    #[test]
+    fn test_rate_limited_requests() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+        let url = serve_sequence(vec![ok.to_vec(), ok.to_vec()]);
+        let client = HttpClient {
+            scheduler: Arc::new(RequestScheduler::new(RateLimits {
+                requests_per_second_per_host: 10.0,
+                ..RateLimits::unlimited()
+            })),
+            ..local_client(1024)
+        };
+
+        // clones share the scheduler
+        block_on(async {
+            tokio::time::pause();
+            let started = tokio::time::Instant::now();
+            client.get(&url).await.unwrap();
+            client.clone().get(&url).await.unwrap();
+            let elapsed = started.elapsed();
+            assert!(elapsed >= Duration::from_millis(100) && elapsed <= Duration::from_millis(101));
+        });
+    }
+
     #[test]
     fn fetch_url_test() {
         let url: Url = Url::parse("https://httpbin.org/get").unwrap();
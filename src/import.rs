@@ -0,0 +1,638 @@
+use crate::error::WebmentionError;
+use crate::export::{needs_formula_guard, Format};
+use crate::html::{visible_text, HTML};
+use crate::snapshot::{escape, sanitize, truncate, Author, MentionKind, Snapshot, SnapshotOptions};
+use crate::storage::WebmentionStorage;
+use crate::timestamp;
+use crate::webmention::{Moderation, Webmention};
+use crate::wm_url::{absolute_url, Url, UrlEquivalence};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Export of the webmention.io API in JF2: a feed with entries as `children`. Entries are
+/// read one by one, so that an invalid one does not spoil the others.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Jf2Document {
+    Feed { children: Vec<serde_json::Value> },
+    Entries(Vec<serde_json::Value>),
+}
+
+/// A single mention as webmention.io describes it
+#[derive(Debug, Deserialize)]
+struct Jf2Entry {
+    #[serde(rename = "wm-source")]
+    source: Url,
+    #[serde(rename = "wm-target")]
+    target: Url,
+    #[serde(rename = "wm-property")]
+    property: Option<String>,
+    #[serde(rename = "wm-received")]
+    received: Option<String>,
+    #[serde(rename = "wm-private", default)]
+    private: bool,
+    /// Moderation of webmentions [exported](crate::export) by this crate
+    #[serde(rename = "wm-moderation")]
+    moderation: Option<Moderation>,
+    /// Whether webmentions exported by this crate have a snapshot. Those of webmention.io
+    /// always have one.
+    #[serde(rename = "wm-snapshot")]
+    has_snapshot: Option<bool>,
+    url: Option<String>,
+    published: Option<String>,
+    author: Option<Jf2Author>,
+    content: Option<Jf2Content>,
+    #[serde(rename = "in-reply-to")]
+    in_reply_to: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jf2Author {
+    name: Option<String>,
+    url: Option<String>,
+    photo: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jf2Content {
+    html: Option<String>,
+    text: Option<String>,
+}
+
+impl Jf2Entry {
+    fn into_webmention(self, options: &SnapshotOptions) -> Webmention {
+        let has_snapshot = self.has_snapshot.unwrap_or(true);
+        let base = self
+            .url
+            .as_deref()
+            .and_then(|url| Url::parse(url).ok())
+            .unwrap_or_else(|| self.source.clone());
+        let url = |url: Option<String>| {
+            url.filter(|url| !url.trim().is_empty())
+                .and_then(|url| absolute_url(url.trim(), &base).ok())
+        };
+
+        let (content, text) = match self.content {
+            Some(Jf2Content {
+                html: Some(html),
+                text,
+            }) => {
+                let content = sanitize(&html, &base);
+                let text = text.unwrap_or_else(|| {
+                    HTML::new(base.clone(), content.clone())
+                        .main_content()
+                        .map(|body| visible_text(&body))
+                        .unwrap_or_default()
+                });
+                (content, text)
+            }
+            Some(Jf2Content {
+                text: Some(text), ..
+            }) => (format!("<p>{}</p>", escape(text.trim())), text),
+            _ => (String::new(), String::new()),
+        };
+        let kind = MentionKind::from_property(self.property.as_deref().unwrap_or_default());
+        let in_reply_to = match self.in_reply_to {
+            Some(serde_json::Value::String(url)) => vec![url],
+            Some(serde_json::Value::Array(urls)) => urls
+                .into_iter()
+                .filter_map(|url| url.as_str().map(str::to_string))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let mut mention = Webmention::from((self.source, self.target));
+        mention.set_checked(true);
+        // webmention.io displays public mentions without moderation
+        mention.moderation = self.moderation.unwrap_or(if self.private {
+            Moderation::Pending
+        } else {
+            Moderation::Approved
+        });
+        if !has_snapshot {
+            return mention;
+        }
+        mention.snapshot = Some(Snapshot {
+            content,
+            summary: truncate(
+                &text.split_whitespace().collect::<Vec<_>>().join(" "),
+                options.max_summary_length,
+            ),
+            fetched_at: self
+                .received
+                .as_deref()
+                .and_then(timestamp::parse)
+                .unwrap_or_else(SystemTime::now),
+            etag: None,
+            kind,
+            author: self
+                .author
+                .map(|author| Author {
+                    name: author.name.filter(|name| !name.trim().is_empty()),
+                    url: url(author.url),
+                    photo: url(author.photo),
+                })
+                .filter(|author| author != &Author::default()),
+            published: self.published.filter(|published| !published.is_empty()),
+            in_reply_to: in_reply_to
+                .into_iter()
+                .filter_map(|u| url(Some(u)))
+                .collect(),
+        });
+        mention
+    }
+}
+
+/// Parses a JF2 export of [webmention.io](https://webmention.io), either a feed with entries as
+/// `children` or an array of entries.
+///
+/// `wm-source` and `wm-target` become source and target, `wm-property` the
+/// [kind](MentionKind), and `author`, `content`, `published` and `wm-received` make the
+/// [snapshot](Webmention::snapshot). HTML content is sanitized. Public mentions are approved,
+/// private ones are pending.
+///
+/// Entries that are not webmentions, such as those with an invalid `wm-source`, are skipped
+/// and reported in [Parsed::invalid]. Fails only if `json` is not a JF2 document at all.
+pub fn parse_webmention_io(
+    json: &str,
+    options: &SnapshotOptions,
+) -> Result<Parsed, serde_json::Error> {
+    let entries = match serde_json::from_str(json)? {
+        Jf2Document::Feed { children } => children,
+        Jf2Document::Entries(entries) => entries,
+    };
+    let mut parsed = Parsed::default();
+    for (index, entry) in entries.into_iter().enumerate() {
+        match serde_json::from_value::<Jf2Entry>(entry) {
+            Ok(entry) => parsed.mentions.push(entry.into_webmention(options)),
+            Err(err) => parsed.invalid.push(WebmentionError::InvalidEntry {
+                entry: index + 1,
+                reason: err.to_string(),
+            }),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Reads a JF2 export of webmention.io from `path`, see [parse_webmention_io].
+pub fn read_webmention_io(
+    path: impl AsRef<Path>,
+    options: &SnapshotOptions,
+) -> Result<Parsed, WebmentionError> {
+    let path = path.as_ref();
+    let json = std::fs::read_to_string(path).map_err(|source| WebmentionError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse_webmention_io(&json, options).map_err(|err| WebmentionError::InvalidFile {
+        path: path.to_path_buf(),
+        source: err.into(),
+    })
+}
+
+/// Parses webmentions [exported](crate::export::to_jsonl) as JSON Lines. Content of snapshots
+/// is sanitized again, as the file may have been edited since.
+pub fn parse_jsonl(text: &str) -> Result<Vec<Webmention>, WebmentionError> {
+    let mut mentions = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mention = serde_json::from_str(line).map_err(|err| WebmentionError::InvalidRecord {
+            line: number + 1,
+            reason: err.to_string(),
+        })?;
+        mentions.push(sanitized(mention));
+    }
+    Ok(mentions)
+}
+
+/// Parses webmentions [exported](crate::export::to_csv) as CSV. Columns are found by the names
+/// in the header, so they may be in any order, and only `source` and `target` are required.
+/// The `'` that guards fields from spreadsheets is removed, and content is sanitized.
+pub fn parse_csv(text: &str) -> Result<Vec<Webmention>, WebmentionError> {
+    let mut records = csv_records(text)?.into_iter();
+    let header = match records.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+    let mut mentions = Vec::new();
+    for (line, record) in records {
+        let field = |name: &str| -> &str {
+            header
+                .iter()
+                .position(|column| column == name)
+                .and_then(|index| record.get(index))
+                .map(|field| match field.strip_prefix('\'') {
+                    Some(guarded) if needs_formula_guard(guarded) => guarded,
+                    _ => field.as_str(),
+                })
+                .unwrap_or_default()
+        };
+        let invalid = |reason: String| WebmentionError::InvalidRecord { line, reason };
+        let url = |name: &str| -> Result<Option<Url>, WebmentionError> {
+            match field(name) {
+                "" => Ok(None),
+                url => Url::parse(url)
+                    .map(Some)
+                    .map_err(|err| invalid(format!("{} {}: {}", name, url, err))),
+            }
+        };
+        let flag = |name: &str| -> Result<Option<bool>, WebmentionError> {
+            match field(name) {
+                "" => Ok(None),
+                "true" => Ok(Some(true)),
+                "false" => Ok(Some(false)),
+                other => Err(invalid(format!("{} is not true or false: {}", name, other))),
+            }
+        };
+        let text = |name: &str| Some(field(name).to_string()).filter(|text| !text.is_empty());
+
+        let source = url("source")?.ok_or_else(|| invalid("no source".to_string()))?;
+        let target = url("target")?.ok_or_else(|| invalid("no target".to_string()))?;
+        let mut mention = Webmention::from((source, target));
+        if let Some(checked) = flag("checked")? {
+            mention.set_checked(checked);
+        }
+        mention.set_sent(flag("sent")?.unwrap_or_default());
+        if !field("moderation").is_empty() {
+            mention.moderation =
+                serde_json::from_value(serde_json::Value::String(field("moderation").into()))
+                    .map_err(|err| invalid(err.to_string()))?;
+        }
+
+        if let Some(fetched_at) = text("fetched_at") {
+            let author = Author {
+                name: text("author_name"),
+                url: url("author_url")?,
+                photo: url("author_photo")?,
+            };
+            let mut in_reply_to = Vec::new();
+            for reply_to in field("in_reply_to").split_whitespace() {
+                in_reply_to.push(
+                    Url::parse(reply_to)
+                        .map_err(|err| invalid(format!("in_reply_to {}: {}", reply_to, err)))?,
+                );
+            }
+            mention.snapshot = Some(Snapshot {
+                content: field("content").to_string(),
+                summary: field("summary").to_string(),
+                fetched_at: timestamp::parse(&fetched_at)
+                    .ok_or_else(|| invalid(format!("fetched_at is not a time: {}", fetched_at)))?,
+                etag: text("etag"),
+                kind: MentionKind::from_property(field("kind")),
+                author: Some(author).filter(|author| author != &Author::default()),
+                published: text("published"),
+                in_reply_to,
+            });
+        }
+        mentions.push(sanitized(mention));
+    }
+    Ok(mentions)
+}
+
+/// Sanitizes the content of the snapshot of `mention`, which came from a file instead of
+/// [Snapshot::from_response]
+fn sanitized(mut mention: Webmention) -> Webmention {
+    if let Some(snapshot) = &mut mention.snapshot {
+        snapshot.content = sanitize(&snapshot.content, &mention.source);
+    }
+    mention
+}
+
+/// Parses webmentions [exported](crate::export::to_jf2) as JF2, or exported by webmention.io,
+/// see [parse_webmention_io].
+pub fn parse_jf2(text: &str) -> Result<Parsed, WebmentionError> {
+    parse_webmention_io(text, &SnapshotOptions::default()).map_err(|err| {
+        WebmentionError::InvalidRecord {
+            line: err.line(),
+            reason: err.to_string(),
+        }
+    })
+}
+
+/// Reads webmentions exported in `format` from `path`. Only JF2 entries that are not
+/// webmentions are skipped, as invalid JSON Lines or CSV records fail the whole file.
+pub fn read(path: impl AsRef<Path>, format: Format) -> Result<Parsed, WebmentionError> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).map_err(|source| WebmentionError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    match format {
+        Format::Jsonl => parse_jsonl(&text).map(Parsed::from),
+        Format::Csv => parse_csv(&text).map(Parsed::from),
+        Format::Jf2 => parse_jf2(&text),
+    }
+    .map_err(|err| WebmentionError::InvalidFile {
+        path: path.to_path_buf(),
+        source: err.into(),
+    })
+}
+
+/// Splits CSV into records of fields, each with the line it starts on
+fn csv_records(text: &str) -> Result<Vec<(usize, Vec<String>)>, WebmentionError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut record_line = 1;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                if record.iter().any(|field| !field.is_empty()) {
+                    records.push((record_line, std::mem::take(&mut record)));
+                }
+                record.clear();
+                line += 1;
+                record_line = line;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if quoted {
+        return Err(WebmentionError::InvalidRecord {
+            line: record_line,
+            reason: "unterminated quote".to_string(),
+        });
+    }
+    record.push(field);
+    if record.iter().any(|field| !field.is_empty()) {
+        records.push((record_line, record));
+    }
+    Ok(records)
+}
+
+/// Webmentions read from an export, with the entries that were skipped
+#[derive(Debug, Default)]
+pub struct Parsed {
+    pub mentions: Vec<Webmention>,
+    /// [InvalidEntry](WebmentionError::InvalidEntry) for every entry that is not a webmention
+    pub invalid: Vec<WebmentionError>,
+}
+
+impl From<Vec<Webmention>> for Parsed {
+    fn from(mentions: Vec<Webmention>) -> Parsed {
+        Parsed {
+            mentions,
+            invalid: Vec::new(),
+        }
+    }
+}
+
+/// Outcome of an [import]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Webmentions skipped because the storage already had one with the same source and target
+    pub duplicates: usize,
+    /// Entries of the export skipped because they are not webmentions, see [Parsed::invalid]
+    pub invalid: usize,
+}
+
+/// Stores `mentions` that are not in `storage` yet. Webmentions with equivalent sources and
+/// targets are duplicates, whether they were stored earlier or come earlier in `mentions`.
+pub fn import(
+    storage: &impl WebmentionStorage,
+    mentions: impl Into<Parsed>,
+) -> Result<ImportSummary, WebmentionError> {
+    import_with(storage, mentions, &UrlEquivalence::default())
+}
+
+/// Same as [import], but compares sources with `equivalence`, which should be the one `storage`
+/// looks targets up with.
+pub fn import_with(
+    storage: &impl WebmentionStorage,
+    mentions: impl Into<Parsed>,
+    equivalence: &UrlEquivalence,
+) -> Result<ImportSummary, WebmentionError> {
+    let parsed = mentions.into();
+    let mut summary = ImportSummary {
+        invalid: parsed.invalid.len(),
+        ..ImportSummary::default()
+    };
+    for mention in parsed.mentions {
+        let source = equivalence.normalize(&mention.source);
+        let duplicate = storage
+            .lookup_by_target(mention.target.clone())?
+            .iter()
+            .any(|stored| equivalence.normalize(&stored.source) == source);
+        if duplicate {
+            summary.duplicates += 1;
+        } else {
+            storage.store(mention)?;
+            summary.imported += 1;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{import, parse_csv, parse_jsonl, parse_webmention_io, ImportSummary};
+    use crate::error::WebmentionError;
+    use crate::render::Renderer;
+    use crate::snapshot::{MentionKind, SnapshotOptions};
+    use crate::storage::{InMemoryWebmentionStorage, WebmentionStorage};
+    use crate::webmention::Moderation;
+    use crate::wm_url::Url;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const EXPORT: &str = r#"{
+      "type": "feed",
+      "name": "Webmentions",
+      "children": [
+        {
+          "type": "entry",
+          "author": {"type": "card", "name": "Alice", "photo": "https://alice.example/me.jpg",
+                     "url": "https://alice.example/"},
+          "url": "https://alice.example/replies/1",
+          "published": "2019-03-16T17:20:45+00:00",
+          "wm-received": "2019-03-16T17:21:00Z",
+          "wm-id": 612345,
+          "wm-source": "https://alice.example/replies/1",
+          "wm-target": "https://bob.example/post",
+          "content": {"html": "<p>Nice <script>x</script><a href=\"/about\">post</a></p>",
+                      "text": "Nice post"},
+          "in-reply-to": "https://bob.example/post",
+          "wm-property": "in-reply-to",
+          "wm-private": false
+        },
+        {
+          "type": "entry",
+          "author": {"type": "card", "name": "", "photo": "", "url": ""},
+          "url": "https://twitter.example/carol/status/1",
+          "published": null,
+          "wm-received": "2020-01-01T00:00:00Z",
+          "wm-source": "https://brid.example/like/carol/1",
+          "wm-target": "https://bob.example/post",
+          "like-of": "https://bob.example/post",
+          "wm-property": "like-of",
+          "wm-private": true
+        },
+        {
+          "type": "entry",
+          "wm-source": "not a url",
+          "wm-target": "https://bob.example/post"
+        }
+      ]
+    }"#;
+
+    #[test]
+    fn test_parse_webmention_io() {
+        let parsed = parse_webmention_io(EXPORT, &SnapshotOptions::default()).unwrap();
+        assert!(matches!(
+            parsed.invalid[..],
+            [WebmentionError::InvalidEntry { entry: 3, .. }]
+        ));
+        let mentions = parsed.mentions;
+        assert_eq!(mentions.len(), 2);
+
+        let reply = &mentions[0];
+        assert_eq!(reply.source.as_str(), "https://alice.example/replies/1");
+        assert_eq!(reply.moderation, Moderation::Approved);
+        let snapshot = reply.snapshot.as_ref().unwrap();
+        assert_eq!(snapshot.kind, MentionKind::Reply);
+        assert_eq!(
+            snapshot.content,
+            "<p>Nice <a href=\"https://alice.example/about\" \
+             rel=\"nofollow ugc noopener noreferrer\">post</a></p>"
+        );
+        assert_eq!(snapshot.summary, "Nice post");
+        assert_eq!(
+            snapshot.author.as_ref().unwrap().name.as_deref(),
+            Some("Alice")
+        );
+        assert_eq!(
+            snapshot.fetched_at,
+            UNIX_EPOCH + Duration::from_secs(1_552_756_860)
+        );
+        assert_eq!(snapshot.in_reply_to.len(), 1);
+
+        let like = &mentions[1];
+        assert_eq!(like.moderation, Moderation::Pending);
+        let snapshot = like.snapshot.as_ref().unwrap();
+        assert_eq!(snapshot.kind, MentionKind::Like);
+        assert_eq!(snapshot.author, None);
+        assert_eq!(snapshot.published, None);
+
+        let entries =
+            r#"[{"wm-source": "https://dave.example/", "wm-target": "https://bob.example/"}]"#;
+        let mentions = parse_webmention_io(entries, &SnapshotOptions::default())
+            .unwrap()
+            .mentions;
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(
+            mentions[0].snapshot.as_ref().unwrap().kind,
+            MentionKind::Mention
+        );
+    }
+
+    #[test]
+    fn test_import() {
+        let storage = InMemoryWebmentionStorage::new();
+        let parsed = parse_webmention_io(EXPORT, &SnapshotOptions::default()).unwrap();
+        let mentions = parsed.mentions.clone();
+        let summary = import(&storage, parsed).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                imported: 2,
+                duplicates: 0,
+                invalid: 1,
+            }
+        );
+
+        let mut again = mentions;
+        again[0].source = Url::parse("https://ALICE.example/replies/1").unwrap();
+        assert_eq!(import(&storage, again).unwrap().duplicates, 2);
+        let target = Url::parse("https://bob.example/post").unwrap();
+        assert_eq!(storage.lookup_by_target(target).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_sanitized_imports() {
+        let content = "<p onclick=\"steal()\">Hi<script>alert(1)</script></p>";
+        let csv = format!(
+            "source,target,kind,fetched_at,content\n\
+             https://alice.example/,https://bob.example/,in-reply-to,2024-01-01T00:00:00Z,{}\n",
+            content
+        );
+        let jsonl = format!(
+            "{{\"source\":\"https://carol.example/\",\"target\":\"https://bob.example/\",\
+             \"checked\":true,\"sent\":false,\"snapshot\":{{\"content\":{:?},\"summary\":\"Hi\",\
+             \"fetched_at\":{{\"secs_since_epoch\":0,\"nanos_since_epoch\":0}},\"etag\":null,\
+             \"kind\":\"Reply\"}}}}",
+            content
+        );
+
+        let storage = InMemoryWebmentionStorage::new();
+        import(&storage, parse_csv(&csv).unwrap()).unwrap();
+        import(&storage, parse_jsonl(&jsonl).unwrap()).unwrap();
+        let mentions = storage
+            .lookup_by_target(Url::parse("https://bob.example/").unwrap())
+            .unwrap();
+        assert_eq!(mentions.len(), 2);
+        for mention in &mentions {
+            assert_eq!(mention.snapshot.as_ref().unwrap().content, "<p>Hi</p>");
+        }
+        let html = Renderer {
+            include_pending: true,
+            ..Renderer::default()
+        }
+        .render(&mentions);
+        assert!(html.contains("<p>Hi</p>"));
+        assert!(!html.contains("script"));
+        assert!(!html.contains("steal"));
+    }
+
+    #[test]
+    fn test_parse_records() {
+        let csv = "target,source,note\r\n\
+                   https://bob.example/,https://alice.example/,\"a, \"\"b\"\"\nc\"\r\n\
+                   \r\n\
+                   https://bob.example/,https://carol.example/,\n";
+        let mentions = parse_csv(csv).unwrap();
+        assert_eq!(mentions.len(), 2);
+        assert_eq!(mentions[1].source.as_str(), "https://carol.example/");
+        assert_eq!(mentions[1].checked(), None);
+        assert!(mentions[1].snapshot.is_none());
+
+        let invalid = "target,source\n\"https://bob.example/,\nhttps://alice.example/\n";
+        assert!(matches!(
+            parse_csv(invalid),
+            Err(WebmentionError::InvalidRecord { line: 2, .. })
+        ));
+        let invalid = "source,target\nhttps://bob.example/,\n";
+        assert!(matches!(
+            parse_csv(invalid),
+            Err(WebmentionError::InvalidRecord { line: 2, .. })
+        ));
+
+        let jsonl = "{\"source\":\"https://a.example/\",\"target\":\"https://b.example/\",\
+                     \"checked\":null,\"sent\":false}\n\n{";
+        assert_eq!(parse_jsonl(&jsonl[..jsonl.len() - 1]).unwrap().len(), 1);
+        assert!(matches!(
+            parse_jsonl(jsonl),
+            Err(WebmentionError::InvalidRecord { line: 3, .. })
+        ));
+    }
+}
@@ -14,45 +14,93 @@
 //!
 //! There is a CLI tool available with support for sending webmentions, and (optionally) a simple endpoint
 //! based on Rocket.
+//!
+//! ## Features
+//!
+//! - `receiver`: verifying and storing incoming webmentions, for any HTTP framework
+//! - `receive`: `receiver` with the Rocket endpoint in the CLI tool
+//! - `cli`: the CLI tool
+//! - `pingback`: sending and receiving pingbacks
 
+/// Defines which remote addresses we are allowed to connect to
+pub mod address_policy;
 pub mod error;
 /// Defines document
 pub mod html;
 /// Defines http_client that is used for GETting and POSTing
 pub mod http_client;
-/// Defines utility to deal with LINK header
+/// Parses and serializes `Link` headers as specified by RFC 8288
 pub mod link_header;
+/// Sending pingbacks to targets without webmention endpoint
+#[cfg(feature = "pingback")]
+pub mod pingback;
 
+/// Defines utility to detect encoding of fetched documents.
+pub(crate) mod charset;
 /// Specifies the endpoint discovery algorithm
 pub mod endpoint_discovery;
+/// Exports stored webmentions for backups, other storages and spreadsheets
+#[cfg(feature = "receiver")]
+pub mod export;
+/// Imports webmentions received by other services, or exported earlier
+#[cfg(feature = "receiver")]
+pub mod import;
+/// Persistent queue of outgoing webmentions and the worker delivering them
+pub mod queue;
+/// Limits how many requests are made at once and how often hosts are contacted
+pub mod rate_limit;
+/// Verifies and stores incoming webmentions, limiting how many are processed
+#[cfg(feature = "receiver")]
+pub mod receiver;
+/// Renders stored webmentions as HTML, e.g. for static site generators
+#[cfg(feature = "receiver")]
+pub mod render;
+/// Defines when and how often failed requests are repeated
+pub mod retry;
+/// Keeps what sources looked like when their webmentions were verified
+pub mod snapshot;
 /// Defines interface for webmention storage
-#[cfg(feature = "receive")]
+#[cfg(feature = "receiver")]
 pub mod storage;
+/// Formats and parses ISO 8601 timestamps.
+#[cfg(feature = "receiver")]
+pub(crate) mod timestamp;
+/// Defines how sources of different content types are checked for links to target
+pub mod verification;
 pub mod webmention;
-/// Defines utility to deal with URLs.
-pub(crate) mod wm_url;
+/// Defines utility to deal with URLs and their equivalence.
+pub mod wm_url;
 
 /// Various error conditions that could happen during processing webmentions
 pub use crate::error::WebmentionError;
+/// HTTP client which could be configured to restrict where requests go
+pub use crate::http_client::HttpClient;
 /// Source URL and target URL combined with some metadata
 pub use crate::webmention::Webmention;
 
-#[cfg(feature = "receive")]
+/// Verifies that `source` links to `target` and stores the webmention.
+///
+/// Returns `Ok(false)` if `source` does not link to `target`.
+#[cfg(feature = "receiver")]
 pub async fn receive_webmention(
     storage: &impl crate::storage::WebmentionStorage,
-    source: &Url,
-    target: &Url,
+    source: &crate::wm_url::Url,
+    target: &crate::wm_url::Url,
+) -> Result<bool, WebmentionError> {
+    receive_webmention_with(&HttpClient::default(), storage, source, target).await
+}
+
+/// Same as [receive_webmention], but fetches the source with the given `client`.
+///
+/// See [Receiver](crate::receiver::Receiver) for limits on incoming webmentions.
+#[cfg(feature = "receiver")]
+pub async fn receive_webmention_with(
+    client: &HttpClient,
+    storage: &impl crate::storage::WebmentionStorage,
+    source: &crate::wm_url::Url,
+    target: &crate::wm_url::Url,
 ) -> Result<bool, WebmentionError> {
-    let mut mention = Webmention::from((source.clone(), target.clone()));
-    if mention.check().await? {
-        println!("Storing webmention {:?}", mention);
-        storage
-            .store(mention)
-            .map_err(|source| WebmentionError::StorageError {
-                source: Box::new(source),
-            })?;
-        return Ok(true);
-    } else {
-        Ok(false)
-    }
+    crate::receiver::Receiver::new(client.clone())
+        .receive(storage, source, target, None)
+        .await
 }
@@ -1,114 +1,411 @@
 use crate::error::WebmentionError;
+use crate::wm_url::{absolute_url, Url};
 use nom::{
     branch::alt,
-    bytes::complete::is_not,
-    character::complete::char,
-    combinator::map_res,
-    multi::{many0, separated_list0},
-    sequence::{delimited, preceded, tuple},
+    bytes::complete::{is_not, take_while, take_while1},
+    character::complete::{char, none_of},
+    combinator::{map, opt, recognize},
+    multi::{fold_many0, many0, many1, separated_list0},
+    sequence::{delimited, pair, preceded, tuple},
     IResult,
 };
 use reqwest::header::{GetAll, HeaderValue};
+use std::collections::HashMap;
 
-pub fn all_rels(header: GetAll<HeaderValue>) -> std::collections::HashMap<String, Vec<String>> {
-    let mut merged_rels = std::collections::HashMap::new();
-    for header_value in header.into_iter() {
-        let link_header = match header_value.to_str() {
-            Ok(s) => match link_header(s) {
-                Ok((_remaining, link)) => link,
-                _ => continue,
-            },
-            Err(_e) => continue,
-        };
-
-        for link in link_header.values {
-            for rel in link.rels.iter() {
-                merged_rels
-                    .entry(rel.clone())
-                    .or_insert(Vec::new())
-                    .push(link.uri_reference.clone());
-            }
+/// Merges `rel`s of every link of every `Link` header, mapping them to the target URIs as
+/// written.
+///
+/// Note that `anchor` is disregarded, see [LinkHeader::rels_for] for links about a resource.
+pub fn all_rels(header: GetAll<HeaderValue>) -> HashMap<String, Vec<String>> {
+    let mut merged_rels = HashMap::new();
+    for link in LinkHeader::from_headers(header).links {
+        for rel in link.rels() {
+            merged_rels
+                .entry(rel)
+                .or_insert_with(Vec::new)
+                .push(link.target.clone());
         }
     }
 
     merged_rels
 }
 
-/// Stores data of a single link inside of the link header (dropping all params save for rel)
-#[derive(Debug)]
-struct LinkHeaderValue {
-    uri_reference: String,
-    rels: Vec<String>,
+/// Parameter of a link, such as `rel="webmention"` or `title*=UTF-8'en'%E2%82%AC`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkParam {
+    /// Lowercased name, ending with `*` for [RFC 8187](https://www.rfc-editor.org/rfc/rfc8187)
+    /// extended values
+    pub name: String,
+    /// Unquoted, and for extended values, decoded value
+    pub value: String,
+    /// Language of an extended value, if specified
+    pub language: Option<String>,
+}
+
+/// A single link of the `Link` header: `<target>; name=value; ...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    /// URI reference as written, relative to the URL of the response
+    pub target: String,
+    pub params: Vec<LinkParam>,
 }
 
-/// List of links inside of the link header (could contain several, according to MDN)
+impl Link {
+    pub fn new(target: impl Into<String>) -> Link {
+        Link {
+            target: target.into(),
+            params: Vec::new(),
+        }
+    }
+
+    /// Adds a parameter. Extended values (names ending with `*`) are encoded as UTF-8.
+    pub fn with_param(mut self, name: &str, value: impl Into<String>) -> Link {
+        self.params.push(LinkParam {
+            name: name.to_ascii_lowercase(),
+            value: value.into(),
+            language: None,
+        });
+        self
+    }
+
+    /// Adds `rel` parameter.
+    pub fn with_rel(self, rel: &str) -> Link {
+        self.with_param("rel", rel)
+    }
+
+    /// Value of the first parameter named `name`: RFC 8288 says later occurrences of `rel`,
+    /// `anchor` and the like must be ignored.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|param| param.name.eq_ignore_ascii_case(name))
+            .map(|param| param.value.as_str())
+    }
+
+    /// Lowercased relation types of the link.
+    pub fn rels(&self) -> Vec<String> {
+        self.param("rel")
+            .map(|rel| {
+                rel.split_whitespace()
+                    .map(|rel| rel.to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn anchor(&self) -> Option<&str> {
+        self.param("anchor")
+    }
+
+    /// `title*` if present, `title` otherwise.
+    pub fn title(&self) -> Option<&str> {
+        self.param("title*").or_else(|| self.param("title"))
+    }
+
+    /// Media type hint, `type` parameter.
+    pub fn media_type(&self) -> Option<&str> {
+        self.param("type")
+    }
+
+    /// All `hreflang` parameters, there could be several.
+    pub fn hreflangs(&self) -> Vec<&str> {
+        self.params
+            .iter()
+            .filter(|param| param.name == "hreflang")
+            .map(|param| param.value.as_str())
+            .collect()
+    }
+
+    /// Target resolved against `base`, the URL of the response the header came with.
+    pub fn target_url(&self, base: &Url) -> Option<Url> {
+        absolute_url(&self.target, base).ok()
+    }
+
+    /// The resource the link is about: `anchor` resolved against `base`, or `base` itself.
+    pub fn context_url(&self, base: &Url) -> Option<Url> {
+        match self.anchor() {
+            Some(anchor) => absolute_url(anchor, base).ok(),
+            None => Some(base.clone()),
+        }
+    }
+}
+
+impl std::fmt::Display for Link {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{}>", self.target)?;
+        for param in self.params.iter() {
+            if param.name.ends_with('*') {
+                write!(
+                    f,
+                    "; {}=UTF-8'{}'{}",
+                    param.name,
+                    param.language.as_deref().unwrap_or_default(),
+                    percent_encode_ext(&param.value)
+                )?;
+            } else if param.name == "hreflang" && is_token(&param.value) {
+                // hreflang has always been a token, unlike rel, anchor or title
+                write!(f, "; {}={}", param.name, param.value)?;
+            } else {
+                write!(f, "; {}=\"{}\"", param.name, escape_quoted(&param.value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parsed value of `Link` header, as specified by
+/// [RFC 8288](https://www.rfc-editor.org/rfc/rfc8288).
+///
 /// Example:
 ///
-/// > Link: <https://marinintim.com/webmention>; option="one"; option=two; rel="webmention", <another-uri>; another="opt"; rel="stylesheet"
+/// > Link: <https://marinintim.com/webmention>; option="one"; option=two; rel="webmention",
+/// > <another-uri>; another="opt"; rel="stylesheet"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkHeader {
+    pub links: Vec<Link>,
+}
+
+impl LinkHeader {
+    pub fn parse(input: &str) -> Result<LinkHeader, WebmentionError> {
+        match link_header(input) {
+            Ok((remaining, header)) if remaining.trim().is_empty() => Ok(header),
+            _ => Err(WebmentionError::InvalidLinkHeader(input.to_string())),
+        }
+    }
+
+    /// Merges links of every `Link` header, skipping the ones that could not be parsed: a
+    /// malformed link is dropped up to the next comma, and the links around it are kept.
+    pub fn from_headers(headers: GetAll<HeaderValue>) -> LinkHeader {
+        LinkHeader {
+            links: headers
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(lenient_links)
+                .collect(),
+        }
+    }
 
-#[derive(Debug)]
-struct LinkHeader {
-    values: Vec<LinkHeaderValue>,
+    /// Relation types of links about `context`, the URL of the response the header came with,
+    /// mapped to their targets resolved against it. Links with `anchor` pointing to some other
+    /// resource are skipped.
+    pub fn rels_for(&self, context: &Url) -> HashMap<String, Vec<Url>> {
+        let mut rels: HashMap<String, Vec<Url>> = HashMap::new();
+        for link in self.links.iter() {
+            if link.context_url(context).as_ref() != Some(context) {
+                continue;
+            }
+            if let Some(target) = link.target_url(context) {
+                for rel in link.rels() {
+                    rels.entry(rel).or_default().push(target.clone());
+                }
+            }
+        }
+        rels
+    }
 }
 
-fn uri_reference(input: &str) -> IResult<&str, &str> {
-    let mut parser = delimited(char('<'), is_not(">"), char('>'));
+impl std::str::FromStr for LinkHeader {
+    type Err = WebmentionError;
 
-    let (input, middle) = parser(input)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LinkHeader::parse(s)
+    }
+}
 
-    Ok((input, middle))
+impl std::fmt::Display for LinkHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, link) in self.links.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", link)?;
+        }
+        Ok(())
+    }
 }
 
-fn option(input: &str) -> IResult<&str, (&str, &str)> {
-    let mut parser = tuple((
-        is_not("="),
-        char('='),
-        alt((delimited(char('"'), is_not("\""), char('"')), is_not(" ;,"))),
-    ));
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
 
-    let (input, (key, _, value)) = parser(input)?;
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(is_tchar)
+}
 
-    Ok((input, (key, value)))
+fn escape_quoted(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
 }
 
-fn list_of_options(input: &str) -> IResult<&str, Vec<(&str, &str)>> {
-    let mut parser = many0(preceded(tuple((char(';'), char(' '))), option));
+/// Percent-encodes everything except `attr-char` of RFC 8187.
+fn percent_encode_ext(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
 
-    let (input, options) = parser(input)?;
+/// Decodes RFC 8187 `ext-value`: `charset'language'pct-encoded`.
+fn decode_ext_value(value: &str) -> Option<(String, Option<String>)> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let language = parts.next()?;
+    let encoded = parts.next()?;
 
-    Ok((input, options))
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+
+    let encoding = encoding_rs::Encoding::for_label(charset.as_bytes())?;
+    let (decoded, had_errors) = encoding.decode_without_bom_handling(&bytes);
+    if had_errors {
+        return None;
+    }
+    let language = Some(language.to_string()).filter(|language| !language.is_empty());
+    Some((decoded.to_string(), language))
 }
 
-fn parsed_to_link_header_value(parsed: (&str, Vec<(&str, &str)>)) -> Result<LinkHeaderValue, ()> {
-    let rels = parsed
-        .1
-        .iter()
-        .filter(|opt| opt.0 == "rel")
-        .flat_map(|opt| opt.1.split_whitespace())
-        .map(|s| s.to_string())
-        .collect();
-    Ok(LinkHeaderValue {
-        uri_reference: parsed.0.to_string(),
-        rels,
-    })
+/// Optional whitespace
+fn ows(input: &str) -> IResult<&str, &str> {
+    take_while(|c| c == ' ' || c == '\t')(input)
 }
 
-fn link(input: &str) -> IResult<&str, LinkHeaderValue> {
-    map_res(
-        tuple((uri_reference, list_of_options)),
-        parsed_to_link_header_value,
+fn token(input: &str) -> IResult<&str, &str> {
+    take_while1(is_tchar)(input)
+}
+
+fn quoted_string(input: &str) -> IResult<&str, String> {
+    delimited(
+        char('"'),
+        fold_many0(
+            alt((
+                preceded(char('\\'), nom::character::complete::anychar),
+                none_of("\"\\"),
+            )),
+            String::new,
+            |mut s, c| {
+                s.push(c);
+                s
+            },
+        ),
+        char('"'),
     )(input)
 }
 
-fn parsed_to_link_header(parsed: Vec<LinkHeaderValue>) -> Result<LinkHeader, WebmentionError> {
-    Ok(LinkHeader { values: parsed })
+fn uri_reference(input: &str) -> IResult<&str, &str> {
+    delimited(char('<'), recognize(opt(is_not(">"))), char('>'))(input)
 }
 
+/// `name [= value]`; values of extended parameters are left encoded
+fn option(input: &str) -> IResult<&str, (String, String)> {
+    let (input, name) = token(input)?;
+    let (input, value) = opt(preceded(
+        tuple((ows, char('='), ows)),
+        alt((quoted_string, map(token, |value| value.to_string()))),
+    ))(input)?;
+
+    Ok((
+        input,
+        (name.to_ascii_lowercase(), value.unwrap_or_default()),
+    ))
+}
+
+fn list_of_options(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    many0(preceded(tuple((ows, char(';'), ows)), option))(input)
+}
+
+fn to_param((name, value): (String, String)) -> LinkParam {
+    if name.ends_with('*') {
+        if let Some((value, language)) = decode_ext_value(&value) {
+            return LinkParam {
+                name,
+                value,
+                language,
+            };
+        }
+    }
+    LinkParam {
+        name,
+        value,
+        language: None,
+    }
+}
+
+fn link(input: &str) -> IResult<&str, Link> {
+    map(pair(uri_reference, list_of_options), |(target, options)| {
+        Link {
+            target: target.trim().to_string(),
+            params: options.into_iter().map(to_param).collect(),
+        }
+    })(input)
+}
+
+/// `#link-value`: a list of links separated by commas with optional whitespace, where empty
+/// elements are allowed
 fn link_header(input: &str) -> IResult<&str, LinkHeader> {
-    map_res(
-        separated_list0(tuple((char(','), char(' '))), link),
-        parsed_to_link_header,
-    )(input)
+    let (input, links) = delimited(
+        many0(pair(ows, char(','))),
+        separated_list0(many1(tuple((ows, char(','), ows))), preceded(ows, link)),
+        pair(many0(pair(ows, char(','))), ows),
+    )(input)?;
+    Ok((input, LinkHeader { links }))
+}
+
+/// Links of a header value, skipping the malformed ones, such as `<feed>; type=text/rss+xml`
+fn lenient_links(mut input: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    loop {
+        input = input.trim_start_matches([' ', '\t', ',']);
+        if input.is_empty() {
+            return links;
+        }
+        if let Ok((rest, link)) = link(input) {
+            let rest = rest.trim_start_matches([' ', '\t']);
+            if rest.is_empty() || rest.starts_with(',') {
+                links.push(link);
+                input = rest;
+                continue;
+            }
+        }
+        input = skip_link(input);
+    }
+}
+
+/// Rest of `input` after the next comma outside of quoted strings and URI references
+fn skip_link(input: &str) -> &str {
+    let (mut quoted, mut escaped, mut uri) = (false, false, false);
+    for (index, c) in input.char_indices() {
+        if quoted {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '<' => uri = true,
+            '>' => uri = false,
+            '"' if !uri => quoted = true,
+            ',' if !uri => return &input[index + 1..],
+            _ => {}
+        }
+    }
+    ""
 }
 
 #[cfg(test)]
@@ -118,6 +415,9 @@ mod test {
     use super::list_of_options;
     use super::option;
     use super::uri_reference;
+    use super::{Link, LinkHeader};
+    use crate::wm_url::Url;
+    use reqwest::header::{HeaderMap, HeaderValue, LINK};
 
     #[test]
     fn test_uri_reference() {
@@ -149,21 +449,34 @@ mod test {
         assert_eq!(key, "key");
         assert_eq!(value, "Quoted value with Spaces");
         assert_eq!(remaining, " not relevant");
+
+        let input = "Title = \"with \\\"escaped\\\" quotes, and a comma\"";
+        let (remaining, (key, value)) = option(input).unwrap();
+        assert_eq!(key, "title");
+        assert_eq!(value, "with \"escaped\" quotes, and a comma");
+        assert_eq!(remaining, "");
     }
 
     #[test]
     fn test_list_of_options() {
-        let input = "; option1=value1; option2=\"value2\"";
+        let input = "; option1=value1;option2=\"value2\" ;  flag";
         let (_, options) = list_of_options(input).unwrap();
-        assert_eq!(options, vec![("option1", "value1"), ("option2", "value2")]);
+        let options: Vec<(&str, &str)> = options
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            options,
+            vec![("option1", "value1"), ("option2", "value2"), ("flag", "")]
+        );
     }
 
     #[test]
     fn test_link() {
         let input = "<https://marinintim.com>; rel=webmention; awesome=true";
         let (_, value) = link(input).unwrap();
-        assert_eq!(value.uri_reference, "https://marinintim.com");
-        assert_eq!(value.rels[0], "webmention");
+        assert_eq!(value.target, "https://marinintim.com");
+        assert_eq!(value.rels()[0], "webmention");
     }
 
     #[test]
@@ -171,6 +484,125 @@ mod test {
         let input = "<https://marinintim.com/pingback>; rel=\"pingback\"; awesome=true, <https://marinintim.com>; rel=\"webmention\"";
         let (_, value) = link_header(input).unwrap();
         println!("{:?}", value);
-        assert_eq!(value.values[1].rels[0], "webmention");
+        assert_eq!(value.links[1].rels()[0], "webmention");
+    }
+
+    #[test]
+    fn test_whitespace_and_commas() {
+        let header = LinkHeader::parse(
+            " <a> ;rel=\"next, prev\" ,, <b>;REL=Webmention;rel=ignored\t,<c>;title=\"a, b\", ",
+        )
+        .unwrap();
+        let rels: Vec<Vec<String>> = header.links.iter().map(|link| link.rels()).collect();
+        assert_eq!(
+            rels,
+            vec![
+                vec!["next,".to_string(), "prev".to_string()],
+                vec!["webmention".to_string()],
+                vec![],
+            ]
+        );
+        assert_eq!(header.links[2].title(), Some("a, b"));
+
+        assert!(LinkHeader::parse("<a>; rel=webmention garbage").is_err());
+        assert!(LinkHeader::parse("a; rel=webmention").is_err());
+        assert_eq!(LinkHeader::parse("").unwrap(), LinkHeader::default());
+    }
+
+    #[test]
+    fn test_malformed_links() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            LINK,
+            HeaderValue::from_static(
+                "<https://a.example/feed>; rel=alternate; type=application/rss+xml, \
+                 <https://a.example/wp-json/>; rel=https://api.w.org/, \
+                 <https://a.example/quoted>; title=\"a, b\" junk, \
+                 <https://a.example/webmention>; rel=\"webmention\", \
+                 <https://a.example/trailing>; rel=next garbage",
+            ),
+        );
+        headers.append(
+            LINK,
+            HeaderValue::from_static("<https://a.example/>; rel=me"),
+        );
+        assert!(LinkHeader::parse(headers[LINK].to_str().unwrap()).is_err());
+
+        let header = LinkHeader::from_headers(headers.get_all(LINK));
+        let targets: Vec<&str> = header
+            .links
+            .iter()
+            .map(|link| link.target.as_str())
+            .collect();
+        assert_eq!(
+            targets,
+            vec!["https://a.example/webmention", "https://a.example/"]
+        );
+        assert_eq!(header.links[0].rels(), vec!["webmention"]);
+    }
+
+    #[test]
+    fn test_parameters() {
+        let header = LinkHeader::parse(
+            "</post>; rel=alternate; anchor=\"#comments\"; type=\"text/html\"; hreflang=en; \
+             hreflang=de; title=\"Fallback\"; title*=UTF-8'de'n%c3%a4chstes%20Kapitel",
+        )
+        .unwrap();
+        let link = &header.links[0];
+        assert_eq!(link.anchor(), Some("#comments"));
+        assert_eq!(link.media_type(), Some("text/html"));
+        assert_eq!(link.hreflangs(), vec!["en", "de"]);
+        assert_eq!(link.title(), Some("nächstes Kapitel"));
+        assert_eq!(link.params.last().unwrap().language.as_deref(), Some("de"));
+
+        let latin1 = LinkHeader::parse("</>; title*=iso-8859-1'en'%A3%20rates").unwrap();
+        assert_eq!(latin1.links[0].title(), Some("£ rates"));
+    }
+
+    #[test]
+    fn test_rels_for() {
+        let base = Url::parse("https://example.com/post/").unwrap();
+        let header = LinkHeader::parse(
+            "<webmention>; rel=webmention, \
+             </other-endpoint>; rel=webmention; anchor=\"https://example.com/other\", \
+             </self>; rel=\"webmention self\"; anchor=\"\"",
+        )
+        .unwrap();
+
+        let rels = header.rels_for(&base);
+        assert_eq!(
+            rels["webmention"],
+            vec![
+                Url::parse("https://example.com/post/webmention").unwrap(),
+                Url::parse("https://example.com/self").unwrap(),
+            ]
+        );
+        assert_eq!(rels["self"].len(), 1);
+
+        let other = Url::parse("https://example.com/other").unwrap();
+        // had the header come with the other page, links without anchor would be about it too
+        assert_eq!(header.rels_for(&other)["webmention"].len(), 3);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let header = LinkHeader {
+            links: vec![
+                Link::new("https://marinintim.com/webmention").with_rel("webmention"),
+                Link::new("/chapter-2")
+                    .with_rel("next")
+                    .with_param("hreflang", "de")
+                    .with_param("title", "Say \"hi\"")
+                    .with_param("title*", "nächstes Kapitel"),
+            ],
+        };
+        let serialized = header.to_string();
+        assert_eq!(
+            serialized,
+            "<https://marinintim.com/webmention>; rel=\"webmention\", \
+             </chapter-2>; rel=\"next\"; hreflang=de; title=\"Say \\\"hi\\\"\"; \
+             title*=UTF-8''n%C3%A4chstes%20Kapitel"
+        );
+        assert_eq!(LinkHeader::parse(&serialized).unwrap(), header);
     }
 }
@@ -0,0 +1,551 @@
+use crate::error::WebmentionError;
+use crate::http_client::HttpClient;
+use crate::wm_url::Url;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+/// Fault codes defined by the [Pingback 1.0](http://www.hixie.ch/specs/pingback/pingback) spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultCode {
+    /// 0: a generic fault code, servers may use it instead of the more specific ones
+    Generic,
+    /// 0x0010: the source URI does not exist
+    SourceNotFound,
+    /// 0x0011: the source URI does not contain a link to the target URI
+    SourceHasNoLink,
+    /// 0x0020: the target URI does not exist
+    TargetNotFound,
+    /// 0x0021: the target URI cannot be used as a target
+    TargetNotPingable,
+    /// 0x0030: the pingback has already been registered
+    AlreadyRegistered,
+    /// 0x0031: access denied
+    AccessDenied,
+    /// 0x0032: the server could not communicate with an upstream server
+    UpstreamError,
+    /// Any code not described by the spec
+    Other(i32),
+}
+
+impl FaultCode {
+    pub fn from_code(code: i32) -> FaultCode {
+        match code {
+            0 => FaultCode::Generic,
+            0x0010 => FaultCode::SourceNotFound,
+            0x0011 => FaultCode::SourceHasNoLink,
+            0x0020 => FaultCode::TargetNotFound,
+            0x0021 => FaultCode::TargetNotPingable,
+            0x0030 => FaultCode::AlreadyRegistered,
+            0x0031 => FaultCode::AccessDenied,
+            0x0032 => FaultCode::UpstreamError,
+            other => FaultCode::Other(other),
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            FaultCode::Generic => 0,
+            FaultCode::SourceNotFound => 0x0010,
+            FaultCode::SourceHasNoLink => 0x0011,
+            FaultCode::TargetNotFound => 0x0020,
+            FaultCode::TargetNotPingable => 0x0021,
+            FaultCode::AlreadyRegistered => 0x0030,
+            FaultCode::AccessDenied => 0x0031,
+            FaultCode::UpstreamError => 0x0032,
+            FaultCode::Other(code) => *code,
+        }
+    }
+}
+
+/// XML-RPC fault returned by the pingback server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub code: FaultCode,
+    pub message: String,
+}
+
+impl Fault {
+    pub fn new(code: FaultCode, message: &str) -> Fault {
+        Fault {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// The logical result of a `pingback.ping` call
+#[derive(Debug, Clone, PartialEq)]
+pub enum PingbackResponse {
+    /// Server registered the pingback, the string is purely informational
+    Accepted(String),
+    /// Server refused the pingback
+    Fault(Fault),
+}
+
+/// Builds the body of the `pingback.ping` XML-RPC call.
+pub fn ping_request(source: &Url, target: &Url) -> String {
+    format!(
+        concat!(
+            "<?xml version=\"1.0\"?>",
+            "<methodCall><methodName>pingback.ping</methodName><params>",
+            "<param><value><string>{}</string></value></param>",
+            "<param><value><string>{}</string></value></param>",
+            "</params></methodCall>"
+        ),
+        quick_xml::escape::escape(source.as_str()),
+        quick_xml::escape::escape(target.as_str())
+    )
+}
+
+/// Parses the `methodResponse` returned by the pingback server.
+pub fn parse_response(xml: &str) -> Result<PingbackResponse, WebmentionError> {
+    let root = parse_tree(xml)?;
+    if root.name != "methodResponse" {
+        return Err(malformed("expected methodResponse"));
+    }
+
+    if let Some(fault) = root.child("fault") {
+        let value = fault
+            .child("value")
+            .map(value_of)
+            .ok_or_else(|| malformed("fault without value"))?;
+        let code = match value.member("faultCode") {
+            Some(Value::Int(code)) => *code,
+            _ => return Err(malformed("fault without faultCode")),
+        };
+        let message = match value.member("faultString") {
+            Some(Value::String(message)) => message.clone(),
+            _ => String::new(),
+        };
+        return Ok(PingbackResponse::Fault(Fault {
+            code: FaultCode::from_code(code),
+            message,
+        }));
+    }
+
+    let value = root
+        .child("params")
+        .and_then(|params| params.child("param"))
+        .and_then(|param| param.child("value"))
+        .map(value_of)
+        .ok_or_else(|| malformed("response without params"))?;
+
+    match value {
+        Value::String(message) => Ok(PingbackResponse::Accepted(message)),
+        _ => Ok(PingbackResponse::Accepted(String::new())),
+    }
+}
+
+/// Sends `pingback.ping` to the pingback server at `endpoint`.
+pub async fn send_pingback(
+    client: &HttpClient,
+    endpoint: &Url,
+    source: &Url,
+    target: &Url,
+) -> Result<PingbackResponse, WebmentionError> {
+    let response = client
+        .post_xml(endpoint, ping_request(source, target))
+        .await?;
+    parse_response(&response)
+}
+
+/// Parses a `pingback.ping` call and returns its `source` and `target` arguments, or the fault
+/// that should be sent back to the caller.
+pub fn parse_ping_request(xml: &str) -> Result<(String, String), Fault> {
+    let root = parse_tree(xml).map_err(|e| Fault::new(FaultCode::Generic, &e.to_string()))?;
+    let method = root.child("methodName").map(|name| name.text.as_str());
+    if root.name != "methodCall" || method != Some("pingback.ping") {
+        return Err(Fault::new(
+            FaultCode::Generic,
+            "only pingback.ping is supported",
+        ));
+    }
+
+    let mut params = root
+        .child("params")
+        .map(|params| params.children.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .filter(|param| param.name == "param")
+        .filter_map(|param| param.child("value"))
+        .map(value_of);
+
+    match (params.next(), params.next()) {
+        (Some(Value::String(source)), Some(Value::String(target))) => Ok((source, target)),
+        _ => Err(Fault::new(
+            FaultCode::Generic,
+            "pingback.ping expects source and target URIs",
+        )),
+    }
+}
+
+/// Serializes successful `methodResponse`.
+pub fn success_response(message: &str) -> String {
+    format!(
+        concat!(
+            "<?xml version=\"1.0\"?>",
+            "<methodResponse><params><param><value><string>{}</string></value></param></params>",
+            "</methodResponse>"
+        ),
+        quick_xml::escape::escape(message)
+    )
+}
+
+/// Serializes `methodResponse` with a fault.
+pub fn fault_response(fault: &Fault) -> String {
+    format!(
+        concat!(
+            "<?xml version=\"1.0\"?>",
+            "<methodResponse><fault><value><struct>",
+            "<member><name>faultCode</name><value><int>{}</int></value></member>",
+            "<member><name>faultString</name><value><string>{}</string></value></member>",
+            "</struct></value></fault></methodResponse>"
+        ),
+        fault.code.code(),
+        quick_xml::escape::escape(&fault.message)
+    )
+}
+
+/// Processes `pingback.ping` call to a target on `domain` as if it was a webmention, and returns
+/// the body of XML-RPC response.
+///
+/// The pingback goes through the same verification and storage as
+/// [receive_webmention](crate::receive_webmention).
+#[cfg(feature = "receiver")]
+pub async fn receive_pingback(
+    client: &HttpClient,
+    storage: &impl crate::storage::WebmentionStorage,
+    domain: &Url,
+    body: &str,
+) -> String {
+    let receiver = crate::receiver::Receiver::new(client.clone());
+    receive_pingback_with(&receiver, storage, domain, body, None).await
+}
+
+/// Same as [receive_pingback], but goes through `receiver` and its limits, as a pingback from
+/// `remote_addr`. Pingbacks over the limits are answered with a generic fault.
+#[cfg(feature = "receiver")]
+pub async fn receive_pingback_with(
+    receiver: &crate::receiver::Receiver,
+    storage: &impl crate::storage::WebmentionStorage,
+    domain: &Url,
+    body: &str,
+    remote_addr: Option<std::net::IpAddr>,
+) -> String {
+    match accept_pingback(receiver, storage, domain, body, remote_addr).await {
+        Ok(()) => success_response("Pingback registered"),
+        Err(fault) => fault_response(&fault),
+    }
+}
+
+#[cfg(feature = "receiver")]
+async fn accept_pingback(
+    receiver: &crate::receiver::Receiver,
+    storage: &impl crate::storage::WebmentionStorage,
+    domain: &Url,
+    body: &str,
+    remote_addr: Option<std::net::IpAddr>,
+) -> Result<(), Fault> {
+    let (source, target) = parse_ping_request(body)?;
+    let source = Url::parse(&source)
+        .map_err(|_| Fault::new(FaultCode::SourceNotFound, "source is not a valid URI"))?;
+    let target = Url::parse(&target)
+        .map_err(|_| Fault::new(FaultCode::TargetNotFound, "target is not a valid URI"))?;
+
+    let equivalence = &receiver.equivalence;
+    if !equivalence.same_host(&target, domain) {
+        return Err(Fault::new(
+            FaultCode::TargetNotPingable,
+            "target is not on this domain",
+        ));
+    }
+
+    let registered = storage
+        .lookup_by_target(target.clone())
+        .map_err(|e| Fault::new(FaultCode::Generic, &e.to_string()))?;
+    if registered
+        .iter()
+        .any(|mention| equivalence.equivalent(&mention.source, &source))
+    {
+        return Err(Fault::new(
+            FaultCode::AlreadyRegistered,
+            "pingback has already been registered",
+        ));
+    }
+
+    match receiver
+        .receive(storage, &source, &target, remote_addr)
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Fault::new(
+            FaultCode::SourceHasNoLink,
+            "source does not link to target",
+        )),
+        Err(WebmentionError::StorageError { source }) => {
+            Err(Fault::new(FaultCode::Generic, &source.to_string()))
+        }
+        Err(e @ WebmentionError::TooManyRequests { .. }) => {
+            Err(Fault::new(FaultCode::Generic, &e.to_string()))
+        }
+        Err(e @ WebmentionError::BlockedSource(_)) => {
+            Err(Fault::new(FaultCode::AccessDenied, &e.to_string()))
+        }
+        Err(e) => Err(Fault::new(FaultCode::SourceNotFound, &e.to_string())),
+    }
+}
+
+fn malformed(reason: &str) -> WebmentionError {
+    WebmentionError::MalformedXmlRpc(reason.to_string())
+}
+
+/// Subset of XML-RPC values that pingback messages are made of
+#[derive(Debug, PartialEq)]
+enum Value {
+    String(String),
+    Int(i32),
+    Struct(Vec<(String, Value)>),
+    Other,
+}
+
+impl Value {
+    fn member(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(members) => members.iter().find(|m| m.0 == name).map(|m| &m.1),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+}
+
+fn value_of(value: &Element) -> Value {
+    let typed = match value.children.first() {
+        // untyped value is a string
+        None => return Value::String(value.text.clone()),
+        Some(typed) => typed,
+    };
+
+    match typed.name.as_str() {
+        "string" => Value::String(typed.text.clone()),
+        "int" | "i4" => typed.text.parse().map(Value::Int).unwrap_or(Value::Other),
+        "struct" => Value::Struct(
+            typed
+                .children
+                .iter()
+                .filter(|m| m.name == "member")
+                .filter_map(|m| {
+                    let name = m.child("name")?.text.clone();
+                    let value = value_of(m.child("value")?);
+                    Some((name, value))
+                })
+                .collect(),
+        ),
+        _ => Value::Other,
+    }
+}
+
+fn parse_tree(xml: &str) -> Result<Element, WebmentionError> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut stack: Vec<Element> = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(start)) => stack.push(Element {
+                name: String::from_utf8_lossy(start.name().as_ref()).to_string(),
+                ..Element::default()
+            }),
+            Ok(Event::Empty(empty)) => {
+                let element = Element {
+                    name: String::from_utf8_lossy(empty.name().as_ref()).to_string(),
+                    ..Element::default()
+                };
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            Ok(Event::Text(text)) => {
+                let text = text
+                    .unescape()
+                    .map_err(|e| WebmentionError::MalformedXmlRpc(e.to_string()))?;
+                if let Some(current) = stack.last_mut() {
+                    current.text.push_str(&text);
+                }
+            }
+            Ok(Event::CData(data)) => {
+                if let Some(current) = stack.last_mut() {
+                    current
+                        .text
+                        .push_str(&String::from_utf8_lossy(&data.into_inner()));
+                }
+            }
+            Ok(Event::End(_)) => {
+                let element = stack.pop().ok_or_else(|| malformed("unbalanced tags"))?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            Ok(Event::Eof) => return Err(malformed("unexpected end of document")),
+            Ok(_) => continue,
+            Err(e) => return Err(WebmentionError::MalformedXmlRpc(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        fault_response, parse_ping_request, parse_response, ping_request, success_response, Fault,
+        FaultCode, PingbackResponse,
+    };
+    use crate::wm_url::Url;
+
+    #[test]
+    fn test_ping_request() {
+        let source = Url::parse("https://marinintim.com/notes/?a=1&b=2").unwrap();
+        let target = Url::parse("https://example.com/post").unwrap();
+        let request = ping_request(&source, &target);
+        assert!(request.contains("<methodName>pingback.ping</methodName>"));
+        assert!(request.contains("<string>https://marinintim.com/notes/?a=1&amp;b=2</string>"));
+        assert!(request.contains("<string>https://example.com/post</string>"));
+    }
+
+    #[test]
+    fn test_parse_accepted() {
+        let xml = "<?xml version=\"1.0\"?>
+            <methodResponse>
+              <params><param><value><string>Pingback registered</string></value></param></params>
+            </methodResponse>";
+        assert_eq!(
+            parse_response(xml).unwrap(),
+            PingbackResponse::Accepted("Pingback registered".to_string())
+        );
+
+        let untyped =
+            "<methodResponse><params><param><value>OK</value></param></params></methodResponse>";
+        assert_eq!(
+            parse_response(untyped).unwrap(),
+            PingbackResponse::Accepted("OK".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_fault() {
+        let xml = "<?xml version=\"1.0\"?>
+            <methodResponse><fault><value><struct>
+              <member><name>faultCode</name><value><int>48</int></value></member>
+              <member><name>faultString</name><value><string>Already registered</string></value></member>
+            </struct></value></fault></methodResponse>";
+        assert_eq!(
+            parse_response(xml).unwrap(),
+            PingbackResponse::Fault(Fault {
+                code: FaultCode::AlreadyRegistered,
+                message: "Already registered".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        assert!(parse_response("<html><body>Not found</body></html>").is_err());
+        assert!(parse_response("<methodResponse><params>").is_err());
+    }
+
+    #[test]
+    fn test_parse_ping_request() {
+        let source = Url::parse("https://marinintim.com/notes/").unwrap();
+        let target = Url::parse("https://example.com/post?a=1&b=2").unwrap();
+        let (parsed_source, parsed_target) =
+            parse_ping_request(&ping_request(&source, &target)).unwrap();
+        assert_eq!(parsed_source, source.as_str());
+        assert_eq!(parsed_target, target.as_str());
+
+        let wrong_method = "<methodCall><methodName>weblogUpdates.ping</methodName></methodCall>";
+        assert_eq!(
+            parse_ping_request(wrong_method).unwrap_err().code,
+            FaultCode::Generic
+        );
+
+        let one_param = "<methodCall><methodName>pingback.ping</methodName><params>
+            <param><value><string>https://marinintim.com/</string></value></param>
+            </params></methodCall>";
+        assert!(parse_ping_request(one_param).is_err());
+    }
+
+    #[test]
+    fn test_responses_roundtrip() {
+        assert_eq!(
+            parse_response(&success_response("Thanks & bye")).unwrap(),
+            PingbackResponse::Accepted("Thanks & bye".to_string())
+        );
+
+        let fault = Fault::new(FaultCode::SourceHasNoLink, "no <a> to target");
+        assert_eq!(
+            parse_response(&fault_response(&fault)).unwrap(),
+            PingbackResponse::Fault(fault)
+        );
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn test_receive_pingback_without_fetching() {
+        use super::receive_pingback;
+        use crate::http_client::HttpClient;
+        use crate::storage::{InMemoryWebmentionStorage, WebmentionStorage};
+        use crate::webmention::Webmention;
+        use tokio_test::block_on;
+
+        let storage = InMemoryWebmentionStorage::new();
+        let domain = Url::parse("https://example.com/").unwrap();
+        let source = Url::parse("https://marinintim.com/notes/").unwrap();
+
+        let foreign = Url::parse("https://elsewhere.com/post").unwrap();
+        let response = block_on(receive_pingback(
+            &HttpClient::default(),
+            &storage,
+            &domain,
+            &ping_request(&source, &foreign),
+        ));
+        assert_eq!(
+            parse_response(&response).unwrap(),
+            PingbackResponse::Fault(Fault::new(
+                FaultCode::TargetNotPingable,
+                "target is not on this domain"
+            ))
+        );
+
+        let target = Url::parse("https://example.com/post").unwrap();
+        storage.store(Webmention::from((&source, &target))).unwrap();
+        let response = block_on(receive_pingback(
+            &HttpClient::default(),
+            &storage,
+            &domain,
+            &ping_request(&source, &target),
+        ));
+        match parse_response(&response).unwrap() {
+            PingbackResponse::Fault(fault) => assert_eq!(fault.code, FaultCode::AlreadyRegistered),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_fault_codes() {
+        assert_eq!(FaultCode::from_code(0x0011), FaultCode::SourceHasNoLink);
+        assert_eq!(FaultCode::from_code(99), FaultCode::Other(99));
+        assert_eq!(FaultCode::TargetNotPingable.code(), 0x0021);
+    }
+}
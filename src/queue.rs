@@ -0,0 +1,530 @@
+use crate::endpoint_discovery::DiscoveryOptions;
+use crate::error::WebmentionError;
+use crate::http_client::HttpClient;
+use crate::retry::RetryPolicy;
+use crate::webmention::Webmention;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Where a queued webmention is in its delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryState {
+    /// Waiting for its next attempt
+    Pending,
+    /// Accepted by the target
+    Delivered,
+    /// Target answered, but did not accept it: no endpoint, source does not link to target, etc.
+    Rejected,
+    /// Failed with a permanent error, or with transient errors too many times
+    Failed,
+}
+
+impl std::fmt::Display for DeliveryState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DeliveryState::Pending => "pending",
+            DeliveryState::Delivered => "delivered",
+            DeliveryState::Rejected => "rejected",
+            DeliveryState::Failed => "failed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Webmention in the outbound queue, with its delivery history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuedWebmention {
+    /// Assigned by the queue
+    pub id: u64,
+    pub mention: Webmention,
+    pub state: DeliveryState,
+    /// Attempts made so far
+    pub attempts: u32,
+    pub enqueued_at: SystemTime,
+    /// When a pending webmention is due
+    pub next_attempt_at: SystemTime,
+    pub last_attempt_at: Option<SystemTime>,
+    /// How the target answered the last attempt, e.g. "accepted"
+    pub outcome: Option<String>,
+    /// Error of the last attempt, if it failed
+    pub last_error: Option<String>,
+}
+
+impl QueuedWebmention {
+    fn new(id: u64, mention: Webmention, now: SystemTime) -> QueuedWebmention {
+        QueuedWebmention {
+            id,
+            mention,
+            state: DeliveryState::Pending,
+            attempts: 0,
+            enqueued_at: now,
+            next_attempt_at: now,
+            last_attempt_at: None,
+            outcome: None,
+            last_error: None,
+        }
+    }
+
+    /// Whether the worker should attempt the delivery at `now`.
+    pub fn is_due(&self, now: SystemTime) -> bool {
+        self.state == DeliveryState::Pending && self.next_attempt_at <= now
+    }
+
+    /// Makes the webmention pending again, due at `now`, with a fresh count of attempts. The
+    /// source is checked again, as it may have been changed since it was found invalid.
+    pub fn reschedule(&mut self, now: SystemTime) {
+        self.mention.reset_checked();
+        self.state = DeliveryState::Pending;
+        self.attempts = 0;
+        self.next_attempt_at = now;
+    }
+}
+
+/// Storage of the outbound queue, see [InMemorySendQueue] and [FileSendQueue].
+pub trait SendQueue: Debug + Send + Sync {
+    /// Adds `mention` as a pending webmention, due immediately.
+    fn push(&self, mention: Webmention) -> Result<QueuedWebmention, WebmentionError>;
+    fn get(&self, id: u64) -> Result<Option<QueuedWebmention>, WebmentionError>;
+    /// All webmentions, ordered by id.
+    fn list(&self) -> Result<Vec<QueuedWebmention>, WebmentionError>;
+    /// Replaces the stored webmention with the same id. Webmentions removed in the meantime are
+    /// not restored.
+    fn update(&self, entry: &QueuedWebmention) -> Result<(), WebmentionError>;
+    /// Returns whether there was a webmention with `id`.
+    fn remove(&self, id: u64) -> Result<bool, WebmentionError>;
+}
+
+/// Queue that lives as long as the process, for tests and embedding.
+#[derive(Debug, Default)]
+pub struct InMemorySendQueue {
+    entries: Mutex<BTreeMap<u64, QueuedWebmention>>,
+}
+
+impl InMemorySendQueue {
+    pub fn new() -> InMemorySendQueue {
+        InMemorySendQueue::default()
+    }
+}
+
+impl SendQueue for InMemorySendQueue {
+    fn push(&self, mention: Webmention) -> Result<QueuedWebmention, WebmentionError> {
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.keys().next_back().map_or(1, |id| id + 1);
+        let entry = QueuedWebmention::new(id, mention, SystemTime::now());
+        entries.insert(id, entry.clone());
+        Ok(entry)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<QueuedWebmention>, WebmentionError> {
+        Ok(self.entries.lock().unwrap().get(&id).cloned())
+    }
+
+    fn list(&self) -> Result<Vec<QueuedWebmention>, WebmentionError> {
+        Ok(self.entries.lock().unwrap().values().cloned().collect())
+    }
+
+    fn update(&self, entry: &QueuedWebmention) -> Result<(), WebmentionError> {
+        if let Some(stored) = self.entries.lock().unwrap().get_mut(&entry.id) {
+            *stored = entry.clone();
+        }
+        Ok(())
+    }
+
+    fn remove(&self, id: u64) -> Result<bool, WebmentionError> {
+        Ok(self.entries.lock().unwrap().remove(&id).is_some())
+    }
+}
+
+/// Queue in a directory, one JSON file per webmention, named by its id.
+///
+/// Every operation goes to the disk, so that one process (e.g. a deploy step) can enqueue
+/// webmentions while another one is delivering them. Ids are claimed by creating their files,
+/// which is atomic, and files are replaced by renaming, so a crash does not leave them truncated.
+/// Only one worker should deliver from a directory at a time.
+#[derive(Debug)]
+pub struct FileSendQueue {
+    dir: PathBuf,
+}
+
+impl FileSendQueue {
+    /// Opens the queue in `dir`, creating the directory if needed.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<FileSendQueue, WebmentionError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|source| WebmentionError::Io {
+            path: dir.clone(),
+            source,
+        })?;
+        Ok(FileSendQueue { dir })
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn ids(&self) -> Result<Vec<u64>, WebmentionError> {
+        let io_error = |source| WebmentionError::Io {
+            path: self.dir.clone(),
+            source,
+        };
+        let mut ids = Vec::new();
+        for file in std::fs::read_dir(&self.dir).map_err(io_error)? {
+            let path = file.map_err(io_error)?.path();
+            if path.extension() == Some("json".as_ref()) {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse().ok())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn read(path: &Path) -> Result<Option<QueuedWebmention>, WebmentionError> {
+        match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents).map(Some).map_err(|err| {
+                WebmentionError::InvalidFile {
+                    path: path.to_path_buf(),
+                    source: err.into(),
+                }
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(WebmentionError::Io {
+                path: path.to_path_buf(),
+                source,
+            }),
+        }
+    }
+
+    fn write(&self, entry: &QueuedWebmention) -> Result<(), WebmentionError> {
+        let path = self.path(entry.id);
+        let contents =
+            serde_json::to_vec_pretty(entry).map_err(|err| WebmentionError::InvalidFile {
+                path: path.clone(),
+                source: err.into(),
+            })?;
+        let temporary = self.dir.join(format!("{}.json.tmp", entry.id));
+
+        std::fs::write(&temporary, contents)
+            .and_then(|_| std::fs::rename(&temporary, &path))
+            .map_err(|source| WebmentionError::Io { path, source })
+    }
+}
+
+impl SendQueue for FileSendQueue {
+    fn push(&self, mention: Webmention) -> Result<QueuedWebmention, WebmentionError> {
+        let mut id = self.ids()?.last().map_or(1, |id| id + 1);
+        loop {
+            let path = self.path(id);
+            // another process may claim the same id between listing and creating
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => break,
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => id += 1,
+                Err(source) => return Err(WebmentionError::Io { path, source }),
+            }
+        }
+
+        let entry = QueuedWebmention::new(id, mention, SystemTime::now());
+        self.write(&entry)?;
+        Ok(entry)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<QueuedWebmention>, WebmentionError> {
+        FileSendQueue::read(&self.path(id))
+    }
+
+    fn list(&self) -> Result<Vec<QueuedWebmention>, WebmentionError> {
+        let mut entries = Vec::new();
+        for id in self.ids()? {
+            // files of just claimed ids are empty until written
+            match self.get(id) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => {}
+                Err(WebmentionError::InvalidFile { .. })
+                    if std::fs::metadata(self.path(id)).is_ok_and(|meta| meta.len() == 0) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn update(&self, entry: &QueuedWebmention) -> Result<(), WebmentionError> {
+        if self.path(entry.id).exists() {
+            self.write(entry)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, id: u64) -> Result<bool, WebmentionError> {
+        let path = self.path(id);
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(source) => Err(WebmentionError::Io { path, source }),
+        }
+    }
+}
+
+/// Delivers webmentions from a [SendQueue].
+///
+/// Each webmention is sent with `client`, which may retry requests by itself according to its
+/// own `retry_policy`. When the delivery still fails with a
+/// [retryable](WebmentionError::is_retryable) error, the worker schedules it again according to
+/// `retry_policy`, honouring `Retry-After`, so that slow or overloaded targets are tried again
+/// over minutes and hours, not seconds.
+#[derive(Debug, Clone)]
+pub struct Worker {
+    pub client: HttpClient,
+    /// How endpoints of targets are discovered
+    pub discovery_options: DiscoveryOptions,
+    /// Attempts per webmention and delays between them
+    pub retry_policy: RetryPolicy,
+    /// How often [run](Worker::run) looks for due webmentions
+    pub poll_interval: Duration,
+}
+
+impl Default for Worker {
+    fn default() -> Self {
+        Worker {
+            client: HttpClient::default(),
+            discovery_options: DiscoveryOptions::default(),
+            retry_policy: RetryPolicy {
+                max_attempts: 8,
+                initial_backoff: Duration::from_secs(60),
+                max_backoff: Duration::from_secs(6 * 60 * 60),
+                jitter: true,
+            },
+            poll_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Worker {
+    /// Makes one attempt to deliver every webmention that is due at `now`, and returns them in
+    /// their new state.
+    pub async fn process_due(
+        &self,
+        queue: &dyn SendQueue,
+        now: SystemTime,
+    ) -> Result<Vec<QueuedWebmention>, WebmentionError> {
+        let mut processed = Vec::new();
+        for mut entry in queue.list()? {
+            if !entry.is_due(now) {
+                continue;
+            }
+            self.deliver(&mut entry, now).await;
+            queue.update(&entry)?;
+            processed.push(entry);
+        }
+        Ok(processed)
+    }
+
+    /// Processes due webmentions every `poll_interval`, until the queue fails.
+    pub async fn run(&self, queue: &dyn SendQueue) -> Result<(), WebmentionError> {
+        loop {
+            self.process_due(queue, SystemTime::now()).await?;
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn deliver(&self, entry: &mut QueuedWebmention, now: SystemTime) {
+        entry.attempts += 1;
+        entry.last_attempt_at = Some(now);
+
+        match entry
+            .mention
+            .send_with_options(&self.client, &self.discovery_options)
+            .await
+        {
+            Ok(acceptance) => {
+                entry.state = if acceptance.is_accepted() {
+                    DeliveryState::Delivered
+                } else {
+                    DeliveryState::Rejected
+                };
+                entry.outcome = Some(acceptance.to_string());
+                entry.last_error = None;
+            }
+            Err(err) => {
+                if err.is_retryable() && entry.attempts < self.retry_policy.max_attempts {
+                    let delay = self
+                        .retry_policy
+                        .backoff(entry.attempts)
+                        .max(err.retry_after().unwrap_or_default());
+                    entry.next_attempt_at = now + delay;
+                } else {
+                    entry.state = DeliveryState::Failed;
+                }
+                entry.outcome = None;
+                entry.last_error = Some(format!("{:#}", anyhow::Error::new(err)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DeliveryState, FileSendQueue, InMemorySendQueue, SendQueue, Worker};
+    use crate::http_client::test::{local_client, serve_sequence, TempDir};
+    use crate::http_client::HttpClient;
+    use crate::retry::RetryPolicy;
+    use crate::webmention::Webmention;
+    use std::time::{Duration, SystemTime};
+    use tokio_test::block_on;
+
+    fn mention(target: &str) -> Webmention {
+        let mut mention = Webmention::new("https://example.com/post", target).unwrap();
+        mention.set_checked(true);
+        mention
+    }
+
+    fn test_queue(queue: &dyn SendQueue) {
+        assert!(queue.list().unwrap().is_empty());
+        let first = queue.push(mention("https://example.org/1")).unwrap();
+        let second = queue.push(mention("https://example.org/2")).unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(first.state, DeliveryState::Pending);
+        assert!(first.is_due(SystemTime::now()));
+
+        let mut failed = second.clone();
+        failed.state = DeliveryState::Failed;
+        failed.attempts = 8;
+        queue.update(&failed).unwrap();
+        assert_eq!(queue.get(2).unwrap(), Some(failed));
+        assert_eq!(queue.list().unwrap().len(), 2);
+
+        assert!(queue.remove(1).unwrap());
+        assert!(!queue.remove(1).unwrap());
+        queue.update(&first).unwrap();
+        assert_eq!(queue.get(1).unwrap(), None);
+        assert_eq!(queue.push(mention("https://example.org/3")).unwrap().id, 3);
+    }
+
+    #[test]
+    fn test_in_memory_queue() {
+        test_queue(&InMemorySendQueue::new());
+    }
+
+    #[test]
+    fn test_file_queue() {
+        let dir = TempDir::new("send-queue");
+        test_queue(&FileSendQueue::open(dir.path()).unwrap());
+
+        let reopened = FileSendQueue::open(dir.path()).unwrap();
+        let ids: Vec<u64> = reopened.list().unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        std::fs::write(dir.path().join("2.json"), "not json").unwrap();
+        assert!(reopened.list().is_err());
+    }
+
+    #[test]
+    fn test_worker() {
+        let target = serve_sequence(vec![
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 120\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nLink: </endpoint>; rel=webmention\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        let worker = Worker {
+            client: HttpClient {
+                retry_policy: RetryPolicy::none(),
+                ..local_client(1024)
+            },
+            retry_policy: RetryPolicy {
+                max_attempts: 2,
+                initial_backoff: Duration::from_secs(10),
+                max_backoff: Duration::from_secs(60),
+                jitter: false,
+            },
+            ..Worker::default()
+        };
+        let queue = InMemorySendQueue::new();
+        queue.push(mention(target.as_str())).unwrap();
+        let now = SystemTime::now();
+
+        // Retry-After is longer than the backoff
+        let processed = block_on(worker.process_due(&queue, now)).unwrap();
+        assert_eq!(processed.len(), 1);
+        let entry = queue.get(1).unwrap().unwrap();
+        assert_eq!(entry.state, DeliveryState::Pending);
+        assert_eq!(entry.attempts, 1);
+        assert_eq!(entry.next_attempt_at, now + Duration::from_secs(120));
+        assert!(entry.last_error.unwrap().contains("503"));
+
+        assert!(
+            block_on(worker.process_due(&queue, now + Duration::from_secs(60)))
+                .unwrap()
+                .is_empty()
+        );
+
+        let later = now + Duration::from_secs(120);
+        let processed = block_on(worker.process_due(&queue, later)).unwrap();
+        assert_eq!(processed[0].state, DeliveryState::Delivered);
+        assert_eq!(processed[0].outcome.as_deref(), Some("accepted"));
+        assert_eq!(processed[0].last_error, None);
+        assert!(block_on(worker.process_due(&queue, later))
+            .unwrap()
+            .is_empty());
+
+        // permanent errors are not retried
+        let target = serve_sequence(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nLink: </endpoint>; rel=webmention\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        queue.push(mention(target.as_str())).unwrap();
+        let processed = block_on(worker.process_due(&queue, later)).unwrap();
+        assert_eq!(processed[0].state, DeliveryState::Failed);
+        assert_eq!(processed[0].attempts, 1);
+    }
+
+    #[test]
+    fn test_worker_checks_source() {
+        let source = serve_sequence(vec![
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        let worker = Worker {
+            client: HttpClient {
+                retry_policy: RetryPolicy::none(),
+                ..local_client(1024)
+            },
+            retry_policy: RetryPolicy {
+                jitter: false,
+                ..RetryPolicy::default()
+            },
+            ..Worker::default()
+        };
+        let queue = InMemorySendQueue::new();
+        let mention = Webmention::new(source.as_str(), "https://example.org/post").unwrap();
+        queue.push(mention).unwrap();
+        let now = SystemTime::now();
+
+        // a source that is unavailable is not taken for one that does not link to the target
+        let processed = block_on(worker.process_due(&queue, now)).unwrap();
+        assert_eq!(processed[0].state, DeliveryState::Pending);
+        assert_eq!(processed[0].mention.checked(), None);
+
+        let later = processed[0].next_attempt_at;
+        let mut processed = block_on(worker.process_due(&queue, later)).unwrap();
+        assert_eq!(processed[0].state, DeliveryState::Rejected);
+        assert_eq!(processed[0].mention.checked(), Some(false));
+
+        processed[0].reschedule(later);
+        assert_eq!(processed[0].mention.checked(), None);
+        assert!(processed[0].is_due(later));
+    }
+}
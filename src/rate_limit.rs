@@ -0,0 +1,214 @@
+use crate::wm_url::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Largest number of permits a semaphore can have
+const MAX_PERMITS: usize = usize::MAX >> 3;
+
+/// Hosts remembered before idle ones are forgotten
+const MAX_IDLE_HOSTS: usize = 1024;
+
+/// How many requests may be in flight and how often a single host may be contacted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    /// Requests in flight at once, across all hosts
+    pub max_concurrent: usize,
+    /// Requests in flight at once to a single host
+    pub max_concurrent_per_host: usize,
+    /// Requests started per second to a single host, unlimited if not positive
+    pub requests_per_second_per_host: f64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            max_concurrent: 16,
+            max_concurrent_per_host: 2,
+            requests_per_second_per_host: 2.0,
+        }
+    }
+}
+
+impl RateLimits {
+    /// No limits at all, as it used to be.
+    pub fn unlimited() -> RateLimits {
+        RateLimits {
+            max_concurrent: MAX_PERMITS,
+            max_concurrent_per_host: MAX_PERMITS,
+            requests_per_second_per_host: 0.0,
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        let rate = self.requests_per_second_per_host;
+        if rate > 0.0 && rate.is_finite() {
+            Duration::from_secs_f64(1.0 / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Host {
+    in_flight: Arc<Semaphore>,
+    /// Earliest moment the next request may start
+    next_start: Mutex<Instant>,
+}
+
+/// Enforces [RateLimits] for every request of an [HttpClient](crate::HttpClient) and its clones,
+/// whether it discovers endpoints, verifies sources or sends webmentions.
+///
+/// Requests wait for their turn: first for a free slot of their host, then for the host's
+/// rate, then for a free slot overall. Each redirect is a separate request to its own host.
+#[derive(Debug)]
+pub struct RequestScheduler {
+    limits: RateLimits,
+    in_flight: Arc<Semaphore>,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+/// Allows a request to be in flight, until dropped.
+#[derive(Debug)]
+pub struct RequestPermit {
+    _host: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl Default for RequestScheduler {
+    fn default() -> Self {
+        RequestScheduler::new(RateLimits::default())
+    }
+}
+
+impl RequestScheduler {
+    pub fn new(limits: RateLimits) -> RequestScheduler {
+        RequestScheduler {
+            in_flight: Arc::new(Semaphore::new(limits.max_concurrent.clamp(1, MAX_PERMITS))),
+            hosts: Mutex::new(HashMap::new()),
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Waits until a request to `url` is allowed to start.
+    pub async fn acquire(&self, url: &Url) -> RequestPermit {
+        let host = self.host(url.host_str().unwrap_or_default());
+
+        // semaphores are never closed
+        let host_permit = host.in_flight.clone().acquire_owned().await.unwrap();
+        let start = {
+            let mut next_start = host.next_start.lock().unwrap();
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + self.limits.interval();
+            start
+        };
+        tokio::time::sleep_until(start).await;
+        let global_permit = self.in_flight.clone().acquire_owned().await.unwrap();
+
+        RequestPermit {
+            _host: host_permit,
+            _global: global_permit,
+        }
+    }
+
+    fn host(&self, name: &str) -> Arc<Host> {
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.len() >= MAX_IDLE_HOSTS && !hosts.contains_key(name) {
+            let now = Instant::now();
+            hosts.retain(|_, host| {
+                Arc::strong_count(host) > 1 || *host.next_start.lock().unwrap() > now
+            });
+        }
+
+        let limits = &self.limits;
+        hosts
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(Host {
+                    in_flight: Arc::new(Semaphore::new(
+                        limits.max_concurrent_per_host.clamp(1, MAX_PERMITS),
+                    )),
+                    next_start: Mutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RateLimits, RequestScheduler};
+    use crate::wm_url::Url;
+    use std::time::Duration;
+    use tokio::time::{timeout, Instant};
+    use tokio_test::block_on;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    const SHORT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn test_concurrency() {
+        let scheduler = RequestScheduler::new(RateLimits {
+            max_concurrent: 2,
+            max_concurrent_per_host: 1,
+            requests_per_second_per_host: 0.0,
+        });
+        block_on(async {
+            let first = scheduler.acquire(&url("https://a.example/1")).await;
+            assert!(
+                timeout(SHORT, scheduler.acquire(&url("https://a.example/2")))
+                    .await
+                    .is_err()
+            );
+
+            let _second = scheduler.acquire(&url("https://b.example/")).await;
+            // hosts have free slots, but the global limit is reached
+            assert!(
+                timeout(SHORT, scheduler.acquire(&url("https://c.example/")))
+                    .await
+                    .is_err()
+            );
+
+            drop(first);
+            assert!(
+                timeout(SHORT, scheduler.acquire(&url("https://a.example/2")))
+                    .await
+                    .is_ok()
+            );
+        });
+    }
+
+    #[test]
+    fn test_rate() {
+        let scheduler = RequestScheduler::new(RateLimits {
+            requests_per_second_per_host: 20.0,
+            ..RateLimits::unlimited()
+        });
+        block_on(async {
+            // the clock moves only while every task waits for it
+            tokio::time::pause();
+            let started = Instant::now();
+            for _ in 0..3 {
+                scheduler.acquire(&url("https://a.example/")).await;
+            }
+            // timers have a resolution of a millisecond
+            let elapsed = started.elapsed();
+            assert!(elapsed >= Duration::from_millis(100) && elapsed <= Duration::from_millis(101));
+
+            // other hosts do not wait
+            let started = Instant::now();
+            scheduler.acquire(&url("https://b.example/")).await;
+            assert!(started.elapsed() <= Duration::from_millis(1));
+        });
+    }
+}
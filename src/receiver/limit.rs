@@ -0,0 +1,404 @@
+use crate::error::WebmentionError;
+use crate::wm_url::Url;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Clients and hosts remembered before those back to their full allowance are forgotten
+const MAX_TRACKED: usize = 10_000;
+
+/// Suggested delay when too many verifications are pending for a target, which usually
+/// finish within seconds
+const PENDING_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// How many webmentions are accepted for processing before the receiver starts rejecting them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiveLimits {
+    /// Webmentions per `window` from a single client address, or a single /64 network for
+    /// IPv6 clients
+    pub per_client: u32,
+    /// Webmentions per `window` with sources on a single host
+    pub per_source_host: u32,
+    pub window: Duration,
+    /// Verifications in progress at once for a single target
+    pub max_pending_per_target: usize,
+}
+
+impl Default for ReceiveLimits {
+    fn default() -> Self {
+        ReceiveLimits {
+            per_client: 30,
+            per_source_host: 10,
+            window: Duration::from_secs(60),
+            max_pending_per_target: 5,
+        }
+    }
+}
+
+/// Allowance that refills continuously, so that `capacity` requests are allowed per `window`
+/// with bursts up to `capacity`.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, capacity: u32, window: Duration, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let rate = f64::from(capacity) / window.as_secs_f64().max(f64::EPSILON);
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(capacity));
+        self.updated = now;
+    }
+
+    /// Checks that there is a token to [take](Bucket::take), or tells how long to wait until
+    /// there is one.
+    fn check(&mut self, capacity: u32, window: Duration, now: Instant) -> Result<(), Duration> {
+        self.refill(capacity, window, now);
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else if capacity == 0 {
+            Err(window)
+        } else {
+            let rate = f64::from(capacity) / window.as_secs_f64();
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    clients: HashMap<IpAddr, Bucket>,
+    source_hosts: HashMap<String, Bucket>,
+    pending: HashMap<String, usize>,
+}
+
+/// Enforces [ReceiveLimits] on incoming webmentions, before their sources are fetched.
+///
+/// It is not tied to any HTTP framework: call [admit](ReceiveLimiter::admit) (or let
+/// [Receiver](super::Receiver) do it) and respond with `429 Too Many Requests` and `Retry-After`
+/// when it fails with [TooManyRequests](WebmentionError::TooManyRequests).
+#[derive(Debug)]
+pub struct ReceiveLimiter {
+    limits: ReceiveLimits,
+    state: Mutex<State>,
+}
+
+/// Counts a verification as pending for its target, until dropped.
+#[derive(Debug)]
+pub struct Admission<'a> {
+    limiter: &'a ReceiveLimiter,
+    target: String,
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if let Some(pending) = state.pending.get_mut(&self.target) {
+            *pending -= 1;
+            if *pending == 0 {
+                state.pending.remove(&self.target);
+            }
+        }
+    }
+}
+
+impl Default for ReceiveLimiter {
+    fn default() -> Self {
+        ReceiveLimiter::new(ReceiveLimits::default())
+    }
+}
+
+impl ReceiveLimiter {
+    pub fn new(limits: ReceiveLimits) -> ReceiveLimiter {
+        ReceiveLimiter {
+            limits,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn limits(&self) -> &ReceiveLimits {
+        &self.limits
+    }
+
+    /// Admits a webmention from `client` (if its address is known) for processing, or fails
+    /// with [TooManyRequests](WebmentionError::TooManyRequests) telling when to try again.
+    ///
+    /// Allowances are only spent by admitted webmentions.
+    pub fn admit(
+        &self,
+        client: Option<IpAddr>,
+        source: &Url,
+        target: &Url,
+    ) -> Result<Admission<'_>, WebmentionError> {
+        self.admit_at(client, source, target, Instant::now())
+    }
+
+    fn admit_at(
+        &self,
+        client: Option<IpAddr>,
+        source: &Url,
+        target: &Url,
+        now: Instant,
+    ) -> Result<Admission<'_>, WebmentionError> {
+        let limits = &self.limits;
+        let mut state = self.state.lock().unwrap();
+        let State {
+            clients,
+            source_hosts,
+            pending,
+        } = &mut *state;
+        let too_many = |reason: String, retry_after| WebmentionError::TooManyRequests {
+            reason,
+            retry_after,
+        };
+
+        let client_bucket = match client {
+            Some(client) => {
+                let bucket = bucket(
+                    clients,
+                    client_key(client),
+                    limits.per_client,
+                    limits.window,
+                    now,
+                );
+                bucket
+                    .check(limits.per_client, limits.window, now)
+                    .map_err(|wait| {
+                        too_many(format!("too many webmentions from {}", client), wait)
+                    })?;
+                Some(bucket)
+            }
+            None => None,
+        };
+        let source_host = source.host_str().unwrap_or_default().to_string();
+        let source_bucket = bucket(
+            source_hosts,
+            source_host.clone(),
+            limits.per_source_host,
+            limits.window,
+            now,
+        );
+        source_bucket
+            .check(limits.per_source_host, limits.window, now)
+            .map_err(|wait| {
+                too_many(
+                    format!("too many webmentions from sources on {}", source_host),
+                    wait,
+                )
+            })?;
+
+        let mut target = target.clone();
+        target.set_fragment(None);
+        let target = target.to_string();
+        if pending.get(&target).copied().unwrap_or(0) >= limits.max_pending_per_target {
+            return Err(too_many(
+                format!("too many webmentions for {} are being verified", target),
+                PENDING_RETRY_AFTER,
+            ));
+        }
+
+        if let Some(bucket) = client_bucket {
+            bucket.take();
+        }
+        source_bucket.take();
+        *pending.entry(target.clone()).or_insert(0) += 1;
+
+        Ok(Admission {
+            limiter: self,
+            target,
+        })
+    }
+}
+
+/// Key of the allowance of `client`: IPv6 clients usually have a whole /64 network to pick
+/// addresses from, while IPv4 clients mapped into IPv6 are keyed by their IPv4 address.
+fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let s = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+            }
+        },
+        ip => ip,
+    }
+}
+
+fn bucket<K: Eq + Hash>(
+    buckets: &mut HashMap<K, Bucket>,
+    key: K,
+    capacity: u32,
+    window: Duration,
+    now: Instant,
+) -> &mut Bucket {
+    if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&key) {
+        buckets.retain(|_, bucket| {
+            bucket.refill(capacity, window, now);
+            bucket.tokens < f64::from(capacity)
+        });
+    }
+    buckets.entry(key).or_insert(Bucket {
+        tokens: f64::from(capacity),
+        updated: now,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReceiveLimiter, ReceiveLimits};
+    use crate::error::WebmentionError;
+    use crate::wm_url::Url;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn retry_after(result: Result<impl std::fmt::Debug, WebmentionError>) -> Duration {
+        match result {
+            Err(WebmentionError::TooManyRequests { retry_after, .. }) => retry_after,
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_client_limit() {
+        let limiter = ReceiveLimiter::new(ReceiveLimits {
+            per_client: 2,
+            per_source_host: 100,
+            window: Duration::from_secs(60),
+            max_pending_per_target: 100,
+        });
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let (source, target) = (url("https://a.example/"), url("https://me.example/"));
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            limiter
+                .admit_at(Some(client), &source, &target, now)
+                .unwrap();
+        }
+        let wait = retry_after(limiter.admit_at(Some(client), &source, &target, now));
+        assert_eq!(wait, Duration::from_secs(30));
+        limiter
+            .admit_at(Some(other), &source, &target, now)
+            .unwrap();
+
+        // allowance refills over the window
+        let later = now + Duration::from_secs(30);
+        limiter
+            .admit_at(Some(client), &source, &target, later)
+            .unwrap();
+        assert!(limiter
+            .admit_at(Some(client), &source, &target, later)
+            .is_err());
+    }
+
+    #[test]
+    fn test_ipv6_client_limit() {
+        let limiter = ReceiveLimiter::new(ReceiveLimits {
+            per_client: 1,
+            per_source_host: 100,
+            window: Duration::from_secs(60),
+            max_pending_per_target: 100,
+        });
+        let (source, target) = (url("https://a.example/"), url("https://me.example/"));
+        let now = Instant::now();
+        let admit = |client: &str| {
+            let client: IpAddr = client.parse().unwrap();
+            limiter
+                .admit_at(Some(client), &source, &target, now)
+                .map(drop)
+        };
+
+        admit("2001:db8:0:1::1").unwrap();
+        // the same /64 network
+        assert!(admit("2001:db8:0:1:ffff::2").is_err());
+        admit("2001:db8:0:2::1").unwrap();
+
+        // IPv4 clients of a dual-stack socket are not one network
+        admit("::ffff:192.0.2.1").unwrap();
+        assert!(admit("192.0.2.1").is_err());
+        admit("::ffff:192.0.2.2").unwrap();
+    }
+
+    #[test]
+    fn test_rejected_attempts_are_free() {
+        let limiter = ReceiveLimiter::new(ReceiveLimits {
+            per_client: 2,
+            per_source_host: 1,
+            window: Duration::from_secs(60),
+            max_pending_per_target: 100,
+        });
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let target = url("https://me.example/");
+        let now = Instant::now();
+
+        limiter
+            .admit_at(Some(client), &url("https://spam.example/1"), &target, now)
+            .unwrap();
+        for _ in 0..3 {
+            assert!(limiter
+                .admit_at(Some(client), &url("https://spam.example/2"), &target, now)
+                .is_err());
+        }
+        // rejected attempts did not spend the allowance of the client
+        limiter
+            .admit_at(Some(client), &url("https://ham.example/"), &target, now)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_source_host_limit() {
+        let limiter = ReceiveLimiter::new(ReceiveLimits {
+            per_client: 100,
+            per_source_host: 1,
+            window: Duration::from_secs(10),
+            max_pending_per_target: 100,
+        });
+        let target = url("https://me.example/");
+        let now = Instant::now();
+
+        limiter
+            .admit_at(None, &url("https://spam.example/1"), &target, now)
+            .unwrap();
+        let wait =
+            retry_after(limiter.admit_at(None, &url("https://spam.example/2"), &target, now));
+        assert_eq!(wait, Duration::from_secs(10));
+        limiter
+            .admit_at(None, &url("https://ham.example/"), &target, now)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pending_limit() {
+        let limiter = ReceiveLimiter::new(ReceiveLimits {
+            max_pending_per_target: 1,
+            per_client: 100,
+            per_source_host: 100,
+            ..ReceiveLimits::default()
+        });
+        let source = url("https://a.example/");
+        let target = url("https://me.example/post");
+
+        let admission = limiter.admit(None, &source, &target).unwrap();
+        assert!(limiter
+            .admit(None, &source, &url("https://me.example/post#comments"))
+            .is_err());
+        limiter
+            .admit(None, &source, &url("https://me.example/other"))
+            .unwrap();
+        drop(admission);
+        limiter.admit(None, &source, &target).unwrap();
+    }
+}
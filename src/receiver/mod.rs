@@ -0,0 +1,270 @@
+use crate::error::WebmentionError;
+use crate::http_client::HttpClient;
+use crate::snapshot::{Snapshot, SnapshotOptions};
+use crate::storage::WebmentionStorage;
+use crate::webmention::{Moderation, Webmention};
+use crate::wm_url::{Url, UrlEquivalence};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+pub mod limit;
+pub mod policy;
+pub mod spam;
+
+use limit::ReceiveLimiter;
+use policy::{SourceDecision, SourcePolicy};
+use spam::{SourceContent, SpamFilter, Verdict};
+
+/// Receiving side of webmentions, independent of the HTTP framework that serves the endpoint.
+///
+/// The framework extracts `source`, `target` and the client address from the request, passes
+/// them to [receive](Receiver::receive) and turns the result into a response.
+#[derive(Debug, Clone, Default)]
+pub struct Receiver {
+    /// Fetches sources
+    pub client: HttpClient,
+    /// Limits on incoming webmentions, if any
+    pub limiter: Option<Arc<ReceiveLimiter>>,
+    /// Sources that are blocked or do not need moderation
+    pub source_policy: SourcePolicy,
+    /// Judges content of sources that are not allowed by `source_policy`, if any
+    pub spam_filter: Option<Arc<dyn SpamFilter>>,
+    /// How the [snapshot](Webmention::snapshot) stored with each webmention is made
+    pub snapshot_options: SnapshotOptions,
+    /// How links of sources are compared with targets, and targets with the domain that
+    /// receives webmentions. Storages should look webmentions up with the same equivalence.
+    pub equivalence: UrlEquivalence,
+}
+
+impl Receiver {
+    pub fn new(client: HttpClient) -> Receiver {
+        Receiver {
+            client,
+            ..Receiver::default()
+        }
+    }
+
+    /// Verifies that `source` links to `target` and stores the webmention with a snapshot of the
+    /// source. `remote_addr` is the address of the client that sent the webmention, if known.
+    ///
+    /// Webmentions from sources blocked by `source_policy` fail with
+    /// [BlockedSource](WebmentionError::BlockedSource) before the source is fetched. Others are
+    /// stored [approved](Moderation::Approved) if their source is allowed. The rest are judged
+    /// by `spam_filter`: stored approved if it finds them ham, as [spam](Moderation::Spam) if it
+    /// finds them spam, and [pending](Moderation::Pending) if it is uncertain, fails or there is
+    /// no filter.
+    ///
+    /// Returns `Ok(false)` if `source` does not link to `target`, and fails with
+    /// [TooManyRequests](WebmentionError::TooManyRequests) if `limiter` does not admit the
+    /// webmention, which should be answered with `429 Too Many Requests` and `Retry-After`.
+    pub async fn receive(
+        &self,
+        storage: &impl WebmentionStorage,
+        source: &Url,
+        target: &Url,
+        remote_addr: Option<IpAddr>,
+    ) -> Result<bool, WebmentionError> {
+        let decision = self.source_policy.decide(source);
+        if decision == SourceDecision::Block {
+            log::info!("Blocked webmention from {} to {}", source, target);
+            return Err(WebmentionError::BlockedSource(source.clone()));
+        }
+
+        let _admission = match &self.limiter {
+            Some(limiter) => Some(limiter.admit(remote_addr, source, target)?),
+            None => None,
+        };
+
+        // the fetched document is not Send, so it is dropped before awaiting anything else
+        let (content, snapshot) = {
+            let response = self.client.get(source).await?;
+            match crate::verification::contains_with(&response, target, &self.equivalence) {
+                Ok(()) => {}
+                Err(WebmentionError::NoDocumentLinks) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+            (
+                SourceContent::from_response(&response, target),
+                Snapshot::from_response(&response, target, &self.snapshot_options),
+            )
+        };
+
+        let mut mention = Webmention::from((source.clone(), target.clone()));
+        mention.snapshot = Some(snapshot);
+        mention.moderation = match (decision, &self.spam_filter) {
+            (SourceDecision::Allow, _) => Moderation::Approved,
+            (_, Some(filter)) => match filter.check(&content, remote_addr).await {
+                Ok(score) => {
+                    log::info!(
+                        "Spam score of {}: {} ({})",
+                        source,
+                        score.score,
+                        score.reasons.join(", ")
+                    );
+                    match score.verdict {
+                        Verdict::Ham => Moderation::Approved,
+                        Verdict::Uncertain => Moderation::Pending,
+                        Verdict::Spam => Moderation::Spam,
+                    }
+                }
+                Err(err) => {
+                    log::warn!("Could not check {} for spam: {}", source, err);
+                    Moderation::Pending
+                }
+            },
+            (_, None) => Moderation::Pending,
+        };
+
+        storage
+            .store(mention)
+            .map_err(|source| WebmentionError::StorageError {
+                source: Box::new(source),
+            })?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::limit::{ReceiveLimiter, ReceiveLimits};
+    use super::policy::{DomainList, SourcePolicy};
+    use super::spam::{HeuristicFilter, SpamFilter};
+    use super::Receiver;
+    use crate::address_policy::AddressPolicy;
+    use crate::error::WebmentionError;
+    use crate::http_client::test::{local_client, serve_sequence};
+    use crate::http_client::HttpClient;
+    use crate::storage::{InMemoryWebmentionStorage, WebmentionStorage};
+    use crate::webmention::Moderation;
+    use crate::wm_url::{Url, UrlEquivalence};
+    use std::sync::Arc;
+    use tokio_test::block_on;
+
+    #[test]
+    fn test_limited_receiver() {
+        let receiver = Receiver {
+            limiter: Some(Arc::new(ReceiveLimiter::new(ReceiveLimits {
+                per_client: 1,
+                ..ReceiveLimits::default()
+            }))),
+            ..Receiver::new(HttpClient::new(AddressPolicy::default()))
+        };
+        let storage = InMemoryWebmentionStorage::new();
+        // forbidden address fails without a request
+        let source = Url::parse("http://127.0.0.1/post").unwrap();
+        let target = Url::parse("https://example.com/").unwrap();
+        let client = Some("192.0.2.1".parse().unwrap());
+
+        match block_on(receiver.receive(&storage, &source, &target, client)) {
+            Err(WebmentionError::ForbiddenAddress { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match block_on(receiver.receive(&storage, &source, &target, client)) {
+            Err(err @ WebmentionError::TooManyRequests { .. }) => {
+                assert!(err.retry_after().is_some())
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_blocked_source() {
+        let receiver = Receiver {
+            source_policy: SourcePolicy {
+                blocked: DomainList::parse("*.spam.example").unwrap(),
+                ..SourcePolicy::default()
+            },
+            limiter: Some(Arc::new(ReceiveLimiter::new(ReceiveLimits {
+                per_client: 1,
+                ..ReceiveLimits::default()
+            }))),
+            ..Receiver::new(HttpClient::new(AddressPolicy::default()))
+        };
+        let storage = InMemoryWebmentionStorage::new();
+        let source = Url::parse("https://www.spam.example/post").unwrap();
+        let target = Url::parse("https://example.com/").unwrap();
+        let client = Some("192.0.2.1".parse().unwrap());
+
+        // blocked before fetching and before counting against limits
+        for _ in 0..2 {
+            match block_on(receiver.receive(&storage, &source, &target, client)) {
+                Err(WebmentionError::BlockedSource(blocked)) => assert_eq!(blocked, source),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert!(storage.lookup_by_target(target).unwrap().is_empty());
+    }
+
+    fn html_response(body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_spam_filter() {
+        let target = Url::parse("https://bob.example/post").unwrap();
+        let links: String = (0..10)
+            .map(|i| format!("<a href=\"https://shop{}.example/\">cheap</a> ", i))
+            .collect();
+        let spam = format!(
+            "<p>Visit our online casino {}<a href=\"{}\">x</a></p>",
+            links, target
+        );
+        let reply = format!(
+            "<article class=\"h-entry\"><a class=\"p-author h-card\" href=\"/\">Alice</a>\
+             <p>Thanks for <a href=\"{}\">the post</a>, it helped me a lot.</p></article>",
+            target
+        );
+        let source = serve_sequence(vec![html_response(&reply), html_response(&spam)]);
+        let filter: Arc<dyn SpamFilter> = Arc::new(HeuristicFilter::default());
+        let receiver = Receiver {
+            spam_filter: Some(filter),
+            ..Receiver::new(local_client(4096))
+        };
+        let storage = InMemoryWebmentionStorage::new();
+
+        assert!(block_on(receiver.receive(&storage, &source, &target, None)).unwrap());
+        let stored = storage.lookup_by_target(target.clone()).unwrap();
+        assert_eq!(stored[0].moderation, Moderation::Approved);
+        let snapshot = stored[0].snapshot.as_ref().unwrap();
+        assert_eq!(
+            snapshot.summary,
+            "Alice Thanks for the post, it helped me a lot."
+        );
+
+        // the source is edited into spam and sent again
+        assert!(block_on(receiver.receive(&storage, &source, &target, None)).unwrap());
+        let stored = storage.lookup_by_target(target).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].moderation, Moderation::Spam);
+    }
+
+    #[test]
+    fn test_equivalence() {
+        let target = Url::parse("https://bob.example/post").unwrap();
+        let page = html_response("<a href=\"http://www.bob.example/post\">post</a>");
+        let source = serve_sequence(vec![page.clone(), page]);
+        let storage = InMemoryWebmentionStorage::new();
+
+        let receiver = Receiver::new(local_client(4096));
+        assert!(!block_on(receiver.receive(&storage, &source, &target, None)).unwrap());
+        let receiver = Receiver {
+            equivalence: UrlEquivalence::lenient(),
+            ..receiver
+        };
+        assert!(block_on(receiver.receive(&storage, &source, &target, None)).unwrap());
+    }
+
+    #[test]
+    fn test_receive_is_send() {
+        fn assert_send<T: Send>(_: T) {}
+        let receiver = Receiver::default();
+        let storage = InMemoryWebmentionStorage::new();
+        let url = Url::parse("https://example.com/").unwrap();
+        assert_send(receiver.receive(&storage, &url, &url, None));
+    }
+}
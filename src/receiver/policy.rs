@@ -0,0 +1,284 @@
+use crate::error::WebmentionError;
+use crate::wm_url::Url;
+use std::path::Path;
+
+/// A domain, or a domain with all of its subdomains when written as `*.example.com`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DomainPattern {
+    Exact(String),
+    WithSubdomains(String),
+}
+
+impl DomainPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            DomainPattern::Exact(domain) => host == domain,
+            DomainPattern::WithSubdomains(domain) => {
+                host == domain
+                    || (host.ends_with(domain.as_str())
+                        && host[..host.len() - domain.len()].ends_with('.'))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DomainPattern::Exact(domain) => write!(f, "{}", domain),
+            DomainPattern::WithSubdomains(domain) => write!(f, "*.{}", domain),
+        }
+    }
+}
+
+/// Lowercases the domain and converts it to punycode, the way hosts of parsed URLs are.
+fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.');
+    if domain.is_empty() || domain.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+        return None;
+    }
+    match url::Host::parse(domain).ok()? {
+        url::Host::Domain(domain) => Some(domain),
+        _ => None,
+    }
+}
+
+/// List of domains in the plain-text format shared by IndieWeb receivers: one domain per line,
+/// `*.example.com` for a domain with all of its subdomains, `#` starts a comment.
+///
+/// Lines with URLs instead of domains, such as `https://spam.example/post`, are accepted and
+/// stand for the host of the URL.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainList {
+    patterns: Vec<DomainPattern>,
+}
+
+impl DomainList {
+    pub fn new() -> DomainList {
+        DomainList::default()
+    }
+
+    /// Parses a list, failing on the first line that is neither a domain nor a URL.
+    pub fn parse(text: &str) -> Result<DomainList, WebmentionError> {
+        let mut list = DomainList::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !list.insert(line) {
+                return Err(WebmentionError::InvalidDomainPattern {
+                    line: number + 1,
+                    pattern: line.to_string(),
+                });
+            }
+        }
+        Ok(list)
+    }
+
+    /// Reads a list from `path`, see [parse](DomainList::parse).
+    pub fn read(path: impl AsRef<Path>) -> Result<DomainList, WebmentionError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|source| WebmentionError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        DomainList::parse(&text)
+    }
+
+    /// Writes the list to `path` in the same format it is read.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), WebmentionError> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_string()).map_err(|source| WebmentionError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Adds a domain, `*.domain` or the host of a URL. Returns `false` if `pattern` is none of
+    /// them.
+    pub fn insert(&mut self, pattern: &str) -> bool {
+        let pattern = pattern.trim();
+        let parsed = if let Some(domain) = pattern.strip_prefix("*.") {
+            normalize_domain(domain).map(DomainPattern::WithSubdomains)
+        } else if pattern.contains("://") {
+            Url::parse(pattern)
+                .ok()
+                .and_then(|url| url.host_str().and_then(normalize_domain))
+                .map(DomainPattern::Exact)
+        } else {
+            normalize_domain(pattern).map(DomainPattern::Exact)
+        };
+
+        match parsed {
+            Some(parsed) => {
+                if !self.patterns.contains(&parsed) {
+                    self.patterns.push(parsed);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the pattern written exactly as `pattern` (after normalization). Returns whether
+    /// it was there.
+    pub fn remove(&mut self, pattern: &str) -> bool {
+        let mut removed = DomainList::new();
+        if !removed.insert(pattern) {
+            return false;
+        }
+        let before = self.patterns.len();
+        self.patterns.retain(|p| !removed.patterns.contains(p));
+        self.patterns.len() != before
+    }
+
+    /// Adds every pattern of `other`, e.g. when importing a list published by someone else.
+    pub fn extend(&mut self, other: &DomainList) {
+        for pattern in other.patterns.iter() {
+            if !self.patterns.contains(pattern) {
+                self.patterns.push(pattern.clone());
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether the host of `url` is on the list.
+    pub fn contains(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.trim_end_matches('.'),
+            None => return false,
+        };
+        self.patterns.iter().any(|pattern| pattern.matches(host))
+    }
+}
+
+impl std::str::FromStr for DomainList {
+    type Err = WebmentionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DomainList::parse(s)
+    }
+}
+
+impl std::fmt::Display for DomainList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for pattern in self.patterns.iter() {
+            writeln!(f, "{}", pattern)?;
+        }
+        Ok(())
+    }
+}
+
+/// What to do with a webmention, judging by its source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceDecision {
+    /// Do not fetch the source and do not store the webmention
+    Block,
+    /// Store the webmention as approved, without moderation
+    Allow,
+    /// Store the webmention for moderation
+    Moderate,
+}
+
+/// Decides by the domain of the source whether incoming webmentions are processed at all and
+/// whether they need moderation. Blocking takes precedence over allowing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourcePolicy {
+    pub blocked: DomainList,
+    pub allowed: DomainList,
+}
+
+impl SourcePolicy {
+    pub fn decide(&self, source: &Url) -> SourceDecision {
+        if self.blocked.contains(source) {
+            SourceDecision::Block
+        } else if self.allowed.contains(source) {
+            SourceDecision::Allow
+        } else {
+            SourceDecision::Moderate
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DomainList, SourceDecision, SourcePolicy};
+    use crate::error::WebmentionError;
+    use crate::wm_url::Url;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_domain_list() {
+        let list = DomainList::parse(
+            "# spam sources\n\
+             spam.example\n\
+             *.Casino.example.  # all of them\n\
+             \n\
+             https://blog.example/spammy-post\n\
+             bücher.example\n",
+        )
+        .unwrap();
+        assert_eq!(list.len(), 4);
+
+        assert!(list.contains(&url("https://spam.example/post")));
+        assert!(!list.contains(&url("https://www.spam.example/post")));
+        assert!(list.contains(&url("https://casino.example/")));
+        assert!(list.contains(&url("https://a.b.casino.example/")));
+        assert!(!list.contains(&url("https://notcasino.example/")));
+        assert!(list.contains(&url("https://blog.example/other-post")));
+        assert!(list.contains(&url("https://xn--bcher-kva.example/")));
+
+        assert_eq!(
+            list.to_string(),
+            "spam.example\n*.casino.example\nblog.example\nxn--bcher-kva.example\n"
+        );
+        assert_eq!(DomainList::parse(&list.to_string()).unwrap(), list);
+
+        match DomainList::parse("ok.example\nnot a domain\n") {
+            Err(WebmentionError::InvalidDomainPattern { line, .. }) => assert_eq!(line, 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_edit_domain_list() {
+        let mut list = DomainList::parse("a.example\n").unwrap();
+        list.extend(&DomainList::parse("a.example\n*.b.example\n").unwrap());
+        assert_eq!(list.len(), 2);
+        assert!(!list.insert("http://"));
+        assert!(list.remove("*.B.example"));
+        assert!(!list.remove("*.b.example"));
+        assert_eq!(list.to_string(), "a.example\n");
+    }
+
+    #[test]
+    fn test_source_policy() {
+        let policy = SourcePolicy {
+            blocked: DomainList::parse("*.spam.example").unwrap(),
+            allowed: DomainList::parse("friend.example\nwww.spam.example").unwrap(),
+        };
+        assert_eq!(
+            policy.decide(&url("https://friend.example/")),
+            SourceDecision::Allow
+        );
+        assert_eq!(
+            policy.decide(&url("https://www.spam.example/")),
+            SourceDecision::Block
+        );
+        assert_eq!(
+            policy.decide(&url("https://stranger.example/")),
+            SourceDecision::Moderate
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use crate::error::WebmentionError;
+use crate::html::visible_text;
+use crate::http_client::{HttpClient, Response};
+use crate::snapshot::find_author;
+use crate::verification::SourceKind;
+use crate::wm_url::Url;
+use select::predicate::{Attr, Name, Predicate};
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+/// What a [SpamFilter] gets to know about a verified source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceContent {
+    pub source: Url,
+    pub target: Url,
+    pub kind: SourceKind,
+    /// Visible text of the first `h-entry`, or of the whole document
+    pub text: String,
+    /// Links in the same part of the document as `text`
+    pub links: usize,
+    pub author_name: Option<String>,
+    pub author_url: Option<Url>,
+    /// Whether the source is marked up as an `h-entry`
+    pub has_entry: bool,
+}
+
+impl SourceContent {
+    /// Extracts the content of the source that was fetched as `response` and links to `target`.
+    ///
+    /// Authors are only found in HTML, from `p-author` of the `h-entry` (or the first `h-card`
+    /// of the document).
+    pub fn from_response(response: &Response, target: &Url) -> SourceContent {
+        let kind = SourceKind::from_content_type(response.content_type.as_deref());
+        let raw = response.html.raw();
+        let mut content = SourceContent {
+            source: response.url.clone(),
+            target: target.clone(),
+            kind,
+            text: raw.split_whitespace().collect::<Vec<_>>().join(" "),
+            links: raw
+                .split_whitespace()
+                .filter(|word| word.contains("http://") || word.contains("https://"))
+                .count(),
+            author_name: None,
+            author_url: None,
+            has_entry: kind == SourceKind::Json && raw.contains("\"h-entry\""),
+        };
+        if kind != SourceKind::Html {
+            return content;
+        }
+
+        content.has_entry = response.html.entry().is_some();
+        let main = match response.html.main_content() {
+            Some(main) => main,
+            None => {
+                content.text.clear();
+                content.links = 0;
+                return content;
+            }
+        };
+        content.text = visible_text(&main);
+        content.links = main.find(Name("a").and(Attr("href", ()))).count();
+
+        if let Some(author) = find_author(&response.html) {
+            content.author_name = author.name;
+            content.author_url = author.url;
+        }
+        content
+    }
+}
+
+/// Conclusion of a [SpamFilter].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Ham,
+    /// Needs a human to decide
+    Uncertain,
+    Spam,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpamScore {
+    /// From 0 (certainly not spam) to 1 (certainly spam)
+    pub score: f64,
+    pub verdict: Verdict,
+    /// Human-readable explanation of the score
+    pub reasons: Vec<String>,
+}
+
+/// Future returned by [SpamFilter::check]
+pub type SpamCheck<'a> =
+    Pin<Box<dyn Future<Output = Result<SpamScore, WebmentionError>> + Send + 'a>>;
+
+/// Judges verified webmentions by the content of their sources.
+///
+/// [Receiver](super::Receiver) consults the filter after the source is verified and stores the
+/// webmention as approved, pending or spam according to the verdict.
+pub trait SpamFilter: Debug + Send + Sync {
+    /// Scores the content of a source, sent as a webmention by `remote_addr` if it is known.
+    fn check<'a>(
+        &'a self,
+        content: &'a SourceContent,
+        remote_addr: Option<IpAddr>,
+    ) -> SpamCheck<'a>;
+}
+
+/// Phrases that legitimate mentions rarely contain
+pub const SPAM_PHRASES: [&str; 10] = [
+    "viagra",
+    "cialis",
+    "online casino",
+    "payday loan",
+    "buy followers",
+    "replica watches",
+    "seo services",
+    "essay writing service",
+    "crypto giveaway",
+    "work from home and earn",
+];
+
+/// Filter that scores sources by simple signals: how many links there are for the amount of
+/// text, whether known spam phrases occur, and whether the source is marked up as an `h-entry`
+/// with an author, as posts of IndieWeb sites usually are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeuristicFilter {
+    /// Links per word above which the source is suspicious
+    pub max_link_density: f64,
+    /// Lowercase phrases to look for
+    pub spam_phrases: Vec<String>,
+    /// Scores from this one are spam
+    pub spam_threshold: f64,
+    /// Scores up to this one are ham
+    pub ham_threshold: f64,
+}
+
+impl Default for HeuristicFilter {
+    fn default() -> Self {
+        HeuristicFilter {
+            max_link_density: 0.1,
+            spam_phrases: SPAM_PHRASES
+                .iter()
+                .map(|phrase| phrase.to_string())
+                .collect(),
+            spam_threshold: 0.6,
+            ham_threshold: 0.2,
+        }
+    }
+}
+
+impl HeuristicFilter {
+    pub fn score(&self, content: &SourceContent) -> SpamScore {
+        let mut score: f64 = 0.0;
+        let mut reasons = Vec::new();
+
+        let words = content.text.split_whitespace().count();
+        if words == 0 {
+            score += 0.3;
+            reasons.push("no text".to_string());
+        }
+        // a couple of links are normal even in short replies
+        if content.links > 2 && content.links as f64 > words as f64 * self.max_link_density {
+            score += 0.4;
+            reasons.push(format!("{} links for {} words", content.links, words));
+        }
+
+        let text = content.text.to_lowercase();
+        for phrase in self.spam_phrases.iter() {
+            if text.contains(phrase.as_str()) {
+                score += 0.3;
+                reasons.push(format!("contains \"{}\"", phrase));
+            }
+        }
+
+        if content.kind != SourceKind::PlainText && !content.has_entry {
+            score += 0.2;
+            reasons.push("no h-entry".to_string());
+        }
+        if content.kind == SourceKind::Html && content.author_name.is_none() {
+            score += 0.1;
+            reasons.push("no author".to_string());
+        }
+
+        let score = score.min(1.0);
+        let verdict = if score >= self.spam_threshold {
+            Verdict::Spam
+        } else if score <= self.ham_threshold {
+            Verdict::Ham
+        } else {
+            Verdict::Uncertain
+        };
+        SpamScore {
+            score,
+            verdict,
+            reasons,
+        }
+    }
+}
+
+impl SpamFilter for HeuristicFilter {
+    fn check<'a>(
+        &'a self,
+        content: &'a SourceContent,
+        _remote_addr: Option<IpAddr>,
+    ) -> SpamCheck<'a> {
+        let score = self.score(content);
+        Box::pin(async move { Ok(score) })
+    }
+}
+
+/// Client of [Akismet](https://akismet.com/development/api/#comment-check) and services
+/// compatible with its `comment-check` API, such as a local stand-in.
+///
+/// Akismet only says whether a comment is spam, so the verdict is never uncertain.
+#[derive(Debug, Clone)]
+pub struct AkismetFilter {
+    pub client: HttpClient,
+    /// URL of `comment-check`
+    pub endpoint: Url,
+    /// Front page of the site receiving webmentions, as registered with the service
+    pub blog: Url,
+}
+
+impl AkismetFilter {
+    /// Client of Akismet itself, using `api_key`.
+    pub fn new(api_key: &str, blog: Url) -> Result<AkismetFilter, WebmentionError> {
+        let endpoint = Url::parse(&format!(
+            "https://{}.rest.akismet.com/1.1/comment-check",
+            api_key
+        ))?;
+        Ok(AkismetFilter::with_endpoint(endpoint, blog))
+    }
+
+    /// Client of a compatible service at `endpoint`.
+    pub fn with_endpoint(endpoint: Url, blog: Url) -> AkismetFilter {
+        AkismetFilter {
+            client: HttpClient::default(),
+            endpoint,
+            blog,
+        }
+    }
+
+    async fn comment_check(
+        &self,
+        content: &SourceContent,
+        remote_addr: Option<IpAddr>,
+    ) -> Result<SpamScore, WebmentionError> {
+        let user_ip = remote_addr.map(|ip| ip.to_string()).unwrap_or_default();
+        let author_url = content.author_url.as_ref().unwrap_or(&content.source);
+        let mut form = vec![
+            ("blog", self.blog.as_str()),
+            ("user_ip", user_ip.as_str()),
+            ("permalink", content.target.as_str()),
+            ("comment_type", "webmention"),
+            ("comment_author_url", author_url.as_str()),
+            ("comment_content", content.text.as_str()),
+        ];
+        if let Some(name) = content.author_name.as_deref() {
+            form.push(("comment_author", name));
+        }
+
+        let answer = self.client.post_form(&self.endpoint, &form).await?;
+        let (score, verdict) = match answer.trim() {
+            "true" => (1.0, Verdict::Spam),
+            "false" => (0.0, Verdict::Ham),
+            other => {
+                return Err(WebmentionError::SpamCheckFailed(format!(
+                    "unexpected answer of <{}>: {}",
+                    self.endpoint, other
+                )))
+            }
+        };
+        Ok(SpamScore {
+            score,
+            verdict,
+            reasons: vec![format!("{} answered {}", self.endpoint, answer.trim())],
+        })
+    }
+}
+
+impl SpamFilter for AkismetFilter {
+    fn check<'a>(
+        &'a self,
+        content: &'a SourceContent,
+        remote_addr: Option<IpAddr>,
+    ) -> SpamCheck<'a> {
+        Box::pin(self.comment_check(content, remote_addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AkismetFilter, HeuristicFilter, SourceContent, SpamFilter, Verdict};
+    use crate::http_client::test::{local_client, response, serve_sequence};
+    use crate::verification::SourceKind;
+    use crate::wm_url::Url;
+    use tokio_test::block_on;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    const REPLY: &str = r#"<html><body><nav><a href="/">Home</a> <a href="/about">About</a></nav>
+        <article class="h-entry">
+          <a class="p-author h-card" href="/">Alice</a>
+          <p class="e-content">Great post! I wrote a <a href="https://bob.example/post">reply</a>
+          with some thoughts on the matter.</p>
+        </article></body></html>"#;
+
+    #[test]
+    fn test_source_content() {
+        let target = url("https://bob.example/post");
+        let content = SourceContent::from_response(&response("text/html", REPLY), &target);
+        assert!(content.has_entry);
+        assert_eq!(content.links, 2);
+        assert_eq!(content.author_name.as_deref(), Some("Alice"));
+        assert_eq!(content.author_url, Some(url("https://alice.example/")));
+        assert!(content.text.starts_with("Alice Great post!"));
+        assert!(!content.text.contains("About"));
+
+        let card = r#"<div class="h-card"><span class="p-name">Alice A.</span>
+            <a class="u-url" href="https://alice.example/">site</a></div>
+            <p>Mentioning <a href="https://bob.example/post">Bob</a></p>"#;
+        let content = SourceContent::from_response(&response("text/html", card), &target);
+        assert!(!content.has_entry);
+        assert_eq!(content.author_name.as_deref(), Some("Alice A."));
+        assert_eq!(content.author_url, Some(url("https://alice.example/")));
+
+        let text = "see https://bob.example/post and https://spam.example/";
+        let content = SourceContent::from_response(&response("text/plain", text), &target);
+        assert_eq!(content.kind, SourceKind::PlainText);
+        assert_eq!(content.links, 2);
+        assert_eq!(content.text, text);
+    }
+
+    #[test]
+    fn test_heuristic_filter() {
+        let target = url("https://bob.example/post");
+        let filter = HeuristicFilter::default();
+
+        let reply = SourceContent::from_response(&response("text/html", REPLY), &target);
+        let score = filter.score(&reply);
+        assert_eq!(score.verdict, Verdict::Ham, "{:?}", score);
+
+        let links: String = (0..20)
+            .map(|i| format!("<a href=\"https://shop{}.example/\">cheap</a> ", i))
+            .collect();
+        let spam = format!(
+            "<p>Best online casino and replica watches {}</p><a href=\"{}\">x</a>",
+            links, target
+        );
+        let spam = SourceContent::from_response(&response("text/html", &spam), &target);
+        let score = block_on(filter.check(&spam, None)).unwrap();
+        assert_eq!(score.verdict, Verdict::Spam);
+        assert_eq!(score.score, 1.0);
+        assert!(score
+            .reasons
+            .contains(&"contains \"online casino\"".to_string()));
+
+        let bare = format!("<p>Nice <a href=\"{}\">post</a></p>", target);
+        let bare = SourceContent::from_response(&response("text/html", &bare), &target);
+        assert_eq!(filter.score(&bare).verdict, Verdict::Uncertain);
+    }
+
+    #[test]
+    fn test_akismet_filter() {
+        let endpoint = serve_sequence(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\ntrue"
+                .to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nfalse"
+                .to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 7\r\n\r\ninvalid"
+                .to_vec(),
+        ]);
+        let filter = AkismetFilter {
+            client: local_client(1024),
+            ..AkismetFilter::with_endpoint(endpoint, url("https://bob.example/"))
+        };
+        let content = SourceContent::from_response(
+            &response("text/html", REPLY),
+            &url("https://bob.example/post"),
+        );
+        let remote = Some("192.0.2.1".parse().unwrap());
+
+        let spam = block_on(filter.check(&content, remote)).unwrap();
+        assert_eq!((spam.score, spam.verdict), (1.0, Verdict::Spam));
+        let ham = block_on(filter.check(&content, remote)).unwrap();
+        assert_eq!(ham.verdict, Verdict::Ham);
+        assert!(block_on(filter.check(&content, remote)).is_err());
+    }
+}
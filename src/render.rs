@@ -0,0 +1,478 @@
+use crate::error::WebmentionError;
+use crate::snapshot::{escape, MentionKind};
+use crate::timestamp;
+use crate::webmention::{Moderation, Webmention};
+use crate::wm_url::{Url, UrlEquivalence};
+use std::path::Path;
+
+/// Kinds of responses shown as facepiles, in order, with their labels for one and for many
+const FACEPILE_KINDS: [(MentionKind, &str, &str, &str); 3] = [
+    (MentionKind::Like, "like", "like", "likes"),
+    (MentionKind::Repost, "repost", "repost", "reposts"),
+    (MentionKind::Bookmark, "bookmark", "bookmark", "bookmarks"),
+];
+
+/// HTML templates of rendered webmentions.
+///
+/// Templates are plain HTML with placeholders in braces, such as `{author_name}`. Values are
+/// escaped, except for `{content}`, which is sanitized when the source is fetched, and
+/// placeholders filled with other rendered templates. Unknown placeholders are left as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Templates {
+    /// The whole fragment: `{facepiles}`, `{replies}`
+    pub container: String,
+    /// Faces of likes, reposts or bookmarks: `{kind}`, `{label}` (such as `like` or `likes`,
+    /// according to `{count}`), `{count}`, `{faces}`
+    pub facepile: String,
+    /// A single face in a facepile: `{author_name}`, `{author_url}`, `{avatar}`, `{source}`
+    pub face: String,
+    /// Photo of an author: `{author_photo}`, `{author_name}`
+    pub photo: String,
+    /// List of replies and mentions, at the top level and under each reply: `{replies}`
+    pub replies: String,
+    /// A reply: `{kind}`, `{author}`, `{content}`, `{summary}`, `{source}`, `{published}`,
+    /// `{date}`, `{children}`
+    pub reply: String,
+    /// A mention, which has the same placeholders as a reply
+    pub mention: String,
+    /// Author of a reply or a mention: `{author_name}`, `{author_url}`, `{photo}`, `{avatar}`
+    pub author: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            container: "<section class=\"webmentions\">\n{facepiles}{replies}</section>\n"
+                .to_string(),
+            facepile: "<div class=\"webmention-facepile webmention-{kind}s\">\n\
+                       <h3>{count} {label}</h3>\n<ul>\n{faces}</ul>\n</div>\n"
+                .to_string(),
+            face: "<li><a class=\"h-card u-url\" href=\"{author_url}\" title=\"{author_name}\">\
+                   {avatar}</a></li>\n"
+                .to_string(),
+            photo: "<img class=\"u-photo\" src=\"{author_photo}\" alt=\"{author_name}\" \
+                    loading=\"lazy\">"
+                .to_string(),
+            replies: "<ol class=\"webmention-replies\">\n{replies}</ol>\n".to_string(),
+            reply: "<li class=\"webmention-{kind} h-cite\">\n{author}\n\
+                    <div class=\"e-content\">{content}</div>\n\
+                    <a class=\"u-url\" href=\"{source}\">\
+                    <time class=\"dt-published\" datetime=\"{published}\">{date}</time></a>\n\
+                    {children}</li>\n"
+                .to_string(),
+            mention: "<li class=\"webmention-{kind} h-cite\">\n{author} \
+                      <a class=\"u-url\" href=\"{source}\">mentioned this</a> \
+                      <time class=\"dt-published\" datetime=\"{published}\">{date}</time>\n\
+                      <p class=\"p-summary\">{summary}</p>\n{children}</li>\n"
+                .to_string(),
+            author: "<a class=\"p-author h-card\" href=\"{author_url}\">{photo}\
+                     <span class=\"p-name\">{author_name}</span></a>"
+                .to_string(),
+        }
+    }
+}
+
+impl Templates {
+    /// Default templates, replaced by `container.html`, `facepile.html`, `face.html`,
+    /// `photo.html`, `replies.html`, `reply.html`, `mention.html` and `author.html` of `dir`
+    /// where those exist.
+    pub fn read_dir(dir: impl AsRef<Path>) -> Result<Templates, WebmentionError> {
+        let dir = dir.as_ref();
+        let mut templates = Templates::default();
+        for (name, template) in [
+            ("container", &mut templates.container),
+            ("facepile", &mut templates.facepile),
+            ("face", &mut templates.face),
+            ("photo", &mut templates.photo),
+            ("replies", &mut templates.replies),
+            ("reply", &mut templates.reply),
+            ("mention", &mut templates.mention),
+            ("author", &mut templates.author),
+        ] {
+            let path = dir.join(format!("{}.html", name));
+            match std::fs::read_to_string(&path) {
+                Ok(text) => *template = text,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(source) => return Err(WebmentionError::Io { path, source }),
+            }
+        }
+        Ok(templates)
+    }
+}
+
+/// Renders webmentions of a target as an HTML fragment: facepiles of likes, reposts and
+/// bookmarks, followed by replies and mentions, where replies to replies are nested under
+/// the replies they respond to.
+///
+/// Only approved webmentions are rendered by default. A [Receiver](crate::receiver::Receiver)
+/// without an allowlist or a spam filter stores every webmention as
+/// [pending](Moderation::Pending), so nothing is rendered until they are moderated or
+/// `include_pending` is set.
+#[derive(Debug, Clone, Default)]
+pub struct Renderer {
+    pub templates: Templates,
+    /// Render webmentions that are not [approved](Moderation::Approved) yet. Spam is never
+    /// rendered.
+    pub include_pending: bool,
+    /// How sources are compared, to find the same source and replies to replies
+    pub equivalence: UrlEquivalence,
+}
+
+/// Author of a webmention as it is shown
+struct Card {
+    name: String,
+    url: Url,
+    photo: Option<Url>,
+}
+
+/// A webmention as it is shown
+struct Item<'a> {
+    mention: &'a Webmention,
+    kind: MentionKind,
+    /// `published`, or the date the source was fetched
+    published: String,
+}
+
+impl Renderer {
+    /// Renders `mentions`, all of the same target, usually from
+    /// [lookup_by_target](crate::storage::WebmentionStorage::lookup_by_target). Of several
+    /// webmentions from the same source, the last one is rendered. Returns an empty string if
+    /// there is nothing to render.
+    pub fn render(&self, mentions: &[Webmention]) -> String {
+        let mut items: Vec<Item> = Vec::new();
+        for mention in mentions.iter() {
+            items.retain(|item| {
+                !self
+                    .equivalence
+                    .equivalent(&item.mention.source, &mention.source)
+            });
+            let shown = match mention.moderation {
+                Moderation::Approved => true,
+                Moderation::Pending => self.include_pending,
+                Moderation::Spam => false,
+            };
+            if shown {
+                items.push(Item::new(mention));
+            }
+        }
+        if items.is_empty() {
+            return String::new();
+        }
+        items.sort_by(|a, b| a.published.cmp(&b.published));
+
+        let mut facepiles = String::new();
+        for (kind, name, one, many) in FACEPILE_KINDS.iter() {
+            let faces: String = items
+                .iter()
+                .filter(|item| item.kind == *kind)
+                .map(|item| self.face(item))
+                .collect();
+            if !faces.is_empty() {
+                let count = items.iter().filter(|item| item.kind == *kind).count();
+                let label = if count == 1 { one } else { many };
+                facepiles.push_str(&fill(
+                    &self.templates.facepile,
+                    &[
+                        ("kind", name),
+                        ("label", label),
+                        ("count", &count.to_string()),
+                        ("faces", &faces),
+                    ],
+                ));
+            }
+        }
+
+        let threaded: Vec<&Item> = items
+            .iter()
+            .filter(|item| FACEPILE_KINDS.iter().all(|(kind, ..)| item.kind != *kind))
+            .collect();
+        let parents = parents(&threaded, &self.equivalence);
+        let replies = self.replies(&threaded, &parents, None);
+
+        fill(
+            &self.templates.container,
+            &[("facepiles", &facepiles), ("replies", &replies)],
+        )
+    }
+
+    fn face(&self, item: &Item) -> String {
+        let author = item.author();
+        fill(
+            &self.templates.face,
+            &[
+                ("author_name", &escape(&author.name)),
+                ("author_url", &escape(author.url.as_str())),
+                ("avatar", &self.avatar(&author)),
+                ("source", &escape(item.mention.source.as_str())),
+            ],
+        )
+    }
+
+    /// Rendered list of the items whose parent is `parent`, or an empty string if there are none
+    fn replies(&self, items: &[&Item], parents: &[Option<usize>], parent: Option<usize>) -> String {
+        let replies: String = items
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| parents[*index] == parent)
+            .map(|(index, item)| self.reply(item, self.replies(items, parents, Some(index))))
+            .collect();
+        if replies.is_empty() {
+            replies
+        } else {
+            fill(&self.templates.replies, &[("replies", &replies)])
+        }
+    }
+
+    fn reply(&self, item: &Item, children: String) -> String {
+        let author = item.author();
+        let author = fill(
+            &self.templates.author,
+            &[
+                ("author_name", &escape(&author.name)),
+                ("author_url", &escape(author.url.as_str())),
+                ("photo", &self.photo(&author)),
+                ("avatar", &self.avatar(&author)),
+            ],
+        );
+        let snapshot = item.mention.snapshot.as_ref();
+        let (template, kind) = match item.kind {
+            MentionKind::Reply => (&self.templates.reply, "reply"),
+            _ => (&self.templates.mention, "mention"),
+        };
+        fill(
+            template,
+            &[
+                ("kind", kind),
+                ("author", &author),
+                ("content", snapshot.map_or("", |s| s.content.as_str())),
+                (
+                    "summary",
+                    &escape(snapshot.map_or("", |s| s.summary.as_str())),
+                ),
+                ("source", &escape(item.mention.source.as_str())),
+                ("published", &escape(&item.published)),
+                (
+                    "date",
+                    &escape(item.published.get(..10).unwrap_or(&item.published)),
+                ),
+                ("children", &children),
+            ],
+        )
+    }
+
+    fn photo(&self, author: &Card) -> String {
+        match &author.photo {
+            Some(photo) => fill(
+                &self.templates.photo,
+                &[
+                    ("author_photo", &escape(photo.as_str())),
+                    ("author_name", &escape(&author.name)),
+                ],
+            ),
+            None => String::new(),
+        }
+    }
+
+    /// Photo of the author, or their name if there is no photo
+    fn avatar(&self, author: &Card) -> String {
+        match author.photo {
+            Some(_) => self.photo(author),
+            None => escape(&author.name),
+        }
+    }
+}
+
+impl<'a> Item<'a> {
+    fn new(mention: &'a Webmention) -> Item<'a> {
+        let snapshot = mention.snapshot.as_ref();
+        Item {
+            mention,
+            kind: snapshot.map(|s| s.kind).unwrap_or_default(),
+            published: snapshot
+                .and_then(|s| s.published.clone())
+                .or_else(|| snapshot.map(|s| timestamp::format(s.fetched_at)))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Name, URL and photo of the author, falling back to the host and URL of the source
+    fn author(&self) -> Card {
+        let author = self
+            .mention
+            .snapshot
+            .as_ref()
+            .and_then(|s| s.author.clone())
+            .unwrap_or_default();
+        let url = author.url.unwrap_or_else(|| self.mention.source.clone());
+        let name = author
+            .name
+            .or_else(|| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| url.to_string());
+        Card {
+            name,
+            url,
+            photo: author.photo,
+        }
+    }
+}
+
+/// Index of the item each item replies to, if it is among the earlier items
+fn parents(items: &[&Item], equivalence: &UrlEquivalence) -> Vec<Option<usize>> {
+    let sources: Vec<Url> = items
+        .iter()
+        .map(|item| equivalence.normalize(&item.mention.source))
+        .collect();
+    items
+        .iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let in_reply_to = item.mention.snapshot.as_ref()?.in_reply_to.iter();
+            let in_reply_to: Vec<Url> = in_reply_to.map(|url| equivalence.normalize(url)).collect();
+            sources[..index]
+                .iter()
+                .position(|source| in_reply_to.contains(source))
+        })
+        .collect()
+}
+
+/// Replaces `{name}` in `template` with values of `values`, in a single pass, so placeholders
+/// in values are not replaced.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Renders `mentions` with default templates, see [Renderer::render].
+pub fn render(mentions: &[Webmention]) -> String {
+    Renderer::default().render(mentions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fill, Renderer, Templates};
+    use crate::snapshot::{Author, MentionKind, Snapshot};
+    use crate::webmention::{Moderation, Webmention};
+    use crate::wm_url::Url;
+    use std::time::UNIX_EPOCH;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn mention(source: &str, kind: MentionKind, published: &str) -> Webmention {
+        let mut mention = Webmention::from((url(source), url("https://bob.example/post")));
+        mention.moderation = Moderation::Approved;
+        mention.snapshot = Some(Snapshot {
+            content: format!("<p>{}</p>", source),
+            summary: source.to_string(),
+            fetched_at: UNIX_EPOCH,
+            etag: None,
+            kind,
+            author: Some(Author {
+                name: Some(format!("<{}>", url(source).host_str().unwrap())),
+                url: None,
+                photo: None,
+            }),
+            published: Some(published.to_string()),
+            in_reply_to: Vec::new(),
+        });
+        mention
+    }
+
+    #[test]
+    fn test_render() {
+        let mut like = mention(
+            "https://alice.example/like",
+            MentionKind::Like,
+            "2024-01-01",
+        );
+        like.snapshot
+            .as_mut()
+            .unwrap()
+            .author
+            .as_mut()
+            .unwrap()
+            .photo = Some(url("https://alice.example/me.jpg"));
+        let reply = mention(
+            "https://carol.example/r",
+            MentionKind::Reply,
+            "2024-01-02T10:00",
+        );
+        let mut nested = mention("https://dave.example/r", MentionKind::Reply, "2024-01-03");
+        nested.snapshot.as_mut().unwrap().in_reply_to = vec![url("https://carol.example/r")];
+        let mut spam = mention("https://spam.example/", MentionKind::Mention, "2024-01-01");
+        spam.moderation = Moderation::Spam;
+        let mut pending = mention("https://erin.example/", MentionKind::Mention, "2024-01-04");
+        pending.moderation = Moderation::Pending;
+
+        let mut other_like = mention("https://bob.example/like", MentionKind::Like, "2024-01-01");
+        other_like.moderation = Moderation::Pending;
+
+        let mentions = vec![nested, spam, pending, reply, like, other_like];
+        let html = Renderer::default().render(&mentions);
+        assert!(html.contains("<h3>1 like</h3>"));
+        assert!(html.contains(r#"<img class="u-photo" src="https://alice.example/me.jpg""#));
+        assert!(html.contains("&lt;carol.example&gt;"));
+        assert!(!html.contains("<carol.example>"));
+        assert!(html.contains(r#"datetime="2024-01-02T10:00">2024-01-02</time>"#));
+        assert!(!html.contains("spam.example"));
+        assert!(!html.contains("erin.example"));
+        // the nested reply is in the list under the reply it responds to
+        let carol = html.find("carol.example/r").unwrap();
+        let dave = html.find("dave.example/r").unwrap();
+        assert!(carol < dave);
+        assert_eq!(html[carol..dave].matches("<ol").count(), 1);
+
+        let with_pending = Renderer {
+            include_pending: true,
+            ..Renderer::default()
+        }
+        .render(&mentions);
+        assert!(with_pending.contains("<h3>2 likes</h3>"));
+
+        let renderer = Renderer {
+            include_pending: true,
+            templates: Templates {
+                container: "{replies}".to_string(),
+                replies: "{replies}".to_string(),
+                mention: "{source} {kind}\n".to_string(),
+                ..Templates::default()
+            },
+            ..Renderer::default()
+        };
+        let pending = &mentions[2..3];
+        assert_eq!(renderer.render(pending), "https://erin.example/ mention\n");
+        assert_eq!(Renderer::default().render(pending), "");
+    }
+
+    #[test]
+    fn test_fill() {
+        assert_eq!(
+            fill("{a} {b} {c} {", &[("a", "{b}"), ("b", "2")]),
+            "{b} 2 {c} {"
+        );
+    }
+}
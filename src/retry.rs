@@ -0,0 +1,178 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime};
+
+/// How many times and how often failed requests are repeated.
+///
+/// A request is repeated when it fails with a [retryable](crate::WebmentionError::is_retryable)
+/// error or with a [retryable status](RetryPolicy::is_retryable_status). The delay before the
+/// next attempt grows exponentially from `initial_backoff` up to `max_backoff`, unless the server
+/// asks for a specific delay with `Retry-After`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one. `1` means no retries.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Randomize delays, so that many clients failed at once do not retry at once
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Whether a response with `status` is worth repeating: `408 Request Timeout`,
+    /// `429 Too Many Requests` and server errors except `501 Not Implemented`.
+    pub fn is_retryable_status(status: StatusCode) -> bool {
+        status == StatusCode::REQUEST_TIMEOUT
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || (status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED)
+    }
+
+    /// Delay before attempt number `attempt + 1`, where `attempt` starts from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+
+        if self.jitter {
+            // "equal jitter": at least half of the delay, so that retries do not come too early
+            let half = delay / 2;
+            half + half.mul_f64(random_fraction())
+        } else {
+            delay
+        }
+    }
+
+    /// Delay before the next attempt after `attempt` has failed, or `None` if we should give up:
+    /// either attempts are exhausted, or the server asks to wait longer than `max_backoff`.
+    pub(crate) fn next_delay(
+        &self,
+        attempt: u32,
+        retry_after: Option<Duration>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_backoff => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+}
+
+/// Parses `Retry-After`, which is either a number of seconds or an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            )
+        }
+    }
+}
+
+/// Random number in `[0, 1)`, good enough for jitter
+fn random_fraction() -> f64 {
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{retry_after, RetryPolicy};
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            jitter: false,
+        };
+        let delays: Vec<u64> = (1..6).map(|n| policy.backoff(n).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10]);
+        assert_eq!(policy.backoff(1000), Duration::from_secs(10));
+
+        let jittered = RetryPolicy {
+            jitter: true,
+            ..policy.clone()
+        };
+        for _ in 0..100 {
+            let delay = jittered.backoff(3);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+
+        assert_eq!(policy.next_delay(10, None), None);
+        assert_eq!(
+            policy.next_delay(1, Some(Duration::from_secs(5))),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(policy.next_delay(1, Some(Duration::from_secs(60))), None);
+        assert_eq!(RetryPolicy::none().next_delay(1, None), None);
+    }
+
+    #[test]
+    fn test_retryable_status() {
+        assert!(RetryPolicy::is_retryable_status(StatusCode::BAD_GATEWAY));
+        assert!(RetryPolicy::is_retryable_status(
+            StatusCode::SERVICE_UNAVAILABLE
+        ));
+        assert!(RetryPolicy::is_retryable_status(
+            StatusCode::TOO_MANY_REQUESTS
+        ));
+        assert!(!RetryPolicy::is_retryable_status(
+            StatusCode::NOT_IMPLEMENTED
+        ));
+        assert!(!RetryPolicy::is_retryable_status(StatusCode::BAD_REQUEST));
+        assert!(!RetryPolicy::is_retryable_status(StatusCode::OK));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers), None);
+    }
+}
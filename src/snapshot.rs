@@ -0,0 +1,549 @@
+use crate::html::{visible_text, HTML};
+use crate::http_client::Response;
+use crate::verification::SourceKind;
+use crate::wm_url::{absolute_url, Url, UrlEquivalence};
+use reqwest::header::ETAG;
+use select::node::Node;
+use select::predicate::{Class, Name};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// Value of `rel` of every link in sanitized content
+#[cfg(feature = "receiver")]
+const LINK_REL: &str = "nofollow ugc noopener noreferrer";
+
+/// URL schemes that links and images of sanitized content may use
+#[cfg(feature = "receiver")]
+const URL_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+/// Properties of an `h-entry` that make it a response of a particular kind, in order of
+/// precedence
+const KIND_PROPERTIES: [(&str, MentionKind); 4] = [
+    ("like-of", MentionKind::Like),
+    ("repost-of", MentionKind::Repost),
+    ("bookmark-of", MentionKind::Bookmark),
+    ("in-reply-to", MentionKind::Reply),
+];
+
+/// How snapshots of sources are made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotOptions {
+    /// Characters of the summary, including the ellipsis of a truncated one
+    pub max_summary_length: usize,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions {
+            max_summary_length: 280,
+        }
+    }
+}
+
+/// What the source of a webmention looked like when it was verified, so that the mention can be
+/// displayed without fetching the source again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Sanitized HTML of the first `h-entry` of the source, or of its whole `<body>`.
+    /// Safe to embed into a page. Without `receiver` feature, only the text of HTML is kept.
+    pub content: String,
+    /// Plain text of `content`, truncated to [max_summary_length](SnapshotOptions)
+    pub summary: String,
+    pub fetched_at: SystemTime,
+    /// `ETag` of the fetched source, for checking later whether it has changed
+    pub etag: Option<String>,
+    /// What the source is in relation to the target
+    #[serde(default)]
+    pub kind: MentionKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<Author>,
+    /// `published` of the source as it is written there, usually in ISO 8601
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+    /// Posts the source replies to, which makes it possible to thread replies to replies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub in_reply_to: Vec<Url>,
+}
+
+/// Kind of response to the target, according to the microformats of the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MentionKind {
+    /// Just links to the target
+    #[default]
+    Mention,
+    /// `in-reply-to` the target
+    Reply,
+    /// `like-of` the target
+    Like,
+    /// `repost-of` the target
+    Repost,
+    /// `bookmark-of` the target
+    Bookmark,
+}
+
+impl MentionKind {
+    /// Property of the source that links to the target, as in `wm-property` of webmention.io
+    pub fn property(self) -> &'static str {
+        match self {
+            MentionKind::Mention => "mention-of",
+            MentionKind::Reply => "in-reply-to",
+            MentionKind::Like => "like-of",
+            MentionKind::Repost => "repost-of",
+            MentionKind::Bookmark => "bookmark-of",
+        }
+    }
+
+    /// Kind of a source that links to the target with `property`. RSVPs are replies, and
+    /// unknown properties are mentions.
+    pub fn from_property(property: &str) -> MentionKind {
+        match property {
+            "in-reply-to" | "rsvp" => MentionKind::Reply,
+            "like-of" => MentionKind::Like,
+            "repost-of" => MentionKind::Repost,
+            "bookmark-of" => MentionKind::Bookmark,
+            _ => MentionKind::Mention,
+        }
+    }
+}
+
+/// Author of a source, from its `h-card`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Author {
+    pub name: Option<String>,
+    pub url: Option<Url>,
+    pub photo: Option<Url>,
+}
+
+/// Microformats of a source that tell who wrote it, when and in response to what
+#[derive(Debug, Default)]
+struct Entry {
+    kinds: Vec<(MentionKind, Vec<Url>)>,
+    author: Option<Author>,
+    published: Option<String>,
+}
+
+impl Entry {
+    fn kind(&self, target: &Url) -> MentionKind {
+        let equivalence = UrlEquivalence::default();
+        let target = equivalence.normalize(target);
+        self.kinds
+            .iter()
+            .find(|(_, urls)| urls.iter().any(|url| equivalence.normalize(url) == target))
+            .map(|(kind, _)| *kind)
+            .unwrap_or_default()
+    }
+
+    fn in_reply_to(&self) -> Vec<Url> {
+        self.kinds
+            .iter()
+            .find(|(kind, _)| *kind == MentionKind::Reply)
+            .map(|(_, urls)| urls.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl Snapshot {
+    /// Makes a snapshot of the source fetched as `response`, which mentions `target`, at the
+    /// current time.
+    ///
+    /// HTML sources are sanitized (reduced to text without `receiver` feature),
+    /// plain text is escaped, and the `content` of an mf2 JSON
+    /// source is used. Feeds have no single entry to show, so their snapshot is empty.
+    /// Kind, author and date come from microformats of HTML and mf2 JSON sources.
+    pub fn from_response(response: &Response, target: &Url, options: &SnapshotOptions) -> Snapshot {
+        let (content, entry) = match SourceKind::from_content_type(response.content_type.as_deref())
+        {
+            SourceKind::Html => (
+                response
+                    .html
+                    .main_content()
+                    .map(|main| sanitize(&main.inner_html(), &response.html.base_url()))
+                    .unwrap_or_default(),
+                html_entry(&response.html),
+            ),
+            SourceKind::Json => {
+                let item = serde_json::from_str::<serde_json::Value>(response.html.raw())
+                    .ok()
+                    .and_then(|json| json_item(&json).cloned());
+                match item {
+                    Some(item) => (
+                        json_content(&item)
+                            .map(|html| sanitize(&html, &response.url))
+                            .unwrap_or_default(),
+                        json_entry(&item, &response.url),
+                    ),
+                    None => (String::new(), Entry::default()),
+                }
+            }
+            SourceKind::PlainText => (
+                format!("<p>{}</p>", escape(response.html.raw().trim())),
+                Entry::default(),
+            ),
+            SourceKind::Feed => (String::new(), Entry::default()),
+        };
+        let sanitized = HTML::new(response.url.clone(), content.clone());
+        let summary = sanitized
+            .doc()
+            .ok()
+            .and_then(|doc| doc.find(Name("body")).next())
+            .map(|body| visible_text(&body))
+            .unwrap_or_default();
+
+        Snapshot {
+            content,
+            summary: truncate(&summary, options.max_summary_length),
+            fetched_at: SystemTime::now(),
+            etag: response
+                .headers
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(str::to_string),
+            kind: entry.kind(target),
+            in_reply_to: entry.in_reply_to(),
+            author: entry.author,
+            published: entry.published,
+        }
+    }
+}
+
+/// Author of the document: `p-author` of its `h-entry`, or its first `h-card`.
+pub(crate) fn find_author(html: &HTML) -> Option<Author> {
+    let author = html
+        .entry()
+        .and_then(|entry| entry.find(Class("p-author")).next())
+        .or_else(|| html.doc().ok()?.find(Class("h-card")).next())?;
+    let base = html.base_url();
+    let url = |href: Option<&str>| href.and_then(|href| absolute_url(href.trim(), &base).ok());
+
+    let name = author
+        .find(Class("p-name"))
+        .next()
+        .map(|name| visible_text(&name))
+        .unwrap_or_else(|| visible_text(&author));
+    Some(Author {
+        name: Some(name).filter(|name| !name.is_empty()),
+        url: url(author
+            .attr("href")
+            .or_else(|| author.find(Class("u-url")).next()?.attr("href"))),
+        photo: url(author
+            .find(Class("u-photo"))
+            .next()
+            .or_else(|| author.find(Name("img")).next())
+            .and_then(|photo| photo.attr("src"))),
+    })
+}
+
+fn html_entry(html: &HTML) -> Entry {
+    let entry = match html.entry() {
+        Some(entry) => entry,
+        None => {
+            return Entry {
+                author: find_author(html),
+                ..Entry::default()
+            }
+        }
+    };
+    let base = html.base_url();
+    let urls = |property: &str| -> Vec<Url> {
+        entry
+            .find(Class(format!("u-{}", property).as_str()))
+            .filter_map(|node: Node| {
+                node.attr("href")
+                    .or_else(|| node.find(Class("u-url")).next()?.attr("href"))
+                    .and_then(|href| absolute_url(href.trim(), &base).ok())
+            })
+            .collect()
+    };
+
+    Entry {
+        kinds: KIND_PROPERTIES
+            .iter()
+            .map(|(property, kind)| (*kind, urls(property)))
+            .collect(),
+        author: find_author(html),
+        published: entry.find(Class("dt-published")).next().and_then(|node| {
+            node.attr("datetime")
+                .map(str::to_string)
+                .or_else(|| Some(visible_text(&node)).filter(|text| !text.is_empty()))
+        }),
+    }
+}
+
+/// The first item of an mf2 JSON document, or the document itself if it is a single item
+fn json_item(json: &serde_json::Value) -> Option<&serde_json::Value> {
+    match json.get("items") {
+        Some(items) => items.get(0),
+        None => Some(json),
+    }
+}
+
+/// String values of a property of an mf2 item: values themselves, or `url` or `value` of
+/// embedded items
+fn json_values<'a>(item: &'a serde_json::Value, property: &str) -> Vec<&'a str> {
+    let values = match item
+        .get("properties")
+        .and_then(|properties| properties.get(property))
+        .and_then(|values| values.as_array())
+    {
+        Some(values) => values,
+        None => return Vec::new(),
+    };
+    values
+        .iter()
+        .filter_map(|value| {
+            value.as_str().or_else(|| {
+                json_values(value, "url")
+                    .first()
+                    .copied()
+                    .or_else(|| value.get("value")?.as_str())
+            })
+        })
+        .collect()
+}
+
+fn json_entry(item: &serde_json::Value, base: &Url) -> Entry {
+    let urls = |property: &str| -> Vec<Url> {
+        json_values(item, property)
+            .into_iter()
+            .filter_map(|url| absolute_url(url.trim(), base).ok())
+            .collect()
+    };
+    let author = item
+        .get("properties")
+        .and_then(|properties| properties.get("author"))
+        .and_then(|authors| authors.get(0))
+        .map(|author| match author.as_str() {
+            Some(url) => Author {
+                url: absolute_url(url.trim(), base).ok(),
+                ..Author::default()
+            },
+            None => Author {
+                name: json_values(author, "name")
+                    .first()
+                    .map(|name| name.to_string()),
+                url: json_values(author, "url")
+                    .first()
+                    .and_then(|url| absolute_url(url.trim(), base).ok()),
+                photo: json_values(author, "photo")
+                    .first()
+                    .and_then(|url| absolute_url(url.trim(), base).ok()),
+            },
+        });
+
+    Entry {
+        kinds: KIND_PROPERTIES
+            .iter()
+            .map(|(property, kind)| (*kind, urls(property)))
+            .collect(),
+        author,
+        published: json_values(item, "published")
+            .first()
+            .map(|published| published.to_string()),
+    }
+}
+
+/// Removes everything but a safe subset of HTML: no scripts, styles, event handlers or unknown
+/// URL schemes. Relative links are resolved against `base`, and every link gets
+/// `rel="nofollow ugc noopener noreferrer"`.
+#[cfg(feature = "receiver")]
+pub fn sanitize(html: &str, base: &Url) -> String {
+    ammonia::Builder::default()
+        .url_schemes(URL_SCHEMES.iter().copied().collect())
+        .url_relative(ammonia::UrlRelative::RewriteWithBase(base.clone()))
+        .link_rel(Some(LINK_REL))
+        .clean(html)
+        .to_string()
+}
+
+/// Keeps only the visible text of `html`, as there is no sanitizer without `receiver` feature.
+#[cfg(not(feature = "receiver"))]
+fn sanitize(html: &str, base: &Url) -> String {
+    let html = HTML::new(base.clone(), format!("<body>{}</body>", html));
+    let text = html
+        .doc()
+        .ok()
+        .and_then(|doc| doc.find(Name("body")).next())
+        .map(|body| visible_text(&body))
+        .unwrap_or_default();
+    format!("<p>{}</p>", escape(text.trim()))
+}
+
+/// Shortens `text` to at most `max_length` characters, at a word boundary if there is one, and
+/// marks it as shortened with an ellipsis.
+pub fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    if max_length == 0 {
+        return String::new();
+    }
+    let end = text
+        .char_indices()
+        .nth(max_length - 1)
+        .map(|(index, _)| index)
+        .unwrap_or(text.len());
+    let cut = &text[..end];
+    let at_boundary = text[end..].starts_with(char::is_whitespace);
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(space) if space > 0 && !at_boundary => &cut[..space],
+        _ => cut,
+    };
+    format!("{}…", cut.trim_end())
+}
+
+/// Escapes text to be shown as HTML
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// HTML of the `content` of an mf2 item
+fn json_content(item: &serde_json::Value) -> Option<String> {
+    let content = item.get("properties")?.get("content")?.get(0)?;
+    match content {
+        serde_json::Value::String(text) => Some(escape(text)),
+        content => content
+            .get("html")
+            .and_then(|html| html.as_str())
+            .map(str::to_string)
+            .or_else(|| Some(escape(content.get("value")?.as_str()?))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{truncate, Author, MentionKind, Snapshot, SnapshotOptions};
+    use crate::http_client::test::response;
+    use crate::wm_url::Url;
+
+    fn target() -> Url {
+        Url::parse("https://bob.example/post").unwrap()
+    }
+
+    #[test]
+    fn test_html_snapshot() {
+        let body = r#"<html><head><title>Reply</title></head><body>
+            <nav><a href="/">Home</a></nav>
+            <article class="h-entry" onclick="steal()">
+              <p class="e-content">Nice <a href="/post" style="color: red">post</a>!
+              <script>alert(1)</script><img src="x.png" onerror="steal()">
+              <a href="javascript:steal()">click</a></p>
+            </article></body></html>"#;
+        let snapshot = Snapshot::from_response(
+            &response("text/html", body),
+            &target(),
+            &SnapshotOptions::default(),
+        );
+
+        assert!(!snapshot.content.contains("Home"));
+        assert!(!snapshot.content.contains("script"));
+        assert!(!snapshot.content.contains("steal"));
+        assert!(!snapshot.content.contains("style"));
+        if cfg!(feature = "receiver") {
+            assert!(snapshot.content.contains(
+                r#"<a href="https://alice.example/post" rel="nofollow ugc noopener noreferrer">post</a>"#
+            ));
+            assert!(snapshot
+                .content
+                .contains(r#"<img src="https://alice.example/notes/x.png">"#));
+        } else {
+            assert_eq!(snapshot.content, "<p>Nice post! click</p>");
+        }
+        assert_eq!(snapshot.summary, "Nice post! click");
+        assert_eq!(snapshot.etag.as_deref(), Some("\"v1\""));
+    }
+
+    #[test]
+    fn test_other_snapshots() {
+        let options = SnapshotOptions {
+            max_summary_length: 12,
+        };
+        let text = Snapshot::from_response(
+            &response("text/plain", "<b>see</b> https://bob.example/post"),
+            &target(),
+            &options,
+        );
+        assert_eq!(
+            text.content,
+            "<p>&lt;b&gt;see&lt;/b&gt; https://bob.example/post</p>"
+        );
+        assert_eq!(text.summary, "<b>see</b>…");
+
+        let json = r#"{"items": [{"type": ["h-entry"], "properties": {"content": [
+            {"html": "<p>Hi <script>x</script>Bob</p>", "value": "Hi Bob"}]}}]}"#;
+        let json =
+            Snapshot::from_response(&response("application/mf2+json", json), &target(), &options);
+        assert_eq!(json.content, "<p>Hi Bob</p>");
+        assert_eq!(json.summary, "Hi Bob");
+
+        let feed = Snapshot::from_response(
+            &response("application/atom+xml", "<feed/>"),
+            &target(),
+            &options,
+        );
+        assert_eq!((feed.content.as_str(), feed.summary.as_str()), ("", ""));
+    }
+
+    #[test]
+    fn test_entry_metadata() {
+        let options = SnapshotOptions::default();
+        let reply = r#"<div class="h-entry">
+            <a class="p-author h-card" href="/"><img class="u-photo" src="/me.jpg">Alice</a>
+            <a class="u-in-reply-to" href="https://bob.example/post/">Bob's post</a>
+            <a class="u-in-reply-to" href="https://carol.example/reply">Carol's reply</a>
+            <time class="dt-published" datetime="2024-03-01T10:00:00Z">March 1</time>
+            <p class="e-content">Agreed!</p></div>"#;
+        let reply = Snapshot::from_response(&response("text/html", reply), &target(), &options);
+        assert_eq!(reply.kind, MentionKind::Reply);
+        assert_eq!(reply.in_reply_to.len(), 2);
+        assert_eq!(reply.published.as_deref(), Some("2024-03-01T10:00:00Z"));
+        assert_eq!(
+            reply.author,
+            Some(Author {
+                name: Some("Alice".to_string()),
+                url: Some(Url::parse("https://alice.example/").unwrap()),
+                photo: Some(Url::parse("https://alice.example/me.jpg").unwrap()),
+            })
+        );
+
+        // liking something else while linking to the target is a mention
+        let like = r#"<div class="h-entry"><a class="u-like-of" href="https://other.example/">
+            </a><a href="https://bob.example/post">via</a></div>"#;
+        let like = Snapshot::from_response(&response("text/html", like), &target(), &options);
+        assert_eq!(like.kind, MentionKind::Mention);
+
+        let json = r#"{"type": ["h-entry"], "properties": {
+            "repost-of": [{"type": ["h-cite"], "properties": {"url": ["https://bob.example/post"]}}],
+            "author": [{"type": ["h-card"], "properties": {"name": ["Dave"], "url": ["/"]}}],
+            "published": ["2024-03-02"]}}"#;
+        let json =
+            Snapshot::from_response(&response("application/json", json), &target(), &options);
+        assert_eq!(json.kind, MentionKind::Repost);
+        assert_eq!(json.published.as_deref(), Some("2024-03-02"));
+        let author = json.author.unwrap();
+        assert_eq!(author.name.as_deref(), Some("Dave"));
+        assert_eq!(
+            author.url,
+            Some(Url::parse("https://alice.example/").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("two words here", 10), "two words…");
+        assert_eq!(truncate("unbreakableword", 5), "unbr…");
+        assert_eq!(truncate("привет мир", 8), "привет…");
+        assert_eq!(truncate("anything", 0), "");
+    }
+}
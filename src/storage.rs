@@ -1,32 +1,66 @@
 use crate::error::WebmentionError;
-use crate::webmention::Webmention;
+use crate::webmention::{Moderation, Webmention};
 
-use crate::wm_url::Url;
+use crate::wm_url::{Url, UrlEquivalence};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 pub trait WebmentionStorage {
+    /// Stores `webmention`, or updates the stored one with equivalent source and target, which
+    /// keeps its moderation unless the update comes as [spam](Moderation::Spam).
     fn store(&self, webmention: Webmention) -> Result<(), WebmentionError>;
     fn lookup_by_target(&self, target: Url) -> Result<Vec<Webmention>, WebmentionError>;
+    /// Every stored webmention, in the order they were stored. Storages that cannot list
+    /// webmentions fail with [ListingUnsupported](WebmentionError::ListingUnsupported), and
+    /// cannot be [exported](crate::export).
+    fn list(&self) -> Result<Vec<Webmention>, WebmentionError> {
+        Err(WebmentionError::ListingUnsupported)
+    }
 }
 
 #[derive(Debug)]
 pub struct InMemoryWebmentionStorage {
     mentions: Arc<Mutex<Vec<Webmention>>>,
+    equivalence: UrlEquivalence,
 }
 
 impl InMemoryWebmentionStorage {
     pub fn new() -> InMemoryWebmentionStorage {
+        InMemoryWebmentionStorage::with_equivalence(UrlEquivalence::default())
+    }
+
+    /// Storage that looks mentions up by targets equivalent under `equivalence`.
+    pub fn with_equivalence(equivalence: UrlEquivalence) -> InMemoryWebmentionStorage {
         InMemoryWebmentionStorage {
             mentions: Arc::new(Mutex::new(Vec::new())),
+            equivalence,
         }
     }
 }
 
+impl Default for InMemoryWebmentionStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WebmentionStorage for InMemoryWebmentionStorage {
-    fn store(&self, mention: Webmention) -> Result<(), WebmentionError> {
-        {
-            let mut lock = self.mentions.lock().unwrap();
-            lock.push(mention);
+    fn store(&self, mut mention: Webmention) -> Result<(), WebmentionError> {
+        let mut lock = self.mentions.lock().unwrap();
+        let stored = lock.iter_mut().find(|stored| {
+            self.equivalence.equivalent(&stored.source, &mention.source)
+                && self.equivalence.equivalent(&stored.target, &mention.target)
+        });
+        match stored {
+            Some(stored) => {
+                mention.moderation = updated_moderation(stored.moderation, mention.moderation);
+                *stored = mention;
+            }
+            None => lock.push(mention),
         }
         Ok(())
     }
@@ -35,10 +69,639 @@ impl WebmentionStorage for InMemoryWebmentionStorage {
         let mut view: Vec<Webmention> = Vec::new();
         let lock = self.mentions.lock().unwrap();
         for mention in lock.iter() {
-            if mention.target.eq(&url) {
+            if self.equivalence.equivalent(&mention.target, &url) {
                 view.push(mention.clone());
             }
         }
         Ok(view)
     }
+
+    fn list(&self) -> Result<Vec<Webmention>, WebmentionError> {
+        Ok(self.mentions.lock().unwrap().clone())
+    }
+}
+
+/// Webmentions in a JSON Lines file, which can be shared by processes such as the receiver and
+/// the CLI, and committed to git alongside a static site.
+///
+/// The file is a log: every change is appended to it as an event. `stored` and `updated` events
+/// carry the whole webmention, `deleted` and `moderated` only its source and target. Lines
+/// without an event are read as stored webmentions, which is how the CLI wrote them before. The
+/// log is replayed into memory when the storage is opened, and events appended by other processes
+/// are replayed before every operation. [compact](JsonlWebmentionStorage::compact) replaces the
+/// log with a `stored` event per webmention. Storing a webmention again updates it, but keeps
+/// its moderation unless it comes back as spam.
+///
+/// Processes take turns through an advisory lock of `<file>.lock`, which also counts compactions,
+/// so that others replay a compacted log from the start. So is a file replaced by other means,
+/// such as `git pull`. The next change ends a last line that lacks a newline, such as an event
+/// added by hand, or drops it if it is incomplete, like a line left by a crash.
+#[derive(Debug)]
+pub struct JsonlWebmentionStorage {
+    path: PathBuf,
+    lock_path: PathBuf,
+    equivalence: UrlEquivalence,
+    log: Mutex<Log>,
+}
+
+/// Event of the log, tagged with `event`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event {
+    Stored(Webmention),
+    Updated(Webmention),
+    Deleted {
+        source: Url,
+        target: Url,
+    },
+    Moderated {
+        source: Url,
+        target: Url,
+        moderation: Moderation,
+    },
+}
+
+/// Webmentions replayed from the log and how much of it was replayed
+#[derive(Debug, Default)]
+struct Log {
+    mentions: Vec<Webmention>,
+    /// Bytes replayed
+    offset: u64,
+    /// Lines replayed
+    lines: usize,
+    /// Compactions before the log was replayed
+    generation: u64,
+    /// [Identity](file_identity) of the replayed file
+    identity: Option<(u64, u64)>,
+}
+
+impl Log {
+    fn position(&self, equivalence: &UrlEquivalence, source: &Url, target: &Url) -> Option<usize> {
+        self.mentions.iter().position(|mention| {
+            equivalence.equivalent(&mention.source, source)
+                && equivalence.equivalent(&mention.target, target)
+        })
+    }
+
+    fn apply(&mut self, equivalence: &UrlEquivalence, event: Event) {
+        match event {
+            Event::Stored(mention) | Event::Updated(mention) => {
+                match self.position(equivalence, &mention.source, &mention.target) {
+                    Some(index) => self.mentions[index] = mention,
+                    None => self.mentions.push(mention),
+                }
+            }
+            Event::Deleted { source, target } => {
+                if let Some(index) = self.position(equivalence, &source, &target) {
+                    self.mentions.remove(index);
+                }
+            }
+            Event::Moderated {
+                source,
+                target,
+                moderation,
+            } => {
+                if let Some(index) = self.position(equivalence, &source, &target) {
+                    self.mentions[index].moderation = moderation;
+                }
+            }
+        }
+    }
+}
+
+impl JsonlWebmentionStorage {
+    /// Opens the log at `path`, which is created when a webmention is stored if needed.
+    pub fn open(path: impl Into<PathBuf>) -> Result<JsonlWebmentionStorage, WebmentionError> {
+        JsonlWebmentionStorage::with_equivalence(path, UrlEquivalence::default())
+    }
+
+    /// Storage that looks mentions up by targets equivalent under `equivalence`. Webmentions
+    /// with equivalent sources and targets are the same webmention, so storing it again updates
+    /// it.
+    pub fn with_equivalence(
+        path: impl Into<PathBuf>,
+        equivalence: UrlEquivalence,
+    ) -> Result<JsonlWebmentionStorage, WebmentionError> {
+        let path = path.into();
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let storage = JsonlWebmentionStorage {
+            path,
+            lock_path: lock_path.into(),
+            equivalence,
+            log: Mutex::new(Log::default()),
+        };
+        storage.replay(false, |_| Ok(()))?;
+        Ok(storage)
+    }
+
+    /// Deletes the webmention from `source` to `target`, returning whether it was stored.
+    pub fn delete(&self, source: &Url, target: &Url) -> Result<bool, WebmentionError> {
+        self.replay(true, |log| {
+            if log.position(&self.equivalence, source, target).is_none() {
+                return Ok(false);
+            }
+            self.append(
+                log,
+                Event::Deleted {
+                    source: source.clone(),
+                    target: target.clone(),
+                },
+            )?;
+            Ok(true)
+        })
+    }
+
+    /// Moderates the webmention from `source` to `target`, returning whether it was stored.
+    pub fn moderate(
+        &self,
+        source: &Url,
+        target: &Url,
+        moderation: Moderation,
+    ) -> Result<bool, WebmentionError> {
+        self.replay(true, |log| {
+            if log.position(&self.equivalence, source, target).is_none() {
+                return Ok(false);
+            }
+            self.append(
+                log,
+                Event::Moderated {
+                    source: source.clone(),
+                    target: target.clone(),
+                    moderation,
+                },
+            )?;
+            Ok(true)
+        })
+    }
+
+    /// Replaces the log with a `stored` event per webmention, in the order they were first
+    /// stored, returning how many lines were dropped.
+    pub fn compact(&self) -> Result<usize, WebmentionError> {
+        let mut lock = self.lock(true)?;
+        let mut log = self.log.lock().unwrap();
+        self.catch_up(&lock, &mut log)?;
+        self.end_last_line(&mut log)?;
+
+        let mut contents = Vec::new();
+        for mention in log.mentions.iter() {
+            contents.extend(self.line(&Event::Stored(mention.clone()))?);
+        }
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        std::fs::write(&temporary, &contents).map_err(|source| WebmentionError::Io {
+            path: temporary.clone(),
+            source,
+        })?;
+        // others replay from the start even if the rename is interrupted
+        let generation = log.generation + 1;
+        lock.set_len(0)
+            .and_then(|_| lock.seek(SeekFrom::Start(0)))
+            .and_then(|_| lock.write_all(generation.to_string().as_bytes()))
+            .map_err(|source| WebmentionError::Io {
+                path: self.lock_path.clone(),
+                source,
+            })?;
+        std::fs::rename(&temporary, &self.path).map_err(|source| WebmentionError::Io {
+            path: self.path.clone(),
+            source,
+        })?;
+
+        let dropped = log.lines - log.mentions.len();
+        log.identity = std::fs::metadata(&self.path)
+            .ok()
+            .and_then(|metadata| file_identity(&metadata));
+        log.generation = generation;
+        log.offset = contents.len() as u64;
+        log.lines = log.mentions.len();
+        Ok(dropped)
+    }
+
+    /// Locks the lock file, exclusively for changes
+    fn lock(&self, exclusive: bool) -> Result<File, WebmentionError> {
+        let io_error = |source| WebmentionError::Io {
+            path: self.lock_path.clone(),
+            source,
+        };
+        let lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.lock_path)
+            .map_err(io_error)?;
+        if exclusive {
+            lock.lock_exclusive().map_err(io_error)?;
+        } else {
+            lock.lock_shared().map_err(io_error)?;
+        }
+        Ok(lock)
+    }
+
+    /// Runs `f` on the log after replaying the events appended since the last time, holding the
+    /// lock
+    fn replay<T>(
+        &self,
+        exclusive: bool,
+        f: impl FnOnce(&mut Log) -> Result<T, WebmentionError>,
+    ) -> Result<T, WebmentionError> {
+        let lock = self.lock(exclusive)?;
+        let mut log = self.log.lock().unwrap();
+        self.catch_up(&lock, &mut log)?;
+        if exclusive {
+            self.end_last_line(&mut log)?;
+        }
+        f(&mut log)
+    }
+
+    /// Replays events appended to the file since the last time, or the whole file if it was
+    /// compacted or replaced since then. A replaced file is told apart by its identity, or by
+    /// the replayed part not ending with a whole line where it used to.
+    fn catch_up(&self, mut lock: &File, log: &mut Log) -> Result<(), WebmentionError> {
+        let io_error = |source| WebmentionError::Io {
+            path: self.path.clone(),
+            source,
+        };
+        let mut generation = String::new();
+        lock.read_to_string(&mut generation)
+            .map_err(|source| WebmentionError::Io {
+                path: self.lock_path.clone(),
+                source,
+            })?;
+        let generation = generation.trim().parse().unwrap_or_default();
+
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                *log = Log {
+                    generation,
+                    ..Log::default()
+                };
+                return Ok(());
+            }
+            Err(source) => return Err(io_error(source)),
+        };
+        let metadata = file.metadata().map_err(io_error)?;
+        let identity = file_identity(&metadata);
+        if generation != log.generation
+            || identity != log.identity
+            || metadata.len() < log.offset
+            || !ends_line(&mut file, log.offset).map_err(io_error)?
+        {
+            *log = Log {
+                generation,
+                identity,
+                ..Log::default()
+            };
+        }
+
+        let mut appended = Vec::new();
+        file.seek(SeekFrom::Start(log.offset))
+            .and_then(|_| file.read_to_end(&mut appended))
+            .map_err(io_error)?;
+        // the last line is incomplete unless it ends with a newline
+        let mut lines: Vec<&[u8]> = appended.split(|&byte| byte == b'\n').collect();
+        lines.pop();
+        for line in lines {
+            let number = log.lines + 1;
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                let event = parse_event(line).map_err(|err| WebmentionError::InvalidFile {
+                    path: self.path.clone(),
+                    source: WebmentionError::InvalidRecord {
+                        line: number,
+                        reason: err.to_string(),
+                    }
+                    .into(),
+                })?;
+                log.apply(&self.equivalence, event);
+            }
+            log.lines = number;
+            log.offset += line.len() as u64 + 1;
+        }
+        Ok(())
+    }
+
+    /// Ends the last line of the file, which was not replayed for the lack of a newline, if it
+    /// is a whole event, and drops it otherwise
+    fn end_last_line(&self, log: &mut Log) -> Result<(), WebmentionError> {
+        let io_error = |source| WebmentionError::Io {
+            path: self.path.clone(),
+            source,
+        };
+        let mut file = match OpenOptions::new().read(true).append(true).open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(source) => return Err(io_error(source)),
+        };
+        let mut last = Vec::new();
+        file.seek(SeekFrom::Start(log.offset))
+            .and_then(|_| file.read_to_end(&mut last))
+            .map_err(io_error)?;
+        if last.is_empty() {
+            return Ok(());
+        }
+        match parse_event(&last) {
+            Ok(event) => {
+                file.write_all(b"\n").map_err(io_error)?;
+                log.apply(&self.equivalence, event);
+                log.offset += last.len() as u64 + 1;
+                log.lines += 1;
+            }
+            Err(_) => file.set_len(log.offset).map_err(io_error)?,
+        }
+        Ok(())
+    }
+
+    /// Appends `event` to the file, which ends with a whole line, and applies it
+    fn append(&self, log: &mut Log, event: Event) -> Result<(), WebmentionError> {
+        let line = self.line(&event)?;
+        let io_error = |source| WebmentionError::Io {
+            path: self.path.clone(),
+            source,
+        };
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .map_err(io_error)?;
+        let metadata = file.metadata().map_err(io_error)?;
+        file.write_all(&line).map_err(io_error)?;
+        log.identity = file_identity(&metadata);
+
+        log.offset += line.len() as u64;
+        log.lines += 1;
+        log.apply(&self.equivalence, event);
+        Ok(())
+    }
+
+    fn line(&self, event: &Event) -> Result<Vec<u8>, WebmentionError> {
+        let mut line = serde_json::to_vec(event).map_err(|err| WebmentionError::InvalidFile {
+            path: self.path.clone(),
+            source: err.into(),
+        })?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+/// Device and inode of a file, which change when it is replaced rather than written to
+#[cfg(unix)]
+fn file_identity(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether the first `offset` bytes of `file` end with a whole line
+fn ends_line(file: &mut File, offset: u64) -> std::io::Result<bool> {
+    if offset == 0 {
+        return Ok(true);
+    }
+    let mut last = [0];
+    file.seek(SeekFrom::Start(offset - 1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+/// Moderation of a webmention stored again: a spam verdict wins, otherwise the stored one is kept
+fn updated_moderation(stored: Moderation, new: Moderation) -> Moderation {
+    match new {
+        Moderation::Spam => Moderation::Spam,
+        _ => stored,
+    }
+}
+
+/// Parses a line of the log, which is a stored webmention if it has no event
+fn parse_event(line: &[u8]) -> Result<Event, serde_json::Error> {
+    let value: serde_json::Value = serde_json::from_slice(line)?;
+    if value.get("event").is_some() {
+        serde_json::from_value(value)
+    } else {
+        serde_json::from_value(value).map(Event::Stored)
+    }
+}
+
+impl WebmentionStorage for JsonlWebmentionStorage {
+    fn store(&self, mention: Webmention) -> Result<(), WebmentionError> {
+        self.replay(true, |log| {
+            let event = match log.position(&self.equivalence, &mention.source, &mention.target) {
+                Some(index) => {
+                    let mut mention = mention;
+                    mention.moderation =
+                        updated_moderation(log.mentions[index].moderation, mention.moderation);
+                    Event::Updated(mention)
+                }
+                None => Event::Stored(mention),
+            };
+            self.append(log, event)
+        })
+    }
+
+    fn lookup_by_target(&self, url: Url) -> Result<Vec<Webmention>, WebmentionError> {
+        self.replay(false, |log| {
+            Ok(log
+                .mentions
+                .iter()
+                .filter(|mention| self.equivalence.equivalent(&mention.target, &url))
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn list(&self) -> Result<Vec<Webmention>, WebmentionError> {
+        self.replay(false, |log| Ok(log.mentions.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InMemoryWebmentionStorage, JsonlWebmentionStorage, WebmentionStorage};
+    use crate::error::WebmentionError;
+    use crate::http_client::test::TempDir;
+    use crate::webmention::{Moderation, Webmention};
+    use crate::wm_url::Url;
+    use std::io::Write;
+
+    fn mention(source: &str) -> Webmention {
+        Webmention::from((
+            Url::parse(source).unwrap(),
+            Url::parse("https://bob.example/post").unwrap(),
+        ))
+    }
+
+    fn sources(storage: &JsonlWebmentionStorage) -> Vec<String> {
+        let mentions = storage.list().unwrap();
+        mentions.iter().map(|m| m.source.to_string()).collect()
+    }
+
+    #[test]
+    fn test_in_memory_updates() {
+        let storage = InMemoryWebmentionStorage::new();
+        let mut sent = mention("https://alice.example/");
+        sent.moderation = Moderation::Approved;
+        storage.store(sent.clone()).unwrap();
+        storage.store(mention("https://carol.example/")).unwrap();
+
+        let mut again = mention("https://alice.example/");
+        again.set_checked(true);
+        storage.store(again).unwrap();
+        let stored = storage.list().unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].checked(), Some(true));
+        assert_eq!(stored[0].moderation, Moderation::Approved);
+
+        sent.moderation = Moderation::Spam;
+        storage.store(sent).unwrap();
+        assert_eq!(storage.list().unwrap()[0].moderation, Moderation::Spam);
+    }
+
+    #[test]
+    fn test_jsonl_storage() {
+        let dir = TempDir::new("storage");
+        let path = dir.path().join("mentions.jsonl");
+        // written by earlier versions of the CLI
+        let legacy = serde_json::to_string(&mention("https://alice.example/")).unwrap();
+        std::fs::write(&path, format!("{}\n", legacy)).unwrap();
+
+        let storage = JsonlWebmentionStorage::open(&path).unwrap();
+        let other = JsonlWebmentionStorage::open(&path).unwrap();
+        storage.store(mention("https://carol.example/")).unwrap();
+        let mut updated = mention("https://alice.example/#reply");
+        updated.set_checked(true);
+        storage.store(updated).unwrap();
+        assert_eq!(
+            sources(&other),
+            ["https://alice.example/#reply", "https://carol.example/"]
+        );
+
+        let source = Url::parse("https://carol.example/").unwrap();
+        let target = Url::parse("https://bob.example/post").unwrap();
+        assert!(other.moderate(&source, &target, Moderation::Spam).unwrap());
+        let stored = storage.lookup_by_target(target.clone()).unwrap();
+        assert_eq!(stored[1].moderation, Moderation::Spam);
+        assert!(other.delete(&source, &target).unwrap());
+        assert!(!storage.delete(&source, &target).unwrap());
+        assert_eq!(sources(&storage), ["https://alice.example/#reply"]);
+
+        // a crash while appending leaves an incomplete line
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"{\"event\":\"stor").unwrap();
+        assert_eq!(sources(&other).len(), 1);
+        other.store(mention("https://dave.example/")).unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 6);
+        assert!(log
+            .lines()
+            .all(|line| line.starts_with("{\"event\":\"") || line == legacy));
+
+        assert_eq!(storage.compact().unwrap(), 4);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        storage.store(mention("https://erin.example/")).unwrap();
+        assert_eq!(sources(&other).len(), 3);
+        assert!(other.list().unwrap()[0].checked().unwrap());
+        assert_eq!(
+            JsonlWebmentionStorage::open(&path).unwrap().list().unwrap(),
+            other.list().unwrap()
+        );
+
+        // edited by hand, without a newline at the end
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let edited = serde_json::to_string(&mention("https://frank.example/")).unwrap();
+        file.write_all(edited.as_bytes()).unwrap();
+        assert_eq!(sources(&other).len(), 3);
+        storage.store(mention("https://grace.example/")).unwrap();
+        assert_eq!(
+            &sources(&other)[3..],
+            ["https://frank.example/", "https://grace.example/"]
+        );
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.contains(&format!("\n{}\n{{\"event\":\"stored\",", edited)));
+
+        std::fs::write(&path, "not json\n").unwrap();
+        assert!(matches!(
+            JsonlWebmentionStorage::open(&path),
+            Err(WebmentionError::InvalidFile { .. })
+        ));
+    }
+
+    #[test]
+    fn test_jsonl_replaced_file() {
+        let dir = TempDir::new("replaced");
+        let path = dir.path().join("mentions.jsonl");
+        let log = |sources: &[&str]| {
+            let path = dir.path().join("other.jsonl");
+            let _ = std::fs::remove_file(&path);
+            let other = JsonlWebmentionStorage::open(&path).unwrap();
+            for source in sources {
+                other.store(mention(source)).unwrap();
+            }
+            std::fs::read(&path).unwrap()
+        };
+
+        // replaced like git does, by a longer file with a line as long as the replayed one;
+        // identities of files are only known on Unix
+        if cfg!(unix) {
+            let storage = JsonlWebmentionStorage::open(&path).unwrap();
+            storage.store(mention("https://alice.example/")).unwrap();
+            let replaced = log(&["https://frank.example/", "https://dave.example/"]);
+            std::fs::write(dir.path().join("new.jsonl"), &replaced).unwrap();
+            std::fs::rename(dir.path().join("new.jsonl"), &path).unwrap();
+            assert_eq!(
+                sources(&storage),
+                ["https://frank.example/", "https://dave.example/"]
+            );
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        // rewritten in place, where only the content tells
+        let storage = JsonlWebmentionStorage::open(&path).unwrap();
+        storage.store(mention("https://alice.example/")).unwrap();
+        let rewritten = log(&["https://carol.example/long/path", "https://dave.example/"]);
+        std::fs::write(&path, &rewritten).unwrap();
+        assert_eq!(
+            sources(&storage),
+            ["https://carol.example/long/path", "https://dave.example/"]
+        );
+    }
+
+    #[test]
+    fn test_jsonl_keeps_moderation() {
+        let dir = TempDir::new("moderation");
+        let path = dir.path().join("mentions.jsonl");
+        let storage = JsonlWebmentionStorage::open(&path).unwrap();
+        let sent = mention("https://alice.example/");
+        storage.store(sent.clone()).unwrap();
+        assert!(storage
+            .moderate(&sent.source, &sent.target, Moderation::Approved)
+            .unwrap());
+
+        // the source sends the webmention again after an edit
+        let mut again = sent.clone();
+        again.set_checked(true);
+        storage.store(again).unwrap();
+        for storage in [&storage, &JsonlWebmentionStorage::open(&path).unwrap()] {
+            let stored = storage.list().unwrap();
+            assert_eq!(stored[0].moderation, Moderation::Approved);
+            assert_eq!(stored[0].checked(), Some(true));
+        }
+
+        // and the spam filter catches the edit
+        let mut spam = sent.clone();
+        spam.moderation = Moderation::Spam;
+        storage.store(spam).unwrap();
+        for storage in [storage, JsonlWebmentionStorage::open(&path).unwrap()] {
+            assert_eq!(storage.list().unwrap()[0].moderation, Moderation::Spam);
+        }
+    }
 }
@@ -0,0 +1,115 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Formats `time` in UTC as `2021-04-05T10:20:30Z`.
+pub fn format(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default() as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let seconds = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Parses an ISO 8601 timestamp such as `2021-04-05T10:20:30.123+02:00`
+pub fn parse(timestamp: &str) -> Option<SystemTime> {
+    let timestamp = timestamp.trim();
+    let number = |s: &str| -> Option<i64> {
+        s.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| s.parse().ok())
+            .flatten()
+    };
+    let year = number(timestamp.get(0..4)?)?;
+    let month = number(timestamp.get(5..7)?)?;
+    let day = number(timestamp.get(8..10)?)?;
+    let hour = number(timestamp.get(11..13)?)?;
+    let minute = number(timestamp.get(14..16)?)?;
+    let second = number(timestamp.get(17..19)?)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+
+    let zone = timestamp
+        .get(19..)?
+        .trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "Z" | "z" | "" => 0,
+        _ => {
+            let sign = match zone.get(0..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let zone = zone[1..].replace(':', "");
+            sign * (number(zone.get(0..2)?)? * 3600 + number(zone.get(2..4)?)? * 60)
+        }
+    };
+
+    let seconds =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    if seconds < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(seconds as u64))
+}
+
+// conversions between civil dates and days since the epoch, see
+// http://howardhinnant.github.io/date_algorithms.html
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format, parse};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_208_005);
+        assert_eq!(format(leap_day), "2024-02-29T12:00:05Z");
+    }
+
+    #[test]
+    fn test_parse() {
+        let time = |seconds| Some(UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(parse("1970-01-01T00:00:00Z"), time(0));
+        assert_eq!(parse("2024-02-29T12:00:00.250Z"), time(1_709_208_000));
+        assert_eq!(parse("2024-02-29T14:00:00+02:00"), time(1_709_208_000));
+        assert_eq!(parse("2024-02-29T14:00:00+0200"), time(1_709_208_000));
+        assert_eq!(parse("2024-02-29"), None);
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_208_005);
+        assert_eq!(parse(&format(leap_day)), Some(leap_day));
+        assert_eq!(parse("yesterday at noon"), None);
+    }
+}
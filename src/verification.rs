@@ -0,0 +1,328 @@
+use crate::error::WebmentionError;
+use crate::html::HTML;
+use crate::http_client::Response;
+use crate::wm_url::{absolute_url, Url, UrlEquivalence};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+/// Kind of the source document, which determines how we look for the link to target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    /// `text/html` and `application/xhtml+xml`: `<a href>` pointing to target
+    Html,
+    /// `application/json` and `application/*+json` (including mf2 JSON): a string value equal
+    /// to target, or a link to target inside of `html` property
+    Json,
+    /// `text/plain` and other text: target URL as a separate word
+    PlainText,
+    /// Atom and RSS: `href` or `<link>` pointing to target, or a link inside of content
+    Feed,
+}
+
+impl SourceKind {
+    /// Determines kind of the document from its `Content-Type`. Documents without one are HTML.
+    pub fn from_content_type(content_type: Option<&str>) -> SourceKind {
+        let essence = match content_type.and_then(|content_type| content_type.split(';').next()) {
+            Some(essence) => essence.trim().to_ascii_lowercase(),
+            None => return SourceKind::Html,
+        };
+
+        match essence.as_str() {
+            "text/html" | "application/xhtml+xml" => SourceKind::Html,
+            "application/json" => SourceKind::Json,
+            json if json.starts_with("application/") && json.ends_with("+json") => SourceKind::Json,
+            "application/atom+xml" | "application/rss+xml" | "application/xml" | "text/xml" => {
+                SourceKind::Feed
+            }
+            _ => SourceKind::PlainText,
+        }
+    }
+}
+
+/// Checks that the fetched source links to `target` under default [UrlEquivalence].
+pub fn contains(response: &Response, target: &Url) -> Result<(), WebmentionError> {
+    contains_with(response, target, &UrlEquivalence::default())
+}
+
+/// Checks that the fetched source links to `target`, according to the content type of source.
+/// Links are compared with `equivalence`.
+pub fn contains_with(
+    response: &Response,
+    target: &Url,
+    equivalence: &UrlEquivalence,
+) -> Result<(), WebmentionError> {
+    let matcher = Matcher::new(&response.url, target, equivalence);
+    let text = response.html.raw();
+
+    let found = match SourceKind::from_content_type(response.content_type.as_deref()) {
+        SourceKind::Html => return response.html.contains_with(target, equivalence),
+        SourceKind::Json => matcher.json_contains(text)?,
+        SourceKind::PlainText => matcher.text_contains(text),
+        SourceKind::Feed => matcher.feed_contains(text)?,
+    };
+
+    if found {
+        Ok(())
+    } else {
+        Err(WebmentionError::NoDocumentLinks)
+    }
+}
+
+/// Elements of Atom and RSS that contain escaped HTML
+const FEED_CONTENT: [&[u8]; 4] = [b"content", b"summary", b"description", b"encoded"];
+/// Elements of RSS that contain a URL as text
+const FEED_LINKS: [&[u8]; 2] = [b"link", b"comments"];
+
+/// Looks for links to target in a non-HTML document fetched from `base`.
+struct Matcher<'a> {
+    base: &'a Url,
+    target: &'a Url,
+    normalized_target: Url,
+    equivalence: &'a UrlEquivalence,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(base: &'a Url, target: &'a Url, equivalence: &'a UrlEquivalence) -> Matcher<'a> {
+        Matcher {
+            base,
+            target,
+            normalized_target: equivalence.normalize(target),
+            equivalence,
+        }
+    }
+
+    /// Whether `link`, resolved relative to the document, points to target
+    fn is_target(&self, link: &str) -> bool {
+        absolute_url(link.trim(), self.base)
+            .is_ok_and(|url| self.equivalence.normalize(&url) == self.normalized_target)
+    }
+
+    /// Whether `link` is an absolute URL pointing to target
+    fn is_absolute_target(&self, link: &str) -> bool {
+        Url::parse(link).is_ok_and(|url| self.equivalence.normalize(&url) == self.normalized_target)
+    }
+
+    fn html_contains(&self, html: &str) -> bool {
+        HTML::new(self.base.clone(), html.to_string())
+            .contains_with(self.target, self.equivalence)
+            .is_ok()
+    }
+
+    fn json_contains(&self, json: &str) -> Result<bool, WebmentionError> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|_| WebmentionError::UnparseableDocument)?;
+        Ok(self.json_value_contains(&value))
+    }
+
+    fn json_value_contains(&self, value: &serde_json::Value) -> bool {
+        use serde_json::Value;
+
+        match value {
+            Value::String(s) => self.is_absolute_target(s),
+            Value::Array(values) => values.iter().any(|v| self.json_value_contains(v)),
+            Value::Object(properties) => {
+                properties.iter().any(|(name, v)| match (name.as_str(), v) {
+                    // mf2 JSON keeps marked up content in `html`
+                    ("html", Value::String(html)) => self.html_contains(html),
+                    _ => self.json_value_contains(v),
+                })
+            }
+            _ => false,
+        }
+    }
+
+    fn text_contains(&self, text: &str) -> bool {
+        text.split(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\''))
+            .map(|word| {
+                word.trim_start_matches(['(', '['])
+                    .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']'])
+            })
+            .any(|word| self.is_absolute_target(word))
+    }
+
+    fn feed_contains(&self, xml: &str) -> Result<bool, WebmentionError> {
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+
+        let href_is_target = |element: &BytesStart| {
+            element
+                .attributes()
+                .filter_map(|attribute| attribute.ok())
+                .filter(|attribute| attribute.key.as_ref() == b"href")
+                .filter_map(|attribute| attribute.unescape_value().ok())
+                .any(|href| self.is_target(&href))
+        };
+
+        let mut current: Vec<Vec<u8>> = Vec::new();
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(element)) => {
+                    if href_is_target(&element) {
+                        return Ok(true);
+                    }
+                    current.push(element.local_name().as_ref().to_vec());
+                }
+                Ok(Event::Empty(element)) => {
+                    if href_is_target(&element) {
+                        return Ok(true);
+                    }
+                }
+                Ok(Event::End(_)) => {
+                    current.pop();
+                }
+                Ok(event @ Event::Text(_)) | Ok(event @ Event::CData(_)) => {
+                    let text = match event {
+                        Event::Text(text) => text
+                            .unescape()
+                            .map_err(|_| WebmentionError::UnparseableDocument)?
+                            .to_string(),
+                        Event::CData(data) => {
+                            String::from_utf8_lossy(&data.into_inner()).to_string()
+                        }
+                        _ => continue,
+                    };
+                    let element = match current.last() {
+                        Some(element) => element.as_slice(),
+                        None => continue,
+                    };
+
+                    if FEED_LINKS.contains(&element) && self.is_target(&text) {
+                        return Ok(true);
+                    }
+                    if FEED_CONTENT.contains(&element) && self.html_contains(&text) {
+                        return Ok(true);
+                    }
+                }
+                Ok(Event::Eof) => return Ok(false),
+                Ok(_) => continue,
+                Err(_) => return Err(WebmentionError::UnparseableDocument),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Matcher, SourceKind};
+    use crate::error::WebmentionError;
+    use crate::wm_url::{Url, UrlEquivalence};
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn json_contains(json: &str, base: &Url, target: &Url) -> Result<bool, WebmentionError> {
+        Matcher::new(base, target, &UrlEquivalence::default()).json_contains(json)
+    }
+
+    fn text_contains(text: &str, target: &Url) -> bool {
+        Matcher::new(target, target, &UrlEquivalence::default()).text_contains(text)
+    }
+
+    fn feed_contains(xml: &str, base: &Url, target: &Url) -> Result<bool, WebmentionError> {
+        Matcher::new(base, target, &UrlEquivalence::default()).feed_contains(xml)
+    }
+
+    #[test]
+    fn test_source_kind() {
+        assert_eq!(SourceKind::from_content_type(None), SourceKind::Html);
+        assert_eq!(
+            SourceKind::from_content_type(Some("text/html; charset=utf-8")),
+            SourceKind::Html
+        );
+        assert_eq!(
+            SourceKind::from_content_type(Some("application/mf2+json")),
+            SourceKind::Json
+        );
+        assert_eq!(
+            SourceKind::from_content_type(Some("text/plain")),
+            SourceKind::PlainText
+        );
+        assert_eq!(
+            SourceKind::from_content_type(Some("application/rss+xml")),
+            SourceKind::Feed
+        );
+    }
+
+    #[test]
+    fn test_json_contains() {
+        let base = url("https://marinintim.com/notes/1.json");
+        let target = url("https://example.com/post");
+
+        let json = r#"{"type": "entry", "in-reply-to": "https://example.com/post"}"#;
+        assert!(json_contains(json, &base, &target).unwrap());
+
+        let mf2 = r#"{"items": [{"type": ["h-entry"], "properties": {
+            "content": [{"value": "Nice post", "html": "<a href=\"https://example.com/post\">Nice</a> post"}]
+        }}]}"#;
+        assert!(json_contains(mf2, &base, &target).unwrap());
+
+        let unrelated =
+            r#"{"url": "https://example.com/post-2", "name": "https://example.com/post is great"}"#;
+        assert!(!json_contains(unrelated, &base, &target).unwrap());
+
+        assert!(json_contains("{not json", &base, &target).is_err());
+    }
+
+    #[test]
+    fn test_text_contains() {
+        let target = url("https://example.com/post");
+        assert!(text_contains("See https://example.com/post.", &target));
+        assert!(text_contains("(https://example.com/post)", &target));
+        assert!(text_contains("<https://example.com/post>", &target));
+        assert!(!text_contains("See https://example.com/post-2", &target));
+        assert!(!text_contains("See https://example.com/post.html", &target));
+        assert!(text_contains(
+            "See https://example.com/post/?utm_source=x",
+            &target
+        ));
+
+        let lenient = UrlEquivalence::lenient();
+        assert!(
+            Matcher::new(&target, &target, &lenient).text_contains("http://www.example.com/post")
+        );
+    }
+
+    #[test]
+    fn test_atom_contains() {
+        let base = url("https://marinintim.com/feed.atom");
+        let target = url("https://example.com/post");
+
+        let atom = r#"<?xml version="1.0" encoding="utf-8"?>
+            <feed xmlns="http://www.w3.org/2005/Atom">
+              <entry>
+                <link rel="related" href="https://example.com/post"/>
+              </entry>
+            </feed>"#;
+        assert!(feed_contains(atom, &base, &target).unwrap());
+
+        let escaped = r#"<feed xmlns="http://www.w3.org/2005/Atom"><entry>
+                <content type="html">&lt;a href="https://example.com/post"&gt;post&lt;/a&gt;</content>
+            </entry></feed>"#;
+        assert!(feed_contains(escaped, &base, &target).unwrap());
+
+        let unrelated = r#"<feed xmlns="http://www.w3.org/2005/Atom"><entry>
+                <link href="https://marinintim.com/notes/1"/>
+                <content type="text">https://example.com/post</content>
+            </entry></feed>"#;
+        assert!(!feed_contains(unrelated, &base, &target).unwrap());
+    }
+
+    #[test]
+    fn test_rss_contains() {
+        let base = url("https://marinintim.com/rss.xml");
+        let target = url("https://example.com/post");
+
+        let rss = r#"<rss version="2.0" xmlns:content="http://purl.org/rss/1.0/modules/content/">
+            <channel><item>
+              <link>https://marinintim.com/notes/1</link>
+              <content:encoded><![CDATA[<p>Replying to <a href="https://example.com/post">this</a></p>]]></content:encoded>
+            </item></channel></rss>"#;
+        assert!(feed_contains(rss, &base, &target).unwrap());
+
+        let rss = r#"<rss version="2.0"><channel><item>
+              <link>https://example.com/post</link>
+            </item></channel></rss>"#;
+        assert!(feed_contains(rss, &base, &target).unwrap());
+    }
+}
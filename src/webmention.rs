@@ -1,23 +1,49 @@
 use crate::{
-    endpoint_discovery::find_target_endpoint, error::WebmentionError, http_client::get, wm_url::Url,
+    endpoint_discovery::{discover, DiscoveryOptions},
+    error::WebmentionError,
+    http_client::{HttpClient, Response},
+    snapshot::{Snapshot, SnapshotOptions},
+    wm_url::Url,
 };
 use serde::{Deserialize, Serialize};
 
 /// Contains source URL and target URL, as well as whether we checked the source and whether we
 /// sent webmention.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Webmention {
     pub source: Url,
     pub target: Url,
     checked: Option<bool>,
     sent: bool,
+    /// Whether a received webmention may be displayed
+    #[serde(default)]
+    pub moderation: Moderation,
+    /// Content of the source when it was last verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<Snapshot>,
 }
 
-/// The logical result of an attempt to send webmention, if there are no [errors](WebmentionError) (such as networking
-/// errors, URL parsing errors, etc.)
+/// Moderation state of a received webmention.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Moderation {
+    /// Waiting for a decision
+    #[default]
+    Pending,
+    /// May be displayed
+    Approved,
+    /// Judged to be spam by a spam filter
+    Spam,
+}
+
+/// The logical result of an attempt to send webmention, if there are no
+/// [errors](WebmentionError) (such as networking errors, URL parsing errors, etc.)
+///
+/// The `pingback` feature adds variants, so matches need a wildcard arm.
 #[derive(Debug, PartialEq)]
+#[non_exhaustive]
 pub enum WebmentionAcceptance {
-    /// Common option as of 2021
+    /// Common option as of 2021. With `pingback` feature it also means there was no pingback
+    /// server to fall back to.
     NoTargetEndpoint,
     /// Source doesn't contain link to target
     NotValid,
@@ -25,6 +51,44 @@ pub enum WebmentionAcceptance {
     NotAccepted,
     /// Target endpoint accepted the webmention
     Accepted,
+    /// Target had no webmention endpoint, but its pingback server accepted the pingback
+    #[cfg(feature = "pingback")]
+    PingbackAccepted,
+    /// Target had no webmention endpoint, and its pingback server refused the pingback
+    #[cfg(feature = "pingback")]
+    PingbackRejected(crate::pingback::Fault),
+}
+
+impl WebmentionAcceptance {
+    /// Whether the webmention (or pingback) was accepted by the target.
+    pub fn is_accepted(&self) -> bool {
+        match self {
+            WebmentionAcceptance::Accepted => true,
+            #[cfg(feature = "pingback")]
+            WebmentionAcceptance::PingbackAccepted => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for WebmentionAcceptance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebmentionAcceptance::NoTargetEndpoint => write!(f, "no endpoint found"),
+            WebmentionAcceptance::NotValid => write!(f, "source does not link to target"),
+            WebmentionAcceptance::NotAccepted => write!(f, "not accepted"),
+            WebmentionAcceptance::Accepted => write!(f, "accepted"),
+            #[cfg(feature = "pingback")]
+            WebmentionAcceptance::PingbackAccepted => write!(f, "accepted as pingback"),
+            #[cfg(feature = "pingback")]
+            WebmentionAcceptance::PingbackRejected(fault) => write!(
+                f,
+                "pingback rejected with fault {}: {}",
+                fault.code.code(),
+                fault.message
+            ),
+        }
+    }
 }
 
 impl Webmention {
@@ -40,14 +104,41 @@ impl Webmention {
     /// This includes a) checking the source to include link to target, b) discovering target
     /// endpoint, c) sending POST request to target endpoint.
     ///
-    /// You can skip a) via `webmention.set_checked(true)`.
+    /// You can skip a) via `webmention.set_checked(true)`. Only a source that does not link to
+    /// the target is remembered as invalid: when the source cannot be fetched, the error is
+    /// returned and the source is checked again next time.
+    ///
+    /// With `pingback` feature, targets without webmention endpoint are sent a pingback instead.
     ///
     /// The result it `WebmentionAcceptance`, which signifies several distinct outcomes.
     pub async fn send(&mut self) -> Result<WebmentionAcceptance, WebmentionError> {
+        self.send_with(&HttpClient::default()).await
+    }
+
+    /// Same as [send](Webmention::send), but makes all requests with the given `client`.
+    pub async fn send_with(
+        &mut self,
+        client: &HttpClient,
+    ) -> Result<WebmentionAcceptance, WebmentionError> {
+        self.send_with_options(client, &DiscoveryOptions::default())
+            .await
+    }
+
+    /// Same as [send_with](Webmention::send_with), but discovers the endpoint of the target
+    /// according to `options`.
+    pub async fn send_with_options(
+        &mut self,
+        client: &HttpClient,
+        options: &DiscoveryOptions,
+    ) -> Result<WebmentionAcceptance, WebmentionError> {
         let valid = if let Some(cached_valid) = self.checked {
             cached_valid
         } else {
-            let valid = self.check().await.is_ok();
+            let valid = match self.fetch_verified(client).await {
+                Ok(_) => true,
+                Err(WebmentionError::NoDocumentLinks) => false,
+                Err(err) => return Err(err),
+            };
             self.checked = Some(valid);
             valid
         };
@@ -56,20 +147,29 @@ impl Webmention {
             return Ok(WebmentionAcceptance::NotValid);
         }
 
-        let endpoint_result = find_target_endpoint(&self.target).await.map_err(|e| {
+        let (report, response) = discover(client, options, &self.target).await.map_err(|e| {
             WebmentionError::DiscoveryRequestFailed {
                 source: Box::new(e),
                 url: self.target.clone(),
             }
         })?;
+        let endpoint = match report.endpoint {
+            Some(endpoint) => {
+                // the fetched target is dropped before awaiting, as parsed documents are not Send
+                drop(response);
+                endpoint
+            }
+            #[cfg(feature = "pingback")]
+            None => return self.fall_back_to_pingback(client, response).await,
+            #[cfg(not(feature = "pingback"))]
+            None => return Ok(WebmentionAcceptance::NoTargetEndpoint),
+        };
 
-        if endpoint_result.is_none() {
-            return Ok(WebmentionAcceptance::NoTargetEndpoint);
-        }
-
-        let endpoint = endpoint_result.ok_or(WebmentionError::UnparseableDocument)?;
-
-        let accepted = crate::http_client::post(&endpoint, &self).await?;
+        let form = [
+            ("source", self.source.as_str()),
+            ("target", self.target.as_str()),
+        ];
+        let accepted = client.post(&endpoint, &form).await?;
         self.sent = true;
         match accepted {
             true => Ok(WebmentionAcceptance::Accepted),
@@ -77,14 +177,110 @@ impl Webmention {
         }
     }
 
+    /// Send pingback to the pingback server of the target, if there is any.
+    #[cfg(feature = "pingback")]
+    pub async fn send_pingback(
+        &mut self,
+        client: &HttpClient,
+    ) -> Result<WebmentionAcceptance, WebmentionError> {
+        let endpoint =
+            crate::endpoint_discovery::find_pingback_endpoint(client, &self.target).await?;
+        self.ping(client, endpoint).await
+    }
+
+    /// Sends pingback to the target that has no webmention endpoint, looking for its pingback
+    /// server in `response` of the discovery, or fetching the target again if there is none.
+    #[cfg(feature = "pingback")]
+    fn fall_back_to_pingback<'a>(
+        &'a mut self,
+        client: &'a HttpClient,
+        response: Option<Response>,
+    ) -> impl std::future::Future<Output = Result<WebmentionAcceptance, WebmentionError>> + 'a {
+        // the response is dropped before awaiting, as parsed documents are not Send
+        let endpoint = response
+            .as_ref()
+            .map(crate::endpoint_discovery::find_pingback_endpoint_in);
+        async move {
+            match endpoint {
+                Some(endpoint) => self.ping(client, endpoint?).await,
+                None => self.send_pingback(client).await,
+            }
+        }
+    }
+
+    /// Sends pingback to `endpoint` of the target, if it has one.
+    #[cfg(feature = "pingback")]
+    async fn ping(
+        &mut self,
+        client: &HttpClient,
+        endpoint: Option<Url>,
+    ) -> Result<WebmentionAcceptance, WebmentionError> {
+        use crate::pingback::{send_pingback, PingbackResponse};
+
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(WebmentionAcceptance::NoTargetEndpoint),
+        };
+
+        match send_pingback(client, &endpoint, &self.source, &self.target).await? {
+            PingbackResponse::Accepted(_) => {
+                self.sent = true;
+                Ok(WebmentionAcceptance::PingbackAccepted)
+            }
+            PingbackResponse::Fault(fault) => Ok(WebmentionAcceptance::PingbackRejected(fault)),
+        }
+    }
+
     pub async fn check(&mut self) -> Result<(), WebmentionError> {
-        let response = get(&self.source).await?;
-        response.html.contains(&self.target)
+        self.check_with(&HttpClient::default()).await
+    }
+
+    /// Same as [check](Webmention::check), but fetches the source with the given `client`.
+    pub async fn check_with(&mut self, client: &HttpClient) -> Result<(), WebmentionError> {
+        self.check_with_options(client, &SnapshotOptions::default())
+            .await
+    }
+
+    /// Checks that the source links to target and keeps a [snapshot](Webmention::snapshot) of
+    /// the source made with `options`.
+    pub async fn check_with_options(
+        &mut self,
+        client: &HttpClient,
+        options: &SnapshotOptions,
+    ) -> Result<(), WebmentionError> {
+        let response = self.fetch_verified(client).await?;
+        self.snapshot = Some(Snapshot::from_response(&response, &self.target, options));
+        Ok(())
+    }
+
+    async fn fetch_verified(&self, client: &HttpClient) -> Result<Response, WebmentionError> {
+        let response = client.get(&self.source).await?;
+        crate::verification::contains(&response, &self.target)?;
+        Ok(response)
     }
 
     pub fn set_checked(&mut self, checked: bool) {
         self.checked = Some(checked);
     }
+
+    /// Whether the source was checked to link to the target, if it was checked at all
+    pub fn checked(&self) -> Option<bool> {
+        self.checked
+    }
+
+    /// Forgets the result of checking the source, so that it is checked again when sending.
+    pub fn reset_checked(&mut self) {
+        self.checked = None;
+    }
+
+    pub fn set_sent(&mut self, sent: bool) {
+        self.sent = sent;
+    }
+
+    /// Whether the webmention was sent to the endpoint of the target
+    pub fn is_sent(&self) -> bool {
+        self.sent
+    }
 }
 
 impl From<(&Url, &Url)> for Webmention {
@@ -94,6 +290,8 @@ impl From<(&Url, &Url)> for Webmention {
             target: tuple.1.clone(),
             sent: false,
             checked: None,
+            moderation: Moderation::default(),
+            snapshot: None,
         }
     }
 }
@@ -105,6 +303,8 @@ impl From<(Url, Url)> for Webmention {
             target: tuple.1,
             sent: false,
             checked: None,
+            moderation: Moderation::default(),
+            snapshot: None,
         }
     }
 }
@@ -115,6 +315,31 @@ mod test {
     use crate::wm_url::Url;
     use tokio_test::block_on;
 
+    #[cfg(feature = "pingback")]
+    #[test]
+    fn test_pingback_fallback_fetches_target_once() {
+        use crate::http_client::test::{local_client, serve_sequence};
+
+        // the server answers once per connection: a second fetch of the target would get the
+        // pingback response instead, and the ping would find nobody listening
+        let target = "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nX-Pingback: /xmlrpc\r\n\
+                      Connection: close\r\n\r\n<html><body>No webmentions here</body></html>";
+        let accepted = "<?xml version=\"1.0\"?><methodResponse><params><param><value>\
+                        <string>OK</string></value></param></params></methodResponse>";
+        let accepted = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            accepted.len(),
+            accepted
+        );
+        let url = serve_sequence(vec![target.as_bytes().to_vec(), accepted.into_bytes()]);
+
+        let mut mention = Webmention::from((Url::parse("https://a.example/").unwrap(), url));
+        mention.set_checked(true);
+        let acceptance = block_on(mention.send_with(&local_client(1024))).unwrap();
+        assert_eq!(acceptance, WebmentionAcceptance::PingbackAccepted);
+    }
+
     #[ignore]
     #[test]
     fn webmention_check_test() {
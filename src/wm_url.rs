@@ -15,9 +15,183 @@ pub fn absolute_url(candidate_url: &str, original_url: &Url) -> Result<Url, url:
     }
 }
 
+/// Query parameters that are only used for tracking and never change the resource
+pub const TRACKING_QUERY_PARAMS: [&str; 6] =
+    ["utm_*", "fbclid", "gclid", "mc_cid", "mc_eid", "ref_src"];
+
+/// Policy that decides which URLs are considered to point to the same resource.
+///
+/// Regardless of the policy, URLs are compared after `Url::parse` (which lowercases the host,
+/// converts IDN hosts to punycode and drops default ports) and after normalizing
+/// percent-encoding: unreserved characters are decoded, the rest are uppercased.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UrlEquivalence {
+    /// `http://` and `https://` are the same
+    pub ignore_scheme: bool,
+    /// `www.example.com` and `example.com` are the same
+    pub ignore_www: bool,
+    /// `/post` and `/post/` are the same
+    pub ignore_trailing_slash: bool,
+    /// `/post#comments` and `/post` are the same
+    pub ignore_fragment: bool,
+    /// Query parameters dropped before comparison; entries ending with `*` match by prefix
+    pub ignored_query_params: Vec<String>,
+}
+
+impl Default for UrlEquivalence {
+    /// Ignores trailing slashes, fragments and [tracking parameters](TRACKING_QUERY_PARAMS).
+    fn default() -> Self {
+        UrlEquivalence {
+            ignore_scheme: false,
+            ignore_www: false,
+            ignore_trailing_slash: true,
+            ignore_fragment: true,
+            ignored_query_params: TRACKING_QUERY_PARAMS
+                .iter()
+                .map(|param| param.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl UrlEquivalence {
+    /// Only percent-encoding is normalized.
+    pub fn strict() -> UrlEquivalence {
+        UrlEquivalence {
+            ignore_scheme: false,
+            ignore_www: false,
+            ignore_trailing_slash: false,
+            ignore_fragment: false,
+            ignored_query_params: Vec::new(),
+        }
+    }
+
+    /// Everything besides percent-encoding is ignored as well.
+    pub fn lenient() -> UrlEquivalence {
+        UrlEquivalence {
+            ignore_scheme: true,
+            ignore_www: true,
+            ..UrlEquivalence::default()
+        }
+    }
+
+    /// Canonical form of the URL under this policy: equivalent URLs have equal canonical forms.
+    pub fn normalize(&self, url: &Url) -> Url {
+        let mut url = url.clone();
+
+        if self.ignore_scheme && url.scheme() == "http" {
+            let port = url.port();
+            if url.set_scheme("https").is_ok() && port.is_none() {
+                let _ = url.set_port(None);
+            }
+        }
+
+        if let Some(host) = self.normalize_host(&url) {
+            let _ = url.set_host(Some(&host));
+        }
+
+        let path = normalize_percent_encoding(url.path());
+        let path = match path.strip_suffix('/') {
+            Some(stripped) if self.ignore_trailing_slash && !stripped.is_empty() => {
+                stripped.to_string()
+            }
+            _ => path,
+        };
+        url.set_path(&path);
+
+        if let Some(query) = url.query() {
+            let query: Vec<String> = query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .filter(|pair| !self.is_ignored_param(pair.split('=').next().unwrap_or_default()))
+                .map(normalize_percent_encoding)
+                .collect();
+            if query.is_empty() {
+                url.set_query(None);
+            } else {
+                url.set_query(Some(&query.join("&")));
+            }
+        }
+
+        if self.ignore_fragment {
+            url.set_fragment(None);
+        } else if let Some(fragment) = url.fragment().map(normalize_percent_encoding) {
+            url.set_fragment(Some(&fragment));
+        }
+
+        url
+    }
+
+    /// Whether both URLs point to the same resource under this policy.
+    pub fn equivalent(&self, url: &Url, other: &Url) -> bool {
+        self.normalize(url) == self.normalize(other)
+    }
+
+    /// Whether `url` is on the same host as `domain` under this policy, e.g. whether it is
+    /// a valid target for a receiver of `domain`.
+    pub fn same_host(&self, url: &Url, domain: &Url) -> bool {
+        match (self.normalize_host(url), self.normalize_host(domain)) {
+            (Some(host), Some(domain_host)) => host == domain_host,
+            _ => false,
+        }
+    }
+
+    fn normalize_host(&self, url: &Url) -> Option<String> {
+        let host = url.host_str()?;
+        match host.strip_prefix("www.") {
+            Some(stripped) if self.ignore_www && url.domain().is_some() => {
+                Some(stripped.to_string())
+            }
+            _ => Some(host.to_string()),
+        }
+    }
+
+    fn is_ignored_param(&self, name: &str) -> bool {
+        self.ignored_query_params
+            .iter()
+            .any(|ignored| match ignored.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == ignored,
+            })
+    }
+}
+
+fn normalize_percent_encoding(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut normalized = String::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let decoded = match bytes.get(i..i + 3) {
+            Some([b'%', hi, lo]) => std::str::from_utf8(&[*hi, *lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+
+        match decoded {
+            Some(c) if c.is_ascii_alphanumeric() || matches!(c, b'-' | b'.' | b'_' | b'~') => {
+                normalized.push(c as char);
+                i += 3;
+            }
+            Some(c) => {
+                normalized.push_str(&format!("%{:02X}", c));
+                i += 3;
+            }
+            None => {
+                let c = s[i..].chars().next().unwrap_or_default();
+                normalized.push(c);
+                i += c.len_utf8().max(1);
+            }
+        }
+    }
+
+    normalized
+}
+
 #[cfg(test)]
 mod test {
-    use super::absolute_url;
+    use super::{absolute_url, UrlEquivalence};
     use url::Url;
     #[test]
     fn test_absolute_url() {
@@ -57,4 +231,79 @@ mod test {
             "https://webmention.rocks/test/23/page/webmention-endpoint/xXNLydslCJo3niJSfoXk"
         );
     }
+
+    #[test]
+    fn test_normalize() {
+        let normalized = |s: &str, policy: &UrlEquivalence| {
+            policy.normalize(&Url::parse(s).unwrap()).to_string()
+        };
+        let default = UrlEquivalence::default();
+        let strict = UrlEquivalence::strict();
+
+        assert_eq!(
+            normalized("HTTPS://Example.COM:443/post/", &default),
+            "https://example.com/post"
+        );
+        assert_eq!(
+            normalized("https://example.com/%7euser/caf%c3%a9?q=%2f", &strict),
+            "https://example.com/~user/caf%C3%A9?q=%2F"
+        );
+        assert_eq!(
+            normalized("https://example.com/", &default),
+            "https://example.com/"
+        );
+        assert_eq!(
+            normalized("https://example.com/post#comments", &default),
+            "https://example.com/post"
+        );
+        assert_eq!(
+            normalized("https://example.com/post#comment-%31", &strict),
+            "https://example.com/post#comment-1"
+        );
+        assert_eq!(
+            normalized(
+                "https://example.com/post?utm_source=rss&id=1&fbclid=abc",
+                &default
+            ),
+            "https://example.com/post?id=1"
+        );
+        assert_eq!(
+            normalized("https://example.com/post?utm_source=rss", &default),
+            "https://example.com/post"
+        );
+    }
+
+    #[test]
+    fn test_equivalent() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let default = UrlEquivalence::default();
+        let lenient = UrlEquivalence::lenient();
+
+        let post = url("https://example.com/post");
+        assert!(default.equivalent(&post, &url("https://example.com/post/?utm_medium=feed")));
+        assert!(!default.equivalent(&post, &url("http://example.com/post")));
+        assert!(!default.equivalent(&post, &url("https://www.example.com/post")));
+        assert!(lenient.equivalent(&post, &url("http://www.example.com/post/")));
+        assert!(!lenient.equivalent(&post, &url("http://example.com:8080/post")));
+        assert!(!UrlEquivalence::strict().equivalent(&post, &url("https://example.com/post/")));
+
+        let idn = url("https://пример.рф/пост");
+        assert!(default.equivalent(
+            &idn,
+            &url("https://xn--e1afmkfd.xn--p1ai/%D0%BF%D0%BE%D1%81%D1%82")
+        ));
+    }
+
+    #[test]
+    fn test_same_host() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let domain = url("https://marinintim.com/");
+        assert!(
+            UrlEquivalence::default().same_host(&url("http://marinintim.com:8080/notes/"), &domain)
+        );
+        assert!(!UrlEquivalence::default().same_host(&url("https://www.marinintim.com/"), &domain));
+        assert!(UrlEquivalence::lenient().same_host(&url("https://www.marinintim.com/"), &domain));
+        assert!(!UrlEquivalence::lenient()
+            .same_host(&url("https://example.com/marinintim.com"), &domain));
+    }
 }